@@ -1,5 +1,6 @@
 extern crate askama;
 
+#[allow(deprecated)]
 fn main() {
     askama::rerun_if_templates_changed();
 }
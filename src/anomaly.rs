@@ -0,0 +1,244 @@
+// anomaly.rs flags entries that look wrong, so whoever checks the digest looks twice
+// Each check has a threshold under [anomalies] in the config
+use crate::{
+    batch::{parse_alert_time, Batch, Product, UserID},
+    config::Anomalies,
+    history::History,
+};
+use chrono::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
+
+/// Something odd about one entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    // One product alerting over and over - usually a misconfigured autodraft
+    Repeated {
+        product: Product,
+        alerts: usize,
+        hours: i64,
+    },
+    // How many products this ID alerted for
+    ManyProducts(usize),
+    // The minute, and how many IDs alerted in it - a bulk job touching everyone
+    SameMinute {
+        time: String,
+        ids: usize,
+    },
+    // A product code that isn't in the catalog
+    UnknownProduct(String),
+}
+
+impl Anomaly {
+    /// The short label shown as a badge
+    pub fn badge(&self) -> &'static str {
+        match self {
+            Anomaly::Repeated { .. } => "REPEAT",
+            Anomaly::ManyProducts(_) => "MANY PRODUCTS",
+            Anomaly::SameMinute { .. } => "BULK JOB",
+            Anomaly::UnknownProduct(_) => "UNKNOWN PRODUCT",
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Anomaly::Repeated {
+                product,
+                alerts,
+                hours,
+            } => {
+                write!(
+                    f,
+                    "{} alerted {} times within {} hours",
+                    product.code(),
+                    alerts,
+                    hours
+                )
+            }
+            Anomaly::ManyProducts(n) => write!(f, "alerted for {} products at once", n),
+            Anomaly::SameMinute { time, ids } => write!(f, "{} IDs alerted at {}", ids, time),
+            Anomaly::UnknownProduct(code) => write!(f, "{} isn't a known product code", code),
+        }
+    }
+}
+
+/// Every anomaly in the batch, by ID
+/// Repeats count alerts archived with earlier digests too
+pub fn check(
+    batch: &Batch,
+    history: &History,
+    thresholds: &Anomalies,
+) -> HashMap<UserID, Vec<Anomaly>> {
+    let mut ret: HashMap<UserID, Vec<Anomaly>> = HashMap::new();
+
+    // Which IDs alerted in each minute, across the whole batch
+    let mut minutes: BTreeMap<_, BTreeSet<UserID>> = BTreeMap::new();
+    for be in batch.entries.values() {
+        for time in be.alerts.values().flatten() {
+            if let Some(t) = parse_alert_time(time) {
+                minutes.entry(t).or_default().insert(be.id);
+            }
+        }
+    }
+
+    for be in batch.entries.values() {
+        let mut found = Vec::new();
+        let alerts = be.alerts_vec();
+
+        if thresholds.repeat_alerts > 0 {
+            let window = Duration::hours(thresholds.repeat_window_hours);
+            for (product, times) in &alerts {
+                let earlier = history
+                    .of(be.id)
+                    .iter()
+                    .flat_map(|a| a.alerts.iter())
+                    .filter(|(p, _)| p == product)
+                    .flat_map(|(_, ts)| ts.iter());
+                let mut parsed: Vec<_> = times
+                    .iter()
+                    .chain(earlier)
+                    .filter_map(|t| parse_alert_time(t))
+                    .collect();
+                parsed.sort_unstable();
+                // The most alerts inside any one window
+                let most = (0..parsed.len())
+                    .map(|i| {
+                        parsed[i..]
+                            .iter()
+                            .take_while(|t| **t - parsed[i] <= window)
+                            .count()
+                    })
+                    .max()
+                    .unwrap_or(0);
+                if most > thresholds.repeat_alerts {
+                    found.push(Anomaly::Repeated {
+                        product: product.clone(),
+                        alerts: most,
+                        hours: thresholds.repeat_window_hours,
+                    });
+                }
+            }
+        }
+
+        if thresholds.many_products > 0 && alerts.len() >= thresholds.many_products {
+            found.push(Anomaly::ManyProducts(alerts.len()));
+        }
+
+        if thresholds.same_minute > 0 {
+            let mut mine: Vec<_> = alerts
+                .iter()
+                .flat_map(|(_, ts)| ts.iter())
+                .filter_map(|t| parse_alert_time(t))
+                .collect();
+            mine.sort_unstable();
+            mine.dedup();
+            for t in mine {
+                let ids = minutes.get(&t).map_or(0, |ids| ids.len());
+                if ids >= thresholds.same_minute {
+                    found.push(Anomaly::SameMinute {
+                        time: t.format("%B %-d, %Y %-I:%M %p").to_string(),
+                        ids,
+                    });
+                }
+            }
+        }
+
+        if thresholds.unknown_products {
+            for (product, _) in &alerts {
+                if let Product::Other(code) = product {
+                    found.push(Anomaly::UnknownProduct(code.clone()));
+                }
+            }
+        }
+
+        if !found.is_empty() {
+            ret.insert(be.id, found);
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{Alerts, BatchEntry};
+    use std::str::FromStr;
+
+    fn test_batch(alerts: &[(UserID, &str, &str)]) -> Batch {
+        let mut batch = Batch::new();
+        for (id, product, time) in alerts {
+            batch.entries.entry(*id).or_insert_with(|| BatchEntry {
+                id: *id,
+                alerts: Alerts::new(),
+            });
+            let product = Product::from_str(product).unwrap();
+            if let Some(be) = batch.entries.get_mut(id) {
+                be.alerts.entry(product).or_default().push(time.to_string());
+            }
+        }
+        batch
+    }
+
+    #[test]
+    fn test_check() {
+        let mut alerts = vec![
+            // Four in a day, then one more a month later
+            (1, "CG_TUITION", "Monday, July 16, 2018 9:00 AM"),
+            (1, "CG_TUITION", "Monday, July 16, 2018 10:00 AM"),
+            (1, "CG_TUITION", "Tuesday, July 17, 2018 9:00 AM"),
+            (1, "CG_TUITION", "Tuesday, July 17, 2018 9:30 AM"),
+            (1, "CG_TUITION", "Thursday, August 16, 2018 9:00 AM"),
+            (2, "CG_TRANS", "Monday, July 16, 2018 9:00 AM"),
+            (2, "CG_TUITION", "Monday, July 16, 2018 11:00 AM"),
+            (2, "CAMP_BUS", "Monday, July 16, 2018 11:00 AM"),
+        ];
+        for id in 3..6 {
+            alerts.push((id, "CAMP_KALE_TUIT", "Monday, July 16, 2018 9:00 AM"));
+        }
+        let batch = test_batch(&alerts);
+        let found = check(&batch, &History::default(), &Anomalies::default());
+        let bulk = Anomaly::SameMinute {
+            time: "July 16, 2018 9:00 AM".into(),
+            ids: 5,
+        };
+
+        assert_eq!(
+            found[&1],
+            vec![
+                Anomaly::Repeated {
+                    product: Product::CgBilling,
+                    alerts: 4,
+                    hours: 168
+                },
+                bulk.clone()
+            ]
+        );
+        assert_eq!(
+            found[&2],
+            vec![
+                Anomaly::ManyProducts(3),
+                bulk.clone(),
+                Anomaly::UnknownProduct("CAMP_BUS".into())
+            ]
+        );
+        assert_eq!(found[&3], vec![bulk]);
+        assert_eq!(found[&2][0].badge(), "MANY PRODUCTS");
+        assert_eq!(
+            found[&1][0].to_string(),
+            "CG_TUITION alerted 4 times within 168 hours"
+        );
+
+        let off = Anomalies {
+            repeat_alerts: 0,
+            repeat_window_hours: 168,
+            many_products: 0,
+            same_minute: 0,
+            unknown_products: false,
+        };
+        assert!(check(&batch, &History::default(), &off).is_empty());
+    }
+}
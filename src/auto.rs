@@ -0,0 +1,162 @@
+// auto.rs decides when `ar-bot auto` should cut a digest without anyone asking
+use crate::{
+    batch::{parse_alert_time, Batch},
+    config::Auto,
+    errors::*,
+};
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Weekday};
+use std::str::FromStr;
+
+/// Why a digest is due.  Displayed in the log and on the console
+#[derive(Debug, PartialEq)]
+pub enum Due {
+    Ids(usize),
+    Alerts(usize),
+    Age(Duration),
+}
+
+impl std::fmt::Display for Due {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Due::Ids(n) => write!(f, "{} unique IDs pending", n),
+            Due::Alerts(n) => write!(f, "{} alerts pending", n),
+            Due::Age(d) => write!(f, "oldest pending alert is {} hours old", d.num_hours()),
+        }
+    }
+}
+
+/// Returns the first threshold the batch has reached, if any
+/// An empty batch is never due
+pub fn due(auto: &Auto, batch: &Batch, now: NaiveDateTime) -> Option<Due> {
+    if batch.entries.is_empty() {
+        return None;
+    }
+    let ids = batch.entries.len();
+    if auto.min_ids.is_some_and(|min| ids >= min) {
+        return Some(Due::Ids(ids));
+    }
+    let alerts: usize = batch
+        .entries
+        .values()
+        .flat_map(|e| e.alerts.values())
+        .map(|times| times.len())
+        .sum();
+    if auto.min_alerts.is_some_and(|min| alerts >= min) {
+        return Some(Due::Alerts(alerts));
+    }
+    if let Some(max_age) = auto.max_age_hours {
+        let oldest = batch
+            .entries
+            .values()
+            .flat_map(|e| e.alerts.values().flatten())
+            .filter_map(|t| parse_alert_time(t))
+            .min();
+        if let Some(oldest) = oldest {
+            let age = now.signed_duration_since(oldest);
+            if age >= Duration::hours(max_age) {
+                return Some(Due::Age(age));
+            }
+        }
+    }
+    None
+}
+
+/// Whether now falls inside the schedule in `[auto]`
+/// No days means every day, no not_before means any time of day
+pub fn in_schedule(auto: &Auto, now: NaiveDateTime) -> Result<bool> {
+    if !auto.days.is_empty() {
+        let mut today = false;
+        for d in &auto.days {
+            let day =
+                Weekday::from_str(d).map_err(|_| format!("Unknown day {:?} under [auto]", d))?;
+            today |= day == now.weekday();
+        }
+        if !today {
+            return Ok(false);
+        }
+    }
+    if let Some(t) = &auto.not_before {
+        let not_before = NaiveTime::parse_from_str(t, "%H:%M").chain_err(|| {
+            ErrorKind::Config(format!("not_before should look like 09:00, not {:?}", t))
+        })?;
+        if now.time() < not_before {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch::Entry, email::Email, util::*};
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_due_ids() {
+        let auto = Auto {
+            min_ids: Some(2),
+            ..Auto::default()
+        };
+        let now = at("2018-07-21 17:00");
+        assert_eq!(due(&auto, &Batch::test(), now), None);
+        assert_eq!(
+            due(&auto, &Batch::test_second_email_str(TEST_DIF_BOTH), now),
+            Some(Due::Ids(2))
+        );
+    }
+    #[test]
+    fn test_due_alerts() {
+        let auto = Auto {
+            min_alerts: Some(2),
+            ..Auto::default()
+        };
+        let mut batch = Batch::test();
+        assert_eq!(due(&auto, &batch, at("2018-07-21 17:00")), None);
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_COOL_STR).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(
+            due(&auto, &batch, at("2018-07-21 17:00")),
+            Some(Due::Alerts(2))
+        );
+    }
+    #[test]
+    fn test_due_age() {
+        // Batch::test() alerts at 4:39 PM on July 21
+        let auto = Auto {
+            max_age_hours: Some(24),
+            ..Auto::default()
+        };
+        assert_eq!(due(&auto, &Batch::test(), at("2018-07-22 16:00")), None);
+        assert_eq!(
+            due(&auto, &Batch::test(), at("2018-07-22 16:39")),
+            Some(Due::Age(Duration::hours(24)))
+        );
+    }
+    #[test]
+    fn test_never_due_empty() {
+        let auto = Auto {
+            min_ids: Some(0),
+            ..Auto::default()
+        };
+        assert_eq!(due(&auto, &Batch::new(), at("2018-07-22 16:00")), None);
+    }
+    #[test]
+    fn test_in_schedule() {
+        let auto = Auto {
+            days: vec!["mon".into(), "Thursday".into()],
+            not_before: Some("09:00".into()),
+            ..Auto::default()
+        };
+        // July 23, 2018 was a Monday
+        assert!(in_schedule(&auto, at("2018-07-23 09:00")).unwrap());
+        assert!(!in_schedule(&auto, at("2018-07-23 08:59")).unwrap());
+        assert!(!in_schedule(&auto, at("2018-07-24 10:00")).unwrap());
+        assert!(in_schedule(&auto, at("2018-07-26 10:00")).unwrap());
+        assert!(in_schedule(&Auto::default(), at("2018-07-24 03:00")).unwrap());
+    }
+}
@@ -1,32 +1,72 @@
 // batch.rs handles the string parsing and batching logic for eliminating redundant line items
-use crate::{brain::Brain, email::Email, errors::*};
-use lazy_static::lazy_static;
+use crate::{
+    brain::Brain,
+    email::Email,
+    errors::*,
+    parser::{AlertParser, Autodraft, Registry},
+};
+use chrono::NaiveDateTime;
 use log::*;
-use regex::Regex;
-use std::{collections::HashMap, fmt, str::FromStr};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap, convert::TryFrom, fmt, hash::Hash, str::FromStr};
 
 #[cfg(test)]
 use crate::util::*;
 
-type Alerts = HashMap<Product, Vec<String>>;
+pub type Alerts<I = Product> = HashMap<I, Vec<String>>;
+
+/// Bump this whenever the serialized shape of a Batch changes - see "Batch schema" in the README
+pub const BATCH_SCHEMA_VERSION: u32 = 1;
+
+/// Anything a batch can group alerts on, or list under a group
+/// For autodraft alerts that's the iMIS ID and the Product, but any AlertParser can bring its own
+pub trait BatchKey:
+    Clone + fmt::Debug + fmt::Display + Eq + Hash + Ord + Serialize + DeserializeOwned + 'static
+{
+}
+
+impl<T> BatchKey for T where
+    T: Clone + fmt::Debug + fmt::Display + Eq + Hash + Ord + Serialize + DeserializeOwned + 'static
+{
+}
 
-// The final batch
-// there should only be one BatchEntry per ID - that's literally the whole point of this app
 // Think about how to encode this constraint in the types
 // Maybe a HashMap?
-#[derive(Debug, PartialEq)]
-pub struct Batch {
-    pub entries: Entries,
+/// The final batch
+/// There should only be one BatchEntry per ID - that's literally the whole point of this app
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(
+    into = "BatchSchema<K, I>",
+    try_from = "BatchSchema<K, I>",
+    bound = "K: BatchKey, I: BatchKey"
+)]
+pub struct Batch<K = UserID, I = Product> {
+    pub entries: Entries<K, I>,
 }
 
-impl Batch {
+// HashMap equality needs Eq + Hash, which derive(PartialEq) doesn't ask for
+impl<K: BatchKey, I: BatchKey> PartialEq for Batch<K, I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> Default for Batch<K, I> {
+    fn default() -> Self {
+        Batch::new()
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> Batch<K, I> {
     pub fn new() -> Self {
         Batch {
             entries: Entries::new(),
         }
     }
 
-    pub fn add_entry(&mut self, e: Entry) -> Result<()> {
+    /// Returns how the entry was classified, so callers can report what happened to it
+    pub fn add_entry(&mut self, e: Entry<K, I>) -> Result<EntryClass<K, I>> {
         let entry_class = self.classify(&e);
 
         // First, search for the id.  Only if we find it, search for a duplicate product on that id.
@@ -36,7 +76,7 @@ impl Batch {
             info_str.push_str(&format!("INSERT: {} <", e));
         }
 
-        match entry_class {
+        match entry_class.clone() {
             EntryClass::Duplicate((id, product)) => {
                 // the only thing I push is the time, and I haven't done those yet
                 // Multiple duplicate times are OK, I still wnat a note that I processed the email
@@ -59,7 +99,7 @@ impl Batch {
                     info_str.push_str("It's a brand new entry for this digest.");
                 }
                 self.entries
-                    .entry(e.id)
+                    .entry(e.id.clone())
                     .or_insert_with(|| BatchEntry::from(e));
             }
             EntryClass::NewProduct(id) => {
@@ -80,21 +120,21 @@ impl Batch {
             }
         }
         if log_enabled!(Level::Info) {
-            info_str.push_str(">");
+            info_str.push('>');
             info!("{}", info_str);
         }
-        Ok(())
+        Ok(entry_class)
     }
 
     // Classifies an entry
-    fn classify(&self, e: &Entry) -> EntryClass {
+    fn classify(&self, e: &Entry<K, I>) -> EntryClass<K, I> {
         let mut entry_class = EntryClass::default();
         for (id, be) in &self.entries {
             if *id == e.id {
-                entry_class = EntryClass::NewProduct(*id);
+                entry_class = EntryClass::NewProduct(id.clone());
                 for p in be.alerts.keys() {
                     if e.product == *p {
-                        entry_class = EntryClass::Duplicate((*id, e.product.clone()));
+                        entry_class = EntryClass::Duplicate((id.clone(), e.product.clone()));
                         break;
                     }
                 }
@@ -105,14 +145,90 @@ impl Batch {
         entry_class
     }
 
-    pub fn from_brain(brain: &Brain) -> Result<Self> {
-        // call add_entry on each email in the brain
+    /// Just the alerts for products that pass, leaving out IDs with none left
+    pub fn filter_products<F: Fn(&I) -> bool>(&self, keep: F) -> Self {
+        let mut ret = Batch::new();
+        for (id, be) in &self.entries {
+            let alerts: Alerts<I> = be
+                .alerts
+                .iter()
+                .filter(|(p, _)| keep(p))
+                .map(|(p, t)| (p.clone(), t.clone()))
+                .collect();
+            if !alerts.is_empty() {
+                ret.entries.insert(
+                    id.clone(),
+                    BatchEntry {
+                        id: id.clone(),
+                        alerts,
+                    },
+                );
+            }
+        }
+        ret
+    }
+
+    /// Reads a batch written by `to_json`, checking the schema version
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s)
+            .chain_err(|| ErrorKind::Parse("Could not read batch from JSON".into()))
+    }
+
+    /// Writes the batch in the versioned schema described in the README
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).chain_err(|| "Could not write batch as JSON")
+    }
+
+    /// Reads a batch written by `to_toml`, checking the schema version
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).chain_err(|| ErrorKind::Parse("Could not read batch from TOML".into()))
+    }
+
+    /// The same schema as `to_json`, as TOML
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).chain_err(|| "Could not write batch as TOML")
+    }
+
+    /// Builds a batch from every email in the brain
+    /// Each is parsed by the first parser in the registry that accepts it
+    pub fn from_brain_with(brain: &Brain, registry: &Registry<K, I>) -> Result<Self> {
         let mut ret = Batch::new();
         for email in &brain.emails {
-            ret.add_entry(Entry::from_email(email)?)?;
+            ret.add_entry(registry.parse(email)?)?;
         }
         Ok(ret)
     }
+}
+
+impl Batch {
+    /// Builds a batch from every email in the brain
+    pub fn from_brain(brain: &Brain) -> Result<Self> {
+        // call add_entry on each email in the brain
+        Batch::from_brain_with(brain, &Registry::default())
+    }
+
+    /// A small made-up batch for checking templates against
+    /// Two people, one with two products and a repeated alert
+    pub fn sample() -> Self {
+        let mut batch = Batch::new();
+        let alerts = [
+            (100001, "CG_TUITION", "Wednesday, July 18, 2018 1:00 PM"),
+            (100001, "CG_TUITION", "Wednesday, July 18, 2018 4:30 PM"),
+            (100001, "CG_TRANS", "Wednesday, July 18, 2018 1:00 PM"),
+            (100002, "CAMP_KALE_TUIT", "Thursday, July 19, 2018 9:15 AM"),
+        ];
+        for (id, product, time) in alerts.iter() {
+            batch.entries.entry(*id).or_insert_with(|| BatchEntry {
+                id: *id,
+                alerts: Alerts::new(),
+            });
+            let product = Product::from_str(product).expect("Product::from_str is infallible");
+            if let Some(be) = batch.entries.get_mut(id) {
+                be.alerts.entry(product).or_default().push(time.to_string());
+            }
+        }
+        batch
+    }
 
     #[cfg(test)]
     pub fn test() -> Self {
@@ -135,60 +251,255 @@ impl Batch {
     }
 }
 
-impl fmt::Display for Batch {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let _ = writeln!(f, "Current Batch:");
+impl<K: BatchKey, I: BatchKey> Batch<K, I> {
+    /// The batch as preview shows it, one line per ID in ID order
+    pub fn to_text(&self, detail: TimeDetail) -> String {
+        let mut ret = String::from("Current Batch:\n");
         if self.entries.is_empty() {
-            write!(f, "No entries")
+            ret.push_str("No entries");
         } else {
-            let entries_strs: Vec<String> =
-                self.entries.iter().map(|(_, e)| format!("{}", e)).collect();
-            let mut entries = String::new();
-            for e in entries_strs {
-                entries.push_str(&e);
+            let mut entries: Vec<&BatchEntry<K, I>> = self.entries.values().collect();
+            entries.sort_by(|a, b| a.id.cmp(&b.id));
+            for e in entries {
+                ret.push_str(&e.to_text(detail));
+            }
+        }
+        ret
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> fmt::Display for Batch<K, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_text(TimeDetail::default()))
+    }
+}
+
+// The serialized shape of a Batch
+// The HashMap is flattened to a list sorted by id so the same batch always comes out the same way
+#[derive(Deserialize, Serialize)]
+#[serde(bound = "K: BatchKey, I: BatchKey")]
+struct BatchSchema<K, I> {
+    schema_version: u32,
+    entries: Vec<BatchEntry<K, I>>,
+}
+
+impl<K: BatchKey, I: BatchKey> From<Batch<K, I>> for BatchSchema<K, I> {
+    fn from(b: Batch<K, I>) -> Self {
+        let mut entries: Vec<BatchEntry<K, I>> = b.entries.into_values().collect();
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        BatchSchema {
+            schema_version: BATCH_SCHEMA_VERSION,
+            entries,
+        }
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> TryFrom<BatchSchema<K, I>> for Batch<K, I> {
+    type Error = String;
+
+    fn try_from(schema: BatchSchema<K, I>) -> ::std::result::Result<Self, Self::Error> {
+        if schema.schema_version != BATCH_SCHEMA_VERSION {
+            return Err(format!(
+                "unsupported batch schema version {} (expected {})",
+                schema.schema_version, BATCH_SCHEMA_VERSION
+            ));
+        }
+        let mut entries = Entries::new();
+        for e in schema.entries {
+            if entries.contains_key(&e.id) {
+                return Err(format!("ID {} appears more than once", e.id));
             }
-            write!(f, "{}", entries)
+            entries.insert(e.id.clone(), e);
         }
+        Ok(Batch { entries })
     }
 }
 
-// Can store multiple entries
-#[derive(Clone, Debug, Default, PartialEq)]
-pub struct BatchEntry {
-    pub id: u32,
-    pub alerts: Alerts,
+/// Every alert for one iMIS ID, grouped by product
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(bound = "K: BatchKey, I: BatchKey")]
+pub struct BatchEntry<K = UserID, I = Product> {
+    pub id: K,
+    #[serde(with = "alerts_schema")]
+    pub alerts: Alerts<I>,
+}
+
+// Alerts are keyed by Product in memory, but written out as a list of {product, times} sorted by product code
+mod alerts_schema {
+    use super::{Alerts, BatchKey};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(bound = "I: BatchKey")]
+    struct AlertSchema<I> {
+        product: I,
+        times: Vec<String>,
+    }
+
+    pub fn serialize<I, S>(alerts: &Alerts<I>, s: S) -> Result<S::Ok, S::Error>
+    where
+        I: BatchKey,
+        S: Serializer,
+    {
+        let mut v: Vec<AlertSchema<I>> = alerts
+            .iter()
+            .map(|(product, times)| AlertSchema {
+                product: product.clone(),
+                times: times.clone(),
+            })
+            .collect();
+        v.sort_by(|a, b| a.product.cmp(&b.product));
+        v.serialize(s)
+    }
+
+    pub fn deserialize<'de, I, D>(d: D) -> Result<Alerts<I>, D::Error>
+    where
+        I: BatchKey,
+        D: Deserializer<'de>,
+    {
+        let mut alerts = Alerts::new();
+        for a in Vec::<AlertSchema<I>>::deserialize(d)? {
+            alerts.entry(a.product).or_default().extend(a.times);
+        }
+        Ok(alerts)
+    }
 }
 
-impl BatchEntry {
-    // Just returns the alerts as a vector of (Product, Vec<DateTime<Local>>)
-    // is this necessary?
-    pub fn alerts_vec(&self) -> Vec<(Product, Vec<String>)> {
+impl<K: BatchKey, I: BatchKey> PartialEq for BatchEntry<K, I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.alerts == other.alerts
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> BatchEntry<K, I> {
+    /// Returns the alerts as a vector of (Product, times)
+    /// Sorted by product code so every rendering lists them in the same order
+    pub fn alerts_vec(&self) -> Vec<(I, Vec<String>)> {
         let mut ret = Vec::new();
         for (k, v) in &self.alerts {
             ret.push((k.clone(), v.clone()));
         }
+        ret.sort_by(|a, b| a.0.cmp(&b.0));
         ret
     }
 }
 
-impl fmt::Display for BatchEntry {
+impl<K: BatchKey, I: BatchKey> BatchEntry<K, I> {
+    /// The same entry with each product's times squashed for showing, not for parsing again
+    pub fn squashed(&self, detail: TimeDetail) -> Self {
+        BatchEntry {
+            id: self.id.clone(),
+            alerts: self
+                .alerts
+                .iter()
+                .map(|(p, ts)| (p.clone(), squash_times(ts, detail)))
+                .collect(),
+        }
+    }
+
+    /// One line per entry, as preview shows it
+    pub fn to_text(&self, detail: TimeDetail) -> String {
+        let alerts: Vec<String> = self
+            .alerts_vec()
+            .into_iter()
+            .map(|(p, ts)| format!("{} at {}", p, squash_times(&ts, detail).join(", ")))
+            .collect();
+        format!("{}: {}\n", self.id, alerts.join("; "))
+    }
+}
+
+impl<K: BatchKey, I: BatchKey> fmt::Display for BatchEntry<K, I> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut alerts = String::new();
-        for (k, ts) in &self.alerts {
-            let times: Vec<String> = ts.iter().map(|t| format!("{}, ", t)).collect();
-            let mut time_str = String::new();
-            for t in times {
-                time_str.push_str(&t);
-            }
-            // TODO squash times.
-            alerts.push_str(&format!("{} at {}", k, time_str));
+        write!(f, "{}", self.to_text(TimeDetail::default()))
+    }
+}
+
+/// How much of each alert time the digest and preview show
+/// Set with `times` under `[digest]`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeDetail {
+    // Every time, as it was in the alert
+    All,
+    // Each time once, with a count for repeats: "Wednesday, July 18, 2018 1:00 PM ×5"
+    #[default]
+    Counted,
+    // One line a day: "Jul 18 1:00–4:30 PM, 7 alerts"
+    Days,
+}
+
+impl FromStr for TimeDetail {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(TimeDetail::All),
+            "counted" => Ok(TimeDetail::Counted),
+            "days" => Ok(TimeDetail::Days),
+            _ => Err(format!("Unknown time detail {} - use all, counted or days", s).into()),
         }
-        writeln!(f, "{}: {}", self.id, alerts)
     }
 }
 
-impl From<Entry> for BatchEntry {
-    fn from(e: Entry) -> Self {
+// "×5" for more than one
+fn times_suffix(n: usize) -> String {
+    if n > 1 {
+        format!(" ×{}", n)
+    } else {
+        String::new()
+    }
+}
+
+/// Squashes a list of alert times down to the detail asked for, oldest first
+/// Times that don't parse are kept as they are, counted like any other
+pub fn squash_times(times: &[String], detail: TimeDetail) -> Vec<String> {
+    if detail == TimeDetail::All {
+        return times.to_vec();
+    }
+    let mut sorted: Vec<(Option<NaiveDateTime>, &str)> = times
+        .iter()
+        .map(|t| (parse_alert_time(t), t.trim()))
+        .collect();
+    sorted.sort_by(|a, b| alert_time_order(a.0, b.0));
+
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < sorted.len() {
+        let (parsed, raw) = sorted[i];
+        let same_day = |t: &(Option<NaiveDateTime>, &str)| match (t.0, parsed) {
+            (Some(x), Some(y)) if detail == TimeDetail::Days => x.date() == y.date(),
+            (Some(x), Some(y)) => x == y,
+            _ => t.1 == raw,
+        };
+        let run = sorted[i..].iter().take_while(|t| same_day(t)).count();
+        let last = sorted[i + run - 1].0;
+        ret.push(match (detail, parsed, last) {
+            (TimeDetail::Days, Some(first), Some(last)) if first != last => {
+                let (from, to) = (
+                    first.format("%-I:%M %p").to_string(),
+                    last.format("%-I:%M %p").to_string(),
+                );
+                // Jul 18 1:00–4:30 PM, or 9:15 AM–4:30 PM across noon
+                let from = match (from.strip_suffix(" AM"), from.strip_suffix(" PM")) {
+                    (Some(f), _) if to.ends_with(" AM") => f.to_string(),
+                    (_, Some(f)) if to.ends_with(" PM") => f.to_string(),
+                    _ => from,
+                };
+                format!("{} {}–{}, {} alerts", first.format("%b %-d"), from, to, run)
+            }
+            (TimeDetail::Days, Some(first), _) => {
+                format!("{}{}", first.format("%b %-d %-I:%M %p"), times_suffix(run))
+            }
+            _ => format!("{}{}", raw, times_suffix(run)),
+        });
+        i += run;
+    }
+    ret
+}
+
+impl<K: BatchKey, I: BatchKey> From<Entry<K, I>> for BatchEntry<K, I> {
+    fn from(e: Entry<K, I>) -> Self {
         let mut alerts = Alerts::new();
         let times = vec![e.time];
         alerts.entry(e.product).or_insert(times);
@@ -196,41 +507,27 @@ impl From<Entry> for BatchEntry {
     }
 }
 
-type Entries = HashMap<UserID, BatchEntry>;
+pub type Entries<K = UserID, I = Product> = HashMap<K, BatchEntry<K, I>>;
 
-#[derive(Debug, PartialEq)]
-pub struct Entry {
-    pub id: UserID,
-    pub product: Product,
+/// One alert: who, for what, and when
+/// Other alert formats reuse the same fields for their own grouping key and item
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(bound = "K: BatchKey, I: BatchKey")]
+pub struct Entry<K = UserID, I = Product> {
+    pub id: K,
+    pub product: I,
     pub time: String,
 }
 
 impl Entry {
-    fn from_email(e: &Email) -> Result<Self> {
-        lazy_static! {
-            // this should go with the splitting logic, and we should pass in a struct to from_email
-            static ref AD_RE: Regex = Regex::new(r"From:.+\s+Sent:\s+(?P<date>.+)\r\nTo:.+\s+Subject:.+\s+The \w+ Invoice For iMIS ID (?P<id>\d+) For the Product (?P<product>\w+) Has Changed\s+You need to verify the Autodraft is now correct").unwrap();
-        }
-        let s = &e.contents;
-
-        if AD_RE.is_match(s) {
-            debug!("MATCH: {}", s);
-            let ad_captures = AD_RE.captures(s).unwrap();
-            Ok(Entry {
-                id: (&ad_captures["id"])
-                    .parse::<u32>()
-                    .chain_err(|| "Could not read iMIS id")?,
-                product: Product::from_str(&ad_captures["product"])?,
-                time: ad_captures["date"].to_string(),
-            })
-        } else {
-            debug!("{}", s);
-            panic!("Couldn't match Regex") // TODO error
-        }
+    /// Pulls the iMIS ID, product and alert time out of an autodraft alert
+    /// Errors if the email doesn't look like one
+    pub fn from_email(e: &Email) -> Result<Self> {
+        Autodraft.parse(e)
     }
 }
 
-impl fmt::Display for Entry {
+impl<K: BatchKey, I: BatchKey> fmt::Display for Entry<K, I> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -240,19 +537,28 @@ impl fmt::Display for Entry {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum EntryClass {
-    Duplicate((UserID, Product)),
+/// How a new Entry relates to what's already in the Batch
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum EntryClass<K = UserID, I = Product> {
+    Duplicate((K, I)),
+    #[default]
     New,
-    NewProduct(UserID),
+    NewProduct(K),
 }
 
-impl Default for EntryClass {
-    fn default() -> Self {
-        EntryClass::New
+impl<K: BatchKey, I: BatchKey> fmt::Display for EntryClass<K, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryClass::Duplicate((id, product)) => {
+                write!(f, "duplicate - another {} alert for {}", product, id)
+            }
+            EntryClass::New => write!(f, "new"),
+            EntryClass::NewProduct(id) => write!(f, "new product for {}", id),
+        }
     }
 }
 
+/// The products we know by name.  Anything else is kept as its raw code
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Product {
     CgBilling,
@@ -264,6 +570,23 @@ pub enum Product {
     Other(String),
 }
 
+impl Product {
+    /// The raw iMIS product code, as it appears in the alert
+    pub fn code(&self) -> &str {
+        use self::Product::*;
+
+        match self {
+            CgBilling => "CG_TUITION",
+            CgTrans => "CG_TRANS",
+            CampKaleTuit => "CAMP_KALE_TUIT",
+            CampKaleTrans => "CAMP_KALE_TRANS",
+            CampKingTuit => "CAMP_KING_TUIT",
+            CampKingTrans => "CAMP_KING_TRANS",
+            Other(s) => s,
+        }
+    }
+}
+
 impl fmt::Display for Product {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Product::*;
@@ -297,7 +620,49 @@ impl FromStr for Product {
     }
 }
 
-type UserID = u32;
+// Products sort by product code, the same order they're listed in everywhere
+impl Ord for Product {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.code().cmp(other.code())
+    }
+}
+
+impl PartialOrd for Product {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Products are serialized as their product code so Other(_) round-trips cleanly
+impl Serialize for Product {
+    fn serialize<S: Serializer>(&self, s: S) -> ::std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Product {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> ::std::result::Result<Self, D::Error> {
+        let code = String::deserialize(d)?;
+        Product::from_str(&code).map_err(serde::de::Error::custom)
+    }
+}
+
+pub type UserID = u32;
+
+/// Alert times are kept as the raw Sent: string - this pulls a real timestamp out for sorting
+pub fn parse_alert_time(s: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s.trim(), "%A, %B %d, %Y %I:%M %p").ok()
+}
+
+/// Sorts parsed alert times in order, then anything that didn't parse - a stable sort keeps those as they came
+pub fn alert_time_order(a: Option<NaiveDateTime>, b: Option<NaiveDateTime>) -> Ordering {
+    match (a, b) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -440,4 +805,131 @@ mod tests {
         let test_entry = Entry::from_email(&Email::from_str(TEST_DIF_ID).unwrap()).unwrap();
         assert_eq!(test_batch.classify(&test_entry), EntryClass::New)
     }
+    #[test]
+    fn test_parse_alert_time() {
+        assert_eq!(
+            parse_alert_time("Wednesday, July 18, 2018 1:00 PM"),
+            Some(NaiveDateTime::parse_from_str("2018-07-18 13:00", "%Y-%m-%d %H:%M").unwrap())
+        );
+        assert_eq!(parse_alert_time("sometime last week"), None);
+    }
+    #[test]
+    fn test_product_code_round_trip() {
+        for code in &["CG_TUITION", "CAMP_KING_TRANS", "COOL_PROD"] {
+            assert_eq!(Product::from_str(code).unwrap().code(), *code)
+        }
+    }
+    #[test]
+    fn test_batch_json_round_trip() {
+        let mut batch = Batch::test_second_email_str(TEST_DIF_BOTH);
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_DIF_PROD).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(Batch::from_json(&batch.to_json().unwrap()).unwrap(), batch)
+    }
+    #[test]
+    fn test_batch_toml_round_trip() {
+        let mut batch = Batch::test_second_email_str(TEST_DIF_BOTH);
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_COOL_STR).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(Batch::from_toml(&batch.to_toml().unwrap()).unwrap(), batch)
+    }
+    #[test]
+    fn test_filter_products() {
+        let sample = Batch::sample();
+        let tuition =
+            sample.filter_products(|p| p.code().ends_with("_TUIT") || *p == Product::CgBilling);
+        assert_eq!(tuition.entries.len(), 2);
+        assert_eq!(
+            tuition.entries[&100001].alerts.keys().collect::<Vec<_>>(),
+            vec![&Product::CgBilling]
+        );
+        assert!(!sample
+            .filter_products(|p| *p == Product::CgTrans)
+            .entries
+            .contains_key(&100002));
+    }
+    #[test]
+    fn test_squash_times() {
+        let times = |ts: &[&str]| ts.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        let mut alerts = times(&["Wednesday, July 18, 2018 4:30 PM"]);
+        alerts.extend(vec!["Wednesday, July 18, 2018 1:00 PM".to_string(); 5]);
+        alerts.extend(times(&[
+            "Wednesday, July 18, 2018 2:00 PM",
+            "Thursday, July 19, 2018 9:15 AM",
+            "Friday, July 20, 2018 9:15 AM",
+            "Friday, July 20, 2018 4:30 PM",
+            "Saturday, July 21, 2018 1:00 PM",
+            "Saturday, July 21, 2018 1:00 PM",
+            "yesterday",
+            "yesterday",
+        ]));
+
+        assert_eq!(squash_times(&alerts, TimeDetail::All), alerts);
+        assert_eq!(
+            squash_times(&alerts, TimeDetail::Counted),
+            times(&[
+                "Wednesday, July 18, 2018 1:00 PM ×5",
+                "Wednesday, July 18, 2018 2:00 PM",
+                "Wednesday, July 18, 2018 4:30 PM",
+                "Thursday, July 19, 2018 9:15 AM",
+                "Friday, July 20, 2018 9:15 AM",
+                "Friday, July 20, 2018 4:30 PM",
+                "Saturday, July 21, 2018 1:00 PM ×2",
+                "yesterday ×2",
+            ])
+        );
+        assert_eq!(
+            squash_times(&alerts, TimeDetail::Days),
+            times(&[
+                "Jul 18 1:00–4:30 PM, 7 alerts",
+                "Jul 19 9:15 AM",
+                "Jul 20 9:15 AM–4:30 PM, 2 alerts",
+                "Jul 21 1:00 PM ×2",
+                "yesterday ×2",
+            ])
+        );
+    }
+    #[test]
+    fn test_batch_to_text() {
+        let mut batch = Batch::test();
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_COOL_STR).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(
+            batch.to_string(),
+            "Current Batch:\n12345: Non-builtin product COOL_PROD at Saturday, July 21, 2018 4:39 PM ×2\n"
+        );
+        assert_eq!(
+            batch.to_text(TimeDetail::Days),
+            "Current Batch:\n12345: Non-builtin product COOL_PROD at Jul 21 4:39 PM ×2\n"
+        );
+    }
+    #[test]
+    fn test_batch_json_schema() {
+        assert_eq!(
+            Batch::test().to_json().unwrap(),
+            r#"{
+  "schema_version": 1,
+  "entries": [
+    {
+      "id": 12345,
+      "alerts": [
+        {
+          "product": "COOL_PROD",
+          "times": [
+            "Saturday, July 21, 2018 4:39 PM"
+          ]
+        }
+      ]
+    }
+  ]
+}"#
+        )
+    }
+    #[test]
+    fn test_batch_rejects_unknown_schema_version() {
+        assert!(<Batch>::from_json(r#"{"schema_version": 99, "entries": []}"#).is_err())
+    }
 }
@@ -1,16 +1,17 @@
 // brain.rs handles all internal storage directory access
-use crate::{config::Config, email::Email, errors::*, util::*};
+use crate::{config::Config, email::Email, errors::*, journal::recover, lock::BrainLock, util::*};
 use lazy_static::lazy_static;
 use log::*;
 use regex::Regex;
 use std::{
-    fmt, fs::{create_dir, read_dir}, path::PathBuf,
+    fmt,
+    fs::{create_dir, read_dir},
+    path::{Path, PathBuf},
 };
-use uuid::Uuid;
 
-// This is my internal folder
 // I want to be able to serialize/deserialize the contents
-#[derive(Debug)]
+/// Every email currently in the brain directory
+#[derive(Debug, Default)]
 pub struct Brain {
     pub emails: Vec<Email>,
 }
@@ -32,34 +33,80 @@ impl fmt::Display for Brain {
     }
 }
 
-// This is the running app state.  Is State a better name?
+// Is State a better name?
+/// The running app state: the config, and the brain it points at
+/// Holds the lock on the brain until it's dropped, or unlock() is called
 #[derive(Debug)]
 pub struct Context {
     pub config: Config,
     pub brain: Brain,
+    lock: Option<BrainLock>,
 }
 
 impl Context {
-    // Take ownership over the fresh ones passed in
+    /// Takes ownership of the config, locks the brain it points at and reads it in
+    /// Fails with ErrorKind::Locked if another run has it
     pub fn initialize(config: Config) -> Result<Self> {
         let mut ctx = Context {
             config,
             brain: Brain::new(),
+            lock: None,
         };
 
+        ctx.relock()?;
         ctx.read_fs()?;
         Ok(ctx)
     }
 
+    /// Lets other runs at the brain, for something long-running that's between reads
+    pub fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    /// Takes the lock back after unlock() - read_fs() again before trusting the brain
+    /// Any digest a run died part way through cutting is finished or rolled back first
+    pub fn relock(&mut self) -> Result<()> {
+        if self.lock.is_none() {
+            let brain_path = &self.config.directory.path;
+            if !brain_path.exists() {
+                warn!("No brain found!  Creating...");
+                create_dir(brain_path).chain_err(|| "Could not create brain dir")?;
+            }
+            self.lock = Some(BrainLock::acquire(
+                brain_path,
+                self.config.directory.stale_lock(),
+            )?);
+            for recovery in recover(brain_path, &self.hx_path())? {
+                warn!("{}", recovery);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where digests and archived emails go
     pub fn hx_path(&self) -> PathBuf {
         let mut ret = self.config.directory.path.clone();
         ret.push("hx");
         ret
     }
 
-    // Reads the brain dir into memory from the dir specified in config.  If no brain exists, makes a new one
+    /// Every input file in the brain - everything but hx/
+    pub fn input_paths(&self) -> Result<Vec<PathBuf>> {
+        let hx_path = self.hx_path();
+        let mut ret = Vec::new();
+        for f in read_dir(&self.config.directory.path).chain_err(|| "Could not read brain!")? {
+            let path = f.chain_err(|| "Could not read brain entry")?.path();
+            if path != hx_path && is_input(&path) {
+                ret.push(path);
+            }
+        }
+        ret.sort();
+        Ok(ret)
+    }
+
     // this is still prpbably all relevant
     // TODO proper Path usage.  Lets start here.
+    /// Reads the brain dir into memory from the dir specified in config.  If no brain exists, makes a new one
     pub fn read_fs(&mut self) -> Result<()> {
         //keeping in case I need this for the report
         //lazy_static! {
@@ -85,10 +132,12 @@ impl Context {
 
         // There will be a cleanup task (maybe as part of report() that will push everything to hx)
         // dir_lisitng holds str paths of each file in Brain
-        let dir_listing: Vec<PathBuf> = read_dir(brain_path)
+        // Sorted, so the batch comes out the same on every filesystem
+        let mut dir_listing: Vec<PathBuf> = read_dir(brain_path)
             .chain_err(|| "Could not read brain!")?
             .map(|f| f.expect("Could not read brain entry").path())
             .collect();
+        dir_listing.sort();
 
         // Grab the current batch
         // Save any emails
@@ -102,6 +151,9 @@ impl Context {
             {
                 debug!("Skipping hx dir {}", p_str);
                 continue;
+            } else if !is_input(l) {
+                debug!("Skipping hidden file {}", p_str);
+                continue;
             } else {
                 // TODO check if its actually an email?
                 // what do we do with non-expected files?
@@ -110,13 +162,7 @@ impl Context {
                 // When moving to hx/ I want to store each one separately still.
                 // this will make it much easier to include this info in the daily report
                 info!("READ: {}", p_str);
-                let contents = file_contents_from_str_path(p_str)?;
-                let email_files = split_emails(&contents);
-                for e in email_files {
-                    let filename = Uuid::new_v4(); // Just assigns a random number
-                    emails.push(Email::new(&filename.to_string(), &e)
-                        .chain_err(|| "Could not add email")?);
-                }
+                emails.extend(read_email_file(l)?);
             }
         }
 
@@ -165,6 +211,39 @@ impl Context {
     //    }
 }
 
+/// Whether a file in the brain is an alert to read
+/// Hidden files aren't - that's the lock, half-written files and whatever the OS leaves around
+pub fn is_input(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| !n.starts_with('.'))
+}
+
+/// Reads every email out of one file in the brain
+/// Each is named after the file it came from, so errors can point somewhere useful
+pub fn read_email_file(path: &Path) -> Result<Vec<Email>> {
+    let p_str = path
+        .to_str()
+        .chain_err(|| "Email path is not valid unicode")?;
+    let contents = file_contents_from_str_path(p_str)?;
+    let email_files = split_emails(&contents);
+    let source = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .chain_err(|| "Could not read email filename")?;
+    let count = email_files.len();
+    let mut emails = Vec::new();
+    for (i, e) in email_files.iter().enumerate() {
+        let filename = if count == 1 {
+            source.to_string()
+        } else {
+            format!("{}#{}", source, i + 1)
+        };
+        emails.push(Email::new(&filename, e).chain_err(|| "Could not add email")?);
+    }
+    Ok(emails)
+}
+
 // split_emails takes a string containing multiple emails and returns a vec with each email separated
 fn split_emails(s: &str) -> Vec<String> {
     info!("Splitting input file into separate emails");
@@ -188,19 +267,82 @@ fn split_emails(s: &str) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    //use super::*;
+    use super::*;
+    use crate::test_util::TempBrain;
+    use std::{
+        fs::{create_dir_all, remove_dir, remove_file, write},
+        str::FromStr,
+    };
 
-    //#[test]
-    //fn test_initialize_empty() {
+    fn filenames(ctx: &Context) -> Vec<&str> {
+        ctx.brain
+            .emails
+            .iter()
+            .map(|e| e.filename.as_str())
+            .collect()
+    }
 
-    //    }
+    #[test]
+    fn test_initialize_empty() {
+        let brain = TempBrain::new();
+        remove_dir(&*brain).unwrap();
+        let ctx = brain.context();
+        // A missing brain is made, and there's nothing in it
+        assert!(brain.is_dir());
+        assert!(ctx.brain.emails.is_empty());
+        assert!(ctx.input_paths().unwrap().is_empty());
+    }
 
-    //#[test]
-    //fn test_initialize_not_empty() {
+    #[test]
+    fn test_initialize_not_empty() {
+        let brain = TempBrain::new();
+        create_dir_all(brain.join("hx").join("1531915200")).unwrap();
+        let email = Email::from_str(TEST_COOL_STR).unwrap();
+        for name in &["b.txt", "a.txt"] {
+            write(brain.join(name), &email.contents).unwrap();
+        }
+        // Archived emails and digests aren't part of the brain
+        write(
+            brain.join("hx").join("1531915200").join("c.txt"),
+            &email.contents,
+        )
+        .unwrap();
+        write(brain.join("hx").join("digest-1531915200.html"), "digest").unwrap();
+
+        let mut ctx = brain.context();
+        assert_eq!(filenames(&ctx), vec!["a.txt", "b.txt"]);
+        assert_eq!(ctx.brain.emails[0].contents, email.contents);
+
+        // read_fs picks up what's changed since
+        write(brain.join("0.txt"), &email.contents).unwrap();
+        remove_file(brain.join("b.txt")).unwrap();
+        ctx.read_fs().unwrap();
+        assert_eq!(filenames(&ctx), vec!["0.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_initialize_locks() {
+        let brain = TempBrain::new();
+        let mut ctx = brain.context();
+        write(
+            brain.join("a.txt"),
+            &Email::from_str(TEST_COOL_STR).unwrap().contents,
+        )
+        .unwrap();
+        write(brain.join(".a.txt.tmp"), "half an email").unwrap();
+        ctx.read_fs().unwrap();
+        // Neither the lock nor the temp file is an input
+        assert_eq!(ctx.brain.emails.len(), 1);
+        assert_eq!(ctx.input_paths().unwrap(), vec![brain.join("a.txt")]);
 
-    //}
+        let err = Context::initialize(brain.config()).unwrap_err();
+        assert_eq!(err.category(), Category::Locked);
+        ctx.unlock();
+        let _other = brain.context();
+        assert!(ctx.relock().is_err());
+    }
     #[test]
     fn test_split_emails() {
-        assert!(true);
+        assert_eq!(split_emails(TEST_COOL_STR), vec![TEST_COOL_STR.to_string()]);
     }
 }
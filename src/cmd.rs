@@ -1,34 +1,369 @@
 // cmd.rs holds the top-level commands, all returning errors::Result<_>
-use crate::{batch::Batch, brain::Context, config::init_config, errors::*, page::*};
-use clap::{App, Arg};
-use pretty_env_logger;
+use ar_bot::{
+    auto::{due, in_schedule},
+    batch::{Batch, Entry, Product, TimeDetail, UserID},
+    brain::Context,
+    config::{
+        check, get_value, load, set_value, write_default_config, Config, Origins, Sources,
+        DEFAULT_CONFIG,
+    },
+    errors::*,
+    export::Export,
+    history::{self, History},
+    imap,
+    journal::{cut_digest, recover, Delivery},
+    lock::BrainLock,
+    page::*,
+    review::{Exclusion, Review},
+    template,
+    tui::{self, Outcome},
+    watch::watch,
+};
+use chrono::Local;
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind as ClapErrorKind, SubCommand};
 use log::*;
+use regex::Regex;
 use std::{
-    env::{remove_var, set_var, var}, fs::create_dir,
+    collections::BTreeMap,
+    env::{remove_var, set_var, var},
+    fs::create_dir,
+    io::{stdin, stdout, BufRead, IsTerminal, Write},
+    path::PathBuf,
+    str::FromStr,
+    thread::sleep,
+    time::Duration,
 };
+use toml::Value;
+
+static VERSION: &str = "0.1.0"; // read from CARGO!
+
+// The codes themselves live in errors, where Error::exit_code() picks one
+static EXIT_CODES: &str = "EXIT CODES:
+    0    Success
+    1    Any other error - the message says what went wrong
+    2    Bad command line
+    3    Nothing found (search, config get)
+    4    Config missing, unreadable or invalid (and config check finding errors)
+    5    Reading or writing a file failed
+    6    An alert email or batch couldn't be parsed
+    7    A digest template is missing or didn't render
+    8    The digest or its inputs couldn't be archived to hx/
+    9    Sending by SMTP or fetching by IMAP failed
+    10   Another ar-bot run holds the lock on the brain
+
+With --json, an error is printed to stdout as
+    {\"error\": {\"category\": \"config\", \"exit_code\": 4, \"message\": \"...\", \"causes\": [...]}}";
+
+// What --json prints in place of the usual error message
+fn error_json(e: &Error) -> serde_json::Value {
+    let causes: Vec<String> = e.iter().skip(1).map(|c| c.to_string()).collect();
+    serde_json::json!({
+        "error": {
+            "category": e.category().to_string(),
+            "exit_code": e.exit_code(),
+            "message": e.to_string(),
+            "causes": causes,
+        }
+    })
+}
+
+// How preview writes the batch out.  Json and Toml follow the versioned batch schema
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Toml,
+}
 
-static VERSION: &'static str = "0.1.0";  // read from CARGO!
+impl FromStr for Format {
+    type Err = Error;
 
-// Does two things:
-// 1. Creates DATETIME.digest.html under hx/
-// 2. Moves every email used in the batch into DATETIME/ and compresses it.
-fn digest(ctx: &Context) -> Result<()> {
-    let hx_path = ctx.hx_path();
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("Unknown output format {}", s).into()),
+        }
+    }
+}
 
-    if !hx_path.exists() {
-        warn!("No history found!  Creating...");
-        create_dir(hx_path).chain_err(|| "Could not create history dir")?;
+// Cuts the digest - see journal::cut_digest - and says where it went
+fn digest(
+    ctx: &Context,
+    review: &Review,
+    renderer: Renderer,
+    exports: &[Export],
+    delivery: Delivery,
+    split: bool,
+) -> Result<()> {
+    let cut = cut_digest(ctx, review, renderer, exports, delivery, split)?;
+    for recipients in &cut.sent {
+        println!("Digest sent to {}", recipients.join(", "));
+    }
+    for eml_path in &cut.emls {
+        println!("Dry run - digest email written to {:?}", eml_path);
     }
+    Ok(())
+}
 
-    write_digest(ctx)?;
+// Cuts a digest if [auto] says one is due, rendered, exported and sent the way [auto] and [digest] say
+// With daemon, keeps checking every interval_minutes instead of returning
+fn auto(ctx: &mut Context, daemon: bool) -> Result<()> {
+    let auto_config = ctx
+        .config
+        .auto
+        .clone()
+        .chain_err(|| "Can't run auto without an [auto] section in the config")?;
+    let delivery = if auto_config.send {
+        Delivery::Send
+    } else {
+        Delivery::Keep
+    };
+
+    loop {
+        let now = Local::now().naive_local();
+        let review = Review::new(ctx);
+        let batch = review.batch()?;
+        let result = if !in_schedule(&auto_config, now)? {
+            println!(
+                "Outside the [auto] schedule - {} IDs pending",
+                batch.entries.len()
+            );
+            Ok(())
+        } else {
+            match due(&auto_config, &batch, now) {
+                Some(reason) => {
+                    println!("Cutting a digest: {}", reason);
+                    digest(
+                        ctx,
+                        &review,
+                        ctx.config.digest.render,
+                        &auto_config.exports,
+                        delivery,
+                        ctx.config.digest.split,
+                    )
+                }
+                None => {
+                    println!("No digest due - {} IDs pending", batch.entries.len());
+                    Ok(())
+                }
+            }
+        };
+
+        if !daemon {
+            return result;
+        }
+        if let Err(e) = result {
+            error!("Could not cut digest: {}", e);
+        }
+        // Nobody else could cut a digest by hand if the lock were held while sleeping
+        ctx.unlock();
+        loop {
+            sleep(Duration::from_secs(auto_config.interval_minutes * 60));
+            match ctx.relock() {
+                Ok(()) => break,
+                Err(e) => warn!("Skipping this check: {}", e),
+            }
+        }
+        ctx.read_fs()?;
+    }
+}
+
+// Pulls any new alerts from the mailbox under [imap] into the brain
+fn fetch(config: &Config) -> Result<()> {
+    let imap_config = config
+        .imap
+        .as_ref()
+        .chain_err(|| "Can't fetch without an [imap] section in the config")?;
+    let brain_path = &config.directory.path;
+    if !brain_path.exists() {
+        warn!("No brain found!  Creating...");
+        create_dir(brain_path).chain_err(|| "Could not create brain dir")?;
+    }
+    let _lock = BrainLock::acquire(brain_path, config.directory.stale_lock())?;
+    let summary = imap::fetch(imap_config, brain_path)?;
+    println!(
+        "Fetched {} new alerts from {} ({} already in the brain, {} other messages left alone)",
+        summary.fetched, imap_config.folder, summary.existing, summary.skipped
+    );
+    Ok(())
+}
+
+// Finishes or rolls back any digest a run died part way through cutting
+// Every command that reads the brain does this first anyway - this says what it did
+fn recover_digests(config: &Config) -> Result<()> {
+    let brain_path = &config.directory.path;
+    if !brain_path.exists() {
+        println!("Nothing to recover - there's no brain at {:?}", brain_path);
+        return Ok(());
+    }
+    let _lock = BrainLock::acquire(brain_path, config.directory.stale_lock())?;
+    let recovered = recover(brain_path, &brain_path.join("hx"))?;
+    if recovered.is_empty() {
+        println!("Nothing to recover - every digest was cut cleanly");
+    }
+    for recovery in recovered {
+        println!("{}", recovery);
+    }
     Ok(())
 }
 
 // Outputs the batch to the console
 // This just reads the emails in the folder and displays what the digest would look like
 // if we ran that command now, but makes no changes.
-fn preview(ctx: &mut Context) -> Result<()> {
-    println!("{}\n", Batch::from_brain(&ctx.brain)?);
+fn preview(ctx: &mut Context, format: Format) -> Result<()> {
+    let review = Review::new(ctx);
+    for (name, why) in &review.unmatched {
+        warn!("SKIP: {} - {}", name, why);
+    }
+    let batch = review.batch()?;
+    // json and toml keep to the batch schema
+    if format == Format::Text {
+        let annotations = annotate(ctx, &batch)?;
+        print!(
+            "{}",
+            preview_text(&batch, &annotations, ctx.config.digest.times)
+        );
+    } else {
+        print_batch(&batch, format, ctx.config.digest.times)?;
+    }
+    Ok(())
+}
+
+// Shows what the digest will use up and asks before cutting it, until the answer is yes or no
+// Answering e leaves IDs or products out of this digest - they stay pending for the next one
+fn confirm<R: BufRead, W: Write>(review: &mut Review, mut input: R, mut out: W) -> Result<bool> {
+    loop {
+        let mut text = format!("{}\n\n", review.batch()?);
+        if !review.excluded.is_empty() {
+            let excluded: Vec<String> = review.excluded.iter().map(|x| x.to_string()).collect();
+            text.push_str(&format!("Excluded: {}\n", excluded.join(", ")));
+        }
+        text.push_str(&format!("{}\n", review.summary()?));
+        for (name, why) in &review.unmatched {
+            text.push_str(&format!("  {}: {}\n", name, why));
+        }
+        text.push_str("\nCut this digest? [y]es, [n]o, [e]xclude IDs or products: ");
+        prompt(&mut out, &text)?;
+
+        match read_answer(&mut input)?.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" | "" => return Ok(false),
+            "e" | "exclude" => {
+                prompt(
+                    &mut out,
+                    "IDs or product codes to leave out, separated by spaces - +ID puts one back: ",
+                )?;
+                for word in read_answer(&mut input)?.split_whitespace() {
+                    let (put_back, word) = match word.strip_prefix('+') {
+                        Some(w) => (true, w),
+                        None => (false, word),
+                    };
+                    let exclusion = Exclusion::from_str(word)?;
+                    if put_back {
+                        review.excluded.remove(&exclusion);
+                    } else if review.mentions(&exclusion) {
+                        review.excluded.insert(exclusion);
+                    } else {
+                        prompt(
+                            &mut out,
+                            &format!("Nothing pending for {} - skipped\n", exclusion),
+                        )?;
+                    }
+                }
+            }
+            other => prompt(&mut out, &format!("Didn't understand {:?}\n", other))?,
+        }
+    }
+}
+
+fn prompt<W: Write>(out: &mut W, text: &str) -> Result<()> {
+    out.write_all(text.as_bytes())
+        .and_then(|_| out.flush())
+        .chain_err(|| "Could not write prompt")
+}
+
+// One line, trimmed - an empty string at the end of input
+fn read_answer<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    input
+        .read_line(&mut line)
+        .chain_err(|| "Could not read answer")?;
+    Ok(line.trim().to_string())
+}
+
+// The digest subcommand: reviews what's pending and asks first, unless --yes
+fn review_and_digest(ctx: &Context, matches: &ArgMatches) -> Result<()> {
+    let (renderer, exports, delivery, split) = digest_options(ctx, matches)?;
+    let mut review = Review::new(ctx);
+    for value in matches.values_of("exclude").into_iter().flatten() {
+        let exclusion = Exclusion::from_str(value)?;
+        if !review.mentions(&exclusion) {
+            warn!("Nothing pending for {} to exclude", exclusion);
+        }
+        review.excluded.insert(exclusion);
+    }
+
+    if !matches.is_present("yes") {
+        if !stdin().is_terminal() {
+            return Err(
+                "Not cutting a digest without asking first - pass --yes to skip the question"
+                    .into(),
+            );
+        }
+        if !confirm(&mut review, stdin().lock(), stdout())? {
+            println!("Nothing was cut - everything is still pending");
+            return Ok(());
+        }
+    }
+    digest(ctx, &review, renderer, &exports, delivery, split)
+}
+
+// The tui subcommand: browse the batch, then cut a digest without whatever was held back
+fn browse_and_digest(ctx: &Context, matches: &ArgMatches) -> Result<()> {
+    let (renderer, exports, delivery, split) = digest_options(ctx, matches)?;
+    if !stdin().is_terminal() || !stdout().is_terminal() {
+        return Err("The terminal UI needs a terminal - try `ar-bot preview` instead".into());
+    }
+    let (outcome, held) = tui::browse(ctx)?;
+    if outcome == Outcome::Quit {
+        return Ok(());
+    }
+
+    let mut review = Review::new(ctx);
+    review.excluded.extend(held.into_iter().map(Exclusion::Id));
+    if !matches.is_present("yes") && !confirm(&mut review, stdin().lock(), stdout())? {
+        println!("Nothing was cut - everything is still pending");
+        return Ok(());
+    }
+    digest(ctx, &review, renderer, &exports, delivery, split)
+}
+
+// Times are only squashed as text - json and toml keep every one
+fn print_batch(batch: &Batch, format: Format, detail: TimeDetail) -> Result<()> {
+    match format {
+        Format::Text => println!("{}\n", batch.to_text(detail)),
+        Format::Json => println!("{}", batch.to_json()?),
+        Format::Toml => print!("{}", batch.to_toml()?),
+    }
+    Ok(())
+}
+
+// Renders every digest template in the directory against sample data
+// Uses the directory given, or template_dir from the config
+fn template_check(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let dir = match matches.value_of("DIR") {
+        Some(d) => PathBuf::from(d),
+        None => config
+            .digest
+            .template_dir
+            .clone()
+            .chain_err(|| "No template directory given, and none set in the config")?,
+    };
+    for name in template::check(&dir)? {
+        println!("{:?}/{}: OK", dir, name);
+    }
     Ok(())
 }
 
@@ -48,110 +383,911 @@ fn report(_ctx: &Context) -> Result<()> {
     Ok(())
 }
 
-// This is the entrypoint - essentially main()
-pub fn run() -> Result<()> {
-    // clap config
-    let matches = App::new("ar-bot")
+// Finds anything matching the pattern in the brain and, unless pending_only, everything archived in hx/
+// Exits EXIT_NOT_FOUND if nothing matched, like grep
+fn search(ctx: &Context, matches: &ArgMatches) -> Result<i32> {
+    let pattern = matches
+        .value_of("PATTERN")
+        .chain_err(|| "No search pattern given")?;
+    let pattern = if matches.is_present("ignore_case") {
+        format!("(?i){}", pattern)
+    } else {
+        pattern.to_string()
+    };
+    let re = Regex::new(&pattern).chain_err(|| format!("Bad search pattern {}", pattern))?;
+
+    let hits = history::search(ctx, &re, matches.is_present("pending"))?;
+    for hit in &hits {
+        let place = match hit.digest {
+            Some(ts) => format!("hx/{}", ts),
+            None => "pending".into(),
+        };
+        match Entry::from_email(&hit.email) {
+            Ok(entry) => println!("{}/{}: {}", place, hit.email.filename, entry),
+            Err(_) => println!("{}/{}", place, hit.email.filename),
+        }
+    }
+    if hits.is_empty() {
+        warn!("Nothing matched {}", pattern);
+        Ok(EXIT_NOT_FOUND)
+    } else {
+        Ok(EXIT_OK)
+    }
+}
+
+// Prints a single setting, defaults included, so scripts can read it
+// Exits EXIT_NOT_FOUND if the key isn't set
+fn config_get(config: &Config, matches: &ArgMatches) -> Result<i32> {
+    let key = matches.value_of("KEY").chain_err(|| "No key given")?;
+    match get_value(config, key)? {
+        Some(Value::String(s)) => println!("{}", s),
+        Some(v) => println!("{}", v),
+        None => {
+            warn!("{} is not set", key);
+            return Ok(EXIT_NOT_FOUND);
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+// Prints every setting, defaults included, as TOML that would load as-is
+// With --origin, one line per setting saying which layer it came from instead
+fn config_show(config: &Config, origins: &Origins, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("origin") {
+        for (key, origin) in origins {
+            let value = get_value(config, key)?.map_or_else(String::new, |v| v.to_string());
+            println!("{} = {}  # {}", key, value, origin);
+        }
+        return Ok(());
+    }
+    if let Some(path) = &config.config_path {
+        println!("# Loaded from {}", path);
+    }
+    print!(
+        "{}",
+        toml::to_string(config).chain_err(|| "Could not write config as TOML")?
+    );
+    Ok(())
+}
+
+// Every problem with the config, one per line, then a count - or with --json, all of it as one object
+// Exits EXIT_CONFIG if there were errors, or with --strict any warnings
+fn config_check(sources: &Sources, matches: &ArgMatches, json: bool) -> Result<i32> {
+    let checked = check(sources)?;
+    let (errors, warnings) = (checked.errors(), checked.warnings());
+    if json {
+        let report = serde_json::json!({
+            "errors": errors,
+            "warnings": warnings,
+            "diagnostics": checked.diagnostics,
+        });
+        println!("{}", report);
+    } else {
+        for d in &checked.diagnostics {
+            println!("{}", d);
+        }
+        if errors + warnings == 0 {
+            println!("Config OK");
+        } else {
+            println!("{} error(s), {} warning(s)", errors, warnings);
+        }
+    }
+    if errors > 0 || (warnings > 0 && matches.is_present("strict")) {
+        Ok(EXIT_CONFIG)
+    } else {
+        Ok(EXIT_OK)
+    }
+}
+
+// Changes a single setting in the config file, refusing anything that wouldn't load
+// With --profile it goes in that profile's section
+fn config_set(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let key = matches.value_of("KEY").chain_err(|| "No key given")?;
+    let key = match &config.profile {
+        Some(profile) => format!("profile.{}.{}", profile, key),
+        None => key.to_string(),
+    };
+    let value = matches.value_of("VALUE").chain_err(|| "No value given")?;
+    let path = config
+        .config_path
+        .as_ref()
+        .chain_err(|| "Don't know which config file to change")?;
+    set_value(path, &key, value)?;
+    println!("{}: {} = {}", path, key, value);
+    Ok(())
+}
+
+// One line per digest in hx/
+fn history_list(ctx: &Context) -> Result<()> {
+    let records = history::list(&ctx.hx_path())?;
+    if records.is_empty() {
+        println!("No digests yet");
+    }
+    for record in records {
+        let files: Vec<String> = record
+            .files
+            .iter()
+            .filter_map(|f| f.file_name().and_then(|n| n.to_str()).map(String::from))
+            .collect();
+        let archived = match record.archive {
+            Some(_) => format!("{} emails archived", record.emails()?.len()),
+            None => "no archive".into(),
+        };
+        println!(
+            "{}  {}  {} ({})",
+            record.timestamp,
+            record.generated(),
+            files.join(", "),
+            archived
+        );
+    }
+    Ok(())
+}
+
+// The batch a past digest was cut from, rebuilt from its archive
+fn history_show(ctx: &Context, matches: &ArgMatches) -> Result<()> {
+    let timestamp = matches
+        .value_of("TIMESTAMP")
+        .chain_err(|| "No timestamp given")?
+        .parse()
+        .chain_err(|| "TIMESTAMP should be a number, as shown by `ar-bot history list`")?;
+    let format = Format::from_str(matches.value_of("format").unwrap_or("text"))?;
+    let record = history::find(&ctx.hx_path(), timestamp)?;
+    if record.archive.is_none() {
+        return Err(format!("Digest {} has no archived emails to show", timestamp).into());
+    }
+    if format == Format::Text {
+        println!("Digest {} - cut {}", record.timestamp, record.generated());
+        for f in &record.files {
+            println!("* {:?}", f);
+        }
+        println!();
+    }
+    print_batch(&record.batch()?, format, ctx.config.digest.times)
+}
+
+// Every digest one ID was in, oldest first, then what's pending for it now
+fn history_id(ctx: &Context, matches: &ArgMatches) -> Result<()> {
+    let id: UserID = matches
+        .value_of("ID")
+        .chain_err(|| "No iMIS ID given")?
+        .parse()
+        .chain_err(|| "ID should be a number")?;
+    let history = History::read(&ctx.hx_path())?;
+    let pending = Review::new(ctx).batch()?.entries.remove(&id);
+
+    let alerts = |alerts: &[(Product, Vec<String>)]| {
+        let list: Vec<String> = alerts
+            .iter()
+            .map(|(p, ts)| {
+                format!(
+                    "{} ({} alert{})",
+                    p.code(),
+                    ts.len(),
+                    if ts.len() == 1 { "" } else { "s" }
+                )
+            })
+            .collect();
+        list.join(", ")
+    };
+    let seen = history.of(id);
+    if seen.is_empty() && pending.is_none() {
+        println!(
+            "iMIS ID {} isn't in any digest, and has nothing pending",
+            id
+        );
+        return Ok(());
+    }
+    println!(
+        "iMIS ID {} - in {} digest{}{}",
+        id,
+        seen.len(),
+        if seen.len() == 1 { "" } else { "s" },
+        if pending.is_some() {
+            ", and pending now"
+        } else {
+            ""
+        }
+    );
+    let mut times: BTreeMap<&str, usize> = BTreeMap::new();
+    for appearance in seen {
+        for (p, _) in &appearance.alerts {
+            *times.entry(p.code()).or_default() += 1;
+        }
+        println!(
+            "{}  {}  {:<10}  {}",
+            appearance.timestamp,
+            appearance.generated(),
+            appearance.status(),
+            alerts(&appearance.alerts)
+        );
+    }
+    if let Some(be) = pending {
+        println!(
+            "{:<10}  {:<16}  {:<10}  {}",
+            "pending",
+            "",
+            "",
+            alerts(&be.alerts_vec())
+        );
+    }
+    // The same product in digest after digest usually means the autodraft is set up wrong
+    for (code, n) in times.into_iter().filter(|(_, n)| *n > 1) {
+        println!("{} was in {} digests - check the autodraft", code, n);
+    }
+    Ok(())
+}
+
+// Records which IDs on a digest's sheets were verified, so later notes can say so
+fn history_verify(ctx: &Context, matches: &ArgMatches) -> Result<()> {
+    let timestamp: i64 = matches
+        .value_of("TIMESTAMP")
+        .chain_err(|| "No digest timestamp given")?
+        .parse()
+        .chain_err(|| "TIMESTAMP should be a number")?;
+    let ids: Vec<UserID> = matches
+        .values_of("ID")
+        .map(|ids| {
+            ids.map(|id| id.parse().chain_err(|| format!("{} isn't an iMIS ID", id)))
+                .collect()
+        })
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let new = history::mark_verified(&ctx.hx_path(), timestamp, &ids)?;
+    let all = history::verified(&ctx.hx_path(), timestamp)?;
+    println!(
+        "Marked {} ID{} verified on digest {} - {} in all",
+        new.len(),
+        if new.len() == 1 { "" } else { "s" },
+        timestamp,
+        all.len()
+    );
+    Ok(())
+}
+
+// How digest, --send and --dry-run were asked for, from either `ar-bot digest` or the old -d flags
+fn digest_options(
+    ctx: &Context,
+    matches: &ArgMatches,
+) -> Result<(Renderer, Vec<Export>, Delivery, bool)> {
+    let exports = matches
+        .values_of("export")
+        .map(|vs| vs.map(Export::from_str).collect::<Result<Vec<_>>>())
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let renderer = match matches.value_of("render") {
+        Some(r) => Renderer::from_str(r)?,
+        None => ctx.config.digest.render,
+    };
+    let delivery = if matches.is_present("dry_run") {
+        Delivery::DryRun
+    } else if matches.is_present("send") {
+        Delivery::Send
+    } else {
+        Delivery::Keep
+    };
+    let split = matches.is_present("split") || ctx.config.digest.split;
+    Ok((renderer, exports, delivery, split))
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .value_name("FORMAT")
+        .possible_values(&["text", "json", "toml"])
+        .default_value("text")
+        .help("Output format.  json and toml follow the versioned batch schema in the README")
+}
+
+// --render, --export, --send, --dry-run and --split, shared by `ar-bot digest` and the deprecated -d
+fn digest_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("render")
+            .long("render")
+            .value_name("RENDERER")
+            .possible_values(&["html", "text", "markdown"])
+            .help("Template used for the digest, overriding the config.  Defaults to html"),
+        Arg::with_name("export")
+            .short("x")
+            .long("export")
+            .value_name("FORMAT")
+            .possible_values(&["csv", "xlsx"])
+            .multiple(true)
+            .number_of_values(1)
+            .help("Also write the digest as a spreadsheet next to the html.  Repeat for both: -x csv -x xlsx"),
+        Arg::with_name("send")
+            .long("send")
+            .help("Email the digest, with any exports attached, to the recipients under [smtp]"),
+        Arg::with_name("dry_run")
+            .long("dry-run")
+            .requires("send")
+            .help("Write the email --send would have sent to hx/digest-<TIMESTAMP>.eml instead"),
+        Arg::with_name("split")
+            .long("split")
+            .help("Cut one digest per [[digest.groups]] in the config, each sent to its own recipients"),
+    ]
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("ar-bot")
         .version(VERSION)
         .author("deciduously <bendlovy@gmail.com>") // TODO read this from Cargo.toml?!
         .about("Batching of auto email alerts")
-        // This one will be a subcommand with subcommands
+        .after_help(EXIT_CODES)
         .arg(
             Arg::with_name("config")
                 .short("c")
                 .long("config")
                 .value_name("CONFIG_FILE")
                 .takes_value(true)
+                .global(true)
                 .help("Specify an alternate toml config file"),
         )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("NAME")
+                .takes_value(true)
+                .global(true)
+                .help("Lay the config's [profile.NAME] section over the rest of it"),
+        )
+        .arg(
+            Arg::with_name("all_profiles")
+                .long("all-profiles")
+                .global(true)
+                .conflicts_with("profile")
+                .help("Run the command once for every [profile.NAME] in the config, then sum up"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("Print errors as JSON on stdout, for scripts - see EXIT CODES"),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Override one setting for this run, like --set digest.render=markdown"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .multiple(true)
+                .global(true)
+                .help("Set RUST_LOG verbosity.  There are three levels: info, debug, and trace.  Repeat the flag to set level: -v, -vv, -vvv.")
+        )
+        // The flags everything used to be - kept working until they're removed, with a warning
         .arg(
             Arg::with_name("digest")
                 .short("d")
                 .long("digest")
                 .takes_value(false)
-                .help("Finalizes a digest with the emails in the brain. Make sure to preview first!")
-        )
-        //.arg(
-        //    Arg::with_name("email")
-        //        .short("e")
-        //        .long("email")
-        //        .takes_value(false)
-        //        .help("Placeholder command for developing email functionality"),
-        //)
+                .help("Deprecated - use `ar-bot digest`"),
+        )
+        .args(&digest_args().into_iter().map(|a| a.requires("digest")).collect::<Vec<_>>())
+        .arg(format_arg())
         .arg(
             Arg::with_name("preview")
                 .short("p")
                 .long("preview")
                 .takes_value(false)
-                .help("Displays the current contents of the batch"),
+                .help("Deprecated - use `ar-bot preview`"),
         )
         .arg(
             Arg::with_name("report")
                 .short("r")
                 .long("report")
                 .takes_value(false)
-                .help("Daily report comparing inputs to outputs for the day"),
+                .help("Deprecated - use `ar-bot report`"),
         )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .multiple(true)
-                .help("Set RUST_LOG verbosity.  There are three levels: info, debug, and trace.  Repeat the flag to set level: -v, -vv, -vvv.")
+        .subcommand(
+            SubCommand::with_name("preview")
+                .about("Displays the batch a digest would be cut from right now, without changing anything")
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("digest")
+                .about("Finalizes a digest with the emails in the brain and archives them, after showing what it'll use up")
+                .args(&digest_args())
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("Cut the digest without asking first.  Needed when not run from a terminal"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .short("e")
+                        .long("exclude")
+                        .value_name("ID|PRODUCT")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Leave an iMIS ID or product code out of this digest, pending for the next.  Repeatable"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tui")
+                .about("Browses the batch full-screen, holding entries back from the digest before it's cut")
+                .args(&digest_args())
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("Cut the digest as soon as it's asked for from the browser, without asking again"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Daily report comparing inputs to outputs for the day"),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Lists every alert, pending or archived, whose email matches a regex")
+                .after_help("Exits 3 if nothing matched.")
+                .arg(
+                    Arg::with_name("PATTERN")
+                        .required(true)
+                        .help("Regex to look for - an iMIS ID, a product code, a date..."),
+                )
+                .arg(
+                    Arg::with_name("ignore_case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Match regardless of case"),
+                )
+                .arg(
+                    Arg::with_name("pending")
+                        .long("pending")
+                        .help("Only search the brain, not the digests archived in hx/"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Reads or changes settings in the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Prints every setting, defaults included")
+                        .arg(
+                            Arg::with_name("origin")
+                                .long("origin")
+                                .help("Say which file, variable or flag each setting came from"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Prints one setting, defaults included")
+                        .after_help("Exits 3 if the setting isn't set.")
+                        .arg(
+                            Arg::with_name("KEY")
+                                .required(true)
+                                .help("Dotted key, like digest.render or smtp.host"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Changes one setting in the config file.  Nothing is written if the result wouldn't load")
+                        .arg(
+                            Arg::with_name("KEY")
+                                .required(true)
+                                .help("Dotted key, like digest.render or smtp.host"),
+                        )
+                        .arg(
+                            Arg::with_name("VALUE")
+                                .required(true)
+                                .help("New value.  Numbers, true/false and [\"lists\"] are read as TOML"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Lists every problem with the config, with the file, line and column it's at")
+                        .after_help("Exits 4 if there are any errors, 0 otherwise.")
+                        .arg(
+                            Arg::with_name("strict")
+                                .long("strict")
+                                .help("Exit 4 on warnings too"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about("Writes a commented config file with every default spelled out, to Bot.toml or -c")
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Replace the file if it already exists"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Looks back at digests already cut")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("Lists every digest in hx/, oldest first"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Shows the batch a digest was cut from")
+                        .arg(
+                            Arg::with_name("TIMESTAMP")
+                                .required(true)
+                                .help("Digest to show, as listed by `ar-bot history list`"),
+                        )
+                        .arg(format_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("id")
+                        .about("Lists every digest an iMIS ID was in, and anything pending for it")
+                        .arg(Arg::with_name("ID").required(true).help("iMIS ID to look up")),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Marks IDs on a digest's sheets as checked and initialed")
+                        .arg(
+                            Arg::with_name("TIMESTAMP")
+                                .required(true)
+                                .help("Digest the sheets came from, as listed by `ar-bot history list`"),
+                        )
+                        .arg(
+                            Arg::with_name("ID")
+                                .multiple(true)
+                                .help("iMIS IDs that were verified.  Every ID in the digest if none are given"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("auto")
+                .about("Cuts a digest if one of the thresholds under [auto] has been reached.  Made for cron")
+                .arg(
+                    Arg::with_name("daemon")
+                        .long("daemon")
+                        .help("Keep running, checking every interval_minutes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("Downloads unseen alerts from the mailbox under [imap] into the brain"),
+        )
+        .subcommand(
+            SubCommand::with_name("recover")
+                .about("Finishes, or rolls back, a digest that was interrupted part way through being cut"),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Keeps running, adding each new file in the brain to the batch as it appears"),
+        )
+        .subcommand(
+            SubCommand::with_name("template")
+                .about("Work with custom digest templates")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Renders each digest template against sample data and reports any errors")
+                        .arg(
+                            Arg::with_name("DIR")
+                                .help("Template directory to check.  Defaults to template_dir from the config"),
+                        ),
+                ),
         )
         // Arg cleanup
-        // Arg search_hx - maybe use ripgrep!
-        // Arg send
-        .get_matches();
+}
 
-    init_logging(matches.occurrences_of("verbose"))?;
+// Global args can be given before or after the subcommand - the one closest to the end wins
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    let deeper = matches
+        .subcommand()
+        .1
+        .and_then(|sub| global_value(sub, name));
+    deeper.or_else(|| matches.value_of(name))
+}
 
-    println!("AR-Bot v.{}\npass '-h' or '--help' for usage\n", VERSION);
+// Every --set, in the order given
+// clap copies a global arg's values from the innermost subcommand over the outer ones,
+// so `ar-bot --set a=1 digest --set b=2` would lose a=1 - read them off the line instead
+fn set_overrides(args: &[String]) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--set" {
+            if let Some(value) = args.next() {
+                ret.push(value.as_str());
+            }
+        } else if let Some(value) = arg.strip_prefix("--set=") {
+            ret.push(value);
+        }
+    }
+    ret
+}
+
+// Whether a global flag was given anywhere on the line
+fn global_flag(matches: &ArgMatches, name: &str) -> bool {
+    matches.is_present(name)
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|sub| global_flag(sub, name))
+}
+
+// What gets printed is meant for a script, so leave out the banner and sign-off
+fn for_scripts(matches: &ArgMatches) -> bool {
+    if global_flag(matches, "json") {
+        return true;
+    }
+    let mut m = matches;
+    loop {
+        if m.value_of("format").is_some_and(|f| f != "text") {
+            return true;
+        }
+        match m.subcommand() {
+            ("get", Some(_)) | ("show", Some(_)) | ("check", Some(_)) => return true,
+            (_, Some(sub)) => m = sub,
+            _ => return false,
+        }
+    }
+}
+
+// This is the entrypoint - essentially main()
+// Returns the exit code for anything that isn't an error - and with --json, for errors too, once they're printed
+pub fn run() -> Result<i32> {
+    let args: Vec<String> = std::env::args().collect();
+    // Checked by hand, since it has to work when the rest of the line doesn't parse
+    let json = args
+        .iter()
+        .skip(1)
+        .take_while(|a| *a != "--")
+        .any(|a| a == "--json");
+    let matches = match app().get_matches_from_safe(&args) {
+        Ok(m) => m,
+        Err(e) => match e.kind {
+            ClapErrorKind::HelpDisplayed | ClapErrorKind::VersionDisplayed => {
+                println!("{}", e.message);
+                return Ok(EXIT_OK);
+            }
+            _ if json => {
+                let message = e
+                    .message
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: ");
+                println!("{}", error_json(&ErrorKind::Usage(message.into()).into()));
+                return Ok(EXIT_USAGE);
+            }
+            _ => {
+                eprintln!("{}", e.message);
+                return Ok(EXIT_USAGE);
+            }
+        },
+    };
+
+    match run_matches(&matches, &args) {
+        Err(e) if json => {
+            println!("{}", error_json(&e));
+            Ok(e.exit_code())
+        }
+        result => result,
+    }
+}
+
+fn run_matches(matches: &ArgMatches, args: &[String]) -> Result<i32> {
+    let verbosity = matches.occurrences_of("verbose")
+        + matches
+            .subcommand()
+            .1
+            .map_or(0, |sub| sub.occurrences_of("verbose"));
+    init_logging(verbosity)?;
+
+    // Keep stdout clean for anything a script is going to parse
+    let chatty = !for_scripts(matches);
+
+    if chatty {
+        println!("AR-Bot v.{}\npass '-h' or '--help' for usage\n", VERSION);
+    }
+
+    // There's no config to load yet
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        if let ("init", Some(m)) = config_matches.subcommand() {
+            let path = global_value(matches, "config").unwrap_or(DEFAULT_CONFIG);
+            write_default_config(path, m.is_present("force"))?;
+            println!("Wrote {}", path);
+            return Ok(EXIT_OK);
+        }
+    }
 
     // Initialize configuration and read in Brain
-    let config = init_config(matches.value_of("config"))
-        .chain_err(|| "Could not load configuration file")
-        .chain_err(|| "Could not make heads or tails of that abomination of a config file")?;
+    let mut sources = Sources::discover(global_value(matches, "config"), &set_overrides(args))?;
+    sources.profile = global_value(matches, "profile").map(String::from);
+
+    let all = global_flag(matches, "all_profiles");
+    // clap only catches the two together when they're on the same side of the subcommand
+    if all && sources.profile.is_some() {
+        let msg = "--profile and --all-profiles can't be used together";
+        return Err(ErrorKind::Usage(msg.into()).into());
+    }
+    let code = if all {
+        all_profiles(matches, &sources, chatty)?
+    } else {
+        run_profile(matches, &sources)?
+    };
+
+    if var("RUST_LOG").chain_err(|| "Could read RUST_LOG on cleanup")? == "trace" {
+        remove_var("RUST_BACKTRACE");
+    }
+    remove_var("RUST_LOG");
+    info!("Cleaned up logging environment variables");
+
+    info!("Everything clean");
+    if chatty {
+        println!("Goodbye!");
+    }
+    Ok(code)
+}
+
+// Everything that needs the config, for one profile or none
+fn run_profile(matches: &ArgMatches, sources: &Sources) -> Result<i32> {
+    // Has to run on a config that won't load
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        if let ("check", Some(m)) = config_matches.subcommand() {
+            return config_check(sources, m, global_flag(matches, "json"));
+        }
+    }
+
+    let (config, origins) = load(sources)?;
     if log_enabled!(::log::Level::Info) {
         info!("Initialized config: {:?}", &config.config_path);
     }
     debug!("{}\n", &config);
 
+    // These don't need the brain
+    match matches.subcommand() {
+        ("fetch", Some(_)) => return fetch(&config).map(|_| EXIT_OK),
+        ("recover", Some(_)) => return recover_digests(&config).map(|_| EXIT_OK),
+        ("template", Some(template_matches)) => {
+            if let ("check", Some(check_matches)) = template_matches.subcommand() {
+                template_check(&config, check_matches)?;
+            }
+            return Ok(EXIT_OK);
+        }
+        ("config", Some(config_matches)) => {
+            return match config_matches.subcommand() {
+                ("show", Some(m)) => config_show(&config, &origins, m).map(|_| EXIT_OK),
+                ("get", Some(m)) => config_get(&config, m),
+                ("set", Some(m)) => config_set(&config, m).map(|_| EXIT_OK),
+                _ => Ok(EXIT_USAGE),
+            };
+        }
+        _ => {}
+    }
+
     // Grab a Context with a Brain
     // this takes ownership of Config - all further access is via this ctx
     // Because Rust is great, everything will clean itslef up nicely when ctx goes out of scope
-    let mut ctx = Context::initialize(config).chain_err(|| "Could not initialze config")?;
+    // Fails straight away, naming who has it, if another run holds the lock on the brain
+    let mut ctx = Context::initialize(config)?;
 
-    // Call relative functions if their respective flags are present.
-    // TODO smart preview with add - how SHOULD it be?
-    // For now, I'm calling preview last so that we always dislay the end result of the batch
+    Ok(match matches.subcommand() {
+        ("watch", Some(_)) => {
+            // Only ever reads, and runs for days - digests still have to be cut meanwhile
+            ctx.unlock();
+            watch(&ctx).map(|_| EXIT_OK)?
+        }
+        ("auto", Some(auto_matches)) => {
+            auto(&mut ctx, auto_matches.is_present("daemon")).map(|_| EXIT_OK)?
+        }
+        ("preview", Some(m)) => {
+            preview(
+                &mut ctx,
+                Format::from_str(m.value_of("format").unwrap_or("text"))?,
+            )?;
+            EXIT_OK
+        }
+        ("digest", Some(m)) => {
+            review_and_digest(&ctx, m)?;
+            EXIT_OK
+        }
+        ("tui", Some(m)) => {
+            browse_and_digest(&ctx, m)?;
+            EXIT_OK
+        }
+        ("report", Some(_)) => {
+            report(&ctx)?;
+            EXIT_OK
+        }
+        ("search", Some(m)) => search(&ctx, m)?,
+        ("history", Some(history_matches)) => {
+            match history_matches.subcommand() {
+                ("list", Some(_)) => history_list(&ctx)?,
+                ("show", Some(m)) => history_show(&ctx, m)?,
+                ("id", Some(m)) => history_id(&ctx, m)?,
+                ("verify", Some(m)) => history_verify(&ctx, m)?,
+                _ => return Ok(EXIT_USAGE),
+            }
+            EXIT_OK
+        }
+        _ => {
+            legacy_flags(&mut ctx, matches)?;
+            EXIT_OK
+        }
+    })
+}
 
-    //if matches.is_present("email") {
-    //    email()?;
-    //}
+// Runs the same command once per [profile.NAME], carrying on past any that fail, then sums up
+// Exits with the first non-zero exit code, if any
+fn all_profiles(matches: &ArgMatches, sources: &Sources, chatty: bool) -> Result<i32> {
+    let names = check(sources)?.profiles;
+    if names.is_empty() {
+        let msg = "--all-profiles given, but the config has no [profile.NAME] sections";
+        return Err(ErrorKind::Config(msg.into()).into());
+    }
+    let mut results = Vec::new();
+    for name in names {
+        if chatty {
+            println!("== Profile {} ==", name);
+        }
+        let profile_sources = Sources {
+            profile: Some(name.clone()),
+            ..sources.clone()
+        };
+        let (code, failed) = match run_profile(matches, &profile_sources) {
+            Ok(code) => (code, false),
+            Err(e) => {
+                if global_flag(matches, "json") {
+                    let mut report = error_json(&e);
+                    report["profile"] = name.clone().into();
+                    println!("{}", report);
+                } else {
+                    error!("profile {}: {}", name, e);
+                }
+                (e.exit_code(), true)
+            }
+        };
+        results.push((name, code, failed));
+    }
 
+    let summary: Vec<String> = results
+        .iter()
+        .map(|(name, code, failed)| match (*code, failed) {
+            (EXIT_OK, _) => format!("  {}: ok", name),
+            (c, true) => format!("  {}: failed (exit {})", name, c),
+            (c, false) => format!("  {}: exit {}", name, c),
+        })
+        .collect();
+    // Scripts parse stdout, so they get the summary on stderr
+    if chatty {
+        println!("\nProfiles:\n{}", summary.join("\n"));
+    } else {
+        eprintln!("Profiles:\n{}", summary.join("\n"));
+    }
+    Ok(results
+        .iter()
+        .map(|(_, code, _)| *code)
+        .find(|c| *c != EXIT_OK)
+        .unwrap_or(EXIT_OK))
+}
+
+// -d, -r and -p from before there were subcommands
+// They still run in that order no matter how they're typed, which is why they're on their way out
+fn legacy_flags(ctx: &mut Context, matches: &ArgMatches) -> Result<()> {
     if matches.is_present("digest") {
-        debug!("Calling Digest function per command line");
-        digest(&ctx)?;
+        warn!("-d/--digest is deprecated and will be removed - use `ar-bot digest` instead");
+        // Never asked before it was deprecated, so scripts using it don't start to now
+        let (renderer, exports, delivery, split) = digest_options(ctx, matches)?;
+        digest(ctx, &Review::new(ctx), renderer, &exports, delivery, split)?;
     }
 
     if matches.is_present("report") {
-        debug!("Calling Report function per command line");
-        report(&ctx)?;
+        warn!("-r/--report is deprecated and will be removed - use `ar-bot report` instead");
+        report(ctx)?;
     }
 
     if matches.is_present("preview") {
-        debug!("Calling Preview function per command line");
-        preview(&mut ctx)?;
-    }
-
-    if var("RUST_LOG").chain_err(|| "Could read RUST_LOG on cleanup")? == "trace" {
-        remove_var("RUST_BACKTRACE");
+        warn!("-p/--preview is deprecated and will be removed - use `ar-bot preview` instead");
+        let format = Format::from_str(matches.value_of("format").unwrap_or("text"))?;
+        preview(ctx, format)?;
     }
-    remove_var("RUST_LOG");
-    info!("Cleaned up logging environment variables");
-
-    info!("Everything clean");
-    println!("Goodbye!");
     Ok(())
 }
 
@@ -160,7 +1296,7 @@ fn init_logging(level: u64) -> Result<()> {
         0 => "warn",
         1 => "info",
         2 => "debug",
-        3 | _ => "trace",
+        _ => "trace",
     };
     if verbosity == "trace" {
         set_var("RUST_BACKTRACE", "1");
@@ -177,3 +1313,140 @@ fn init_logging(level: u64) -> Result<()> {
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ar_bot::test_util::TempBrain;
+
+    fn parse(args: &[&str]) -> ArgMatches<'static> {
+        app().get_matches_from_safe(args.iter()).unwrap()
+    }
+
+    #[test]
+    fn test_legacy_flags_still_parse() {
+        let matches = parse(&[
+            "ar-bot", "-d", "--render", "text", "-x", "csv", "-p", "-f", "json",
+        ]);
+        assert!(matches.is_present("digest"));
+        assert!(matches.is_present("preview"));
+        assert_eq!(matches.value_of("render"), Some("text"));
+        assert!(for_scripts(&matches));
+        assert!(app()
+            .get_matches_from_safe(vec!["ar-bot", "--send"])
+            .is_err());
+    }
+    #[test]
+    fn test_global_config_either_side() {
+        for args in &[
+            vec![
+                "ar-bot",
+                "-c",
+                "Alternate.toml",
+                "config",
+                "get",
+                "digest.render",
+            ],
+            vec![
+                "ar-bot",
+                "config",
+                "get",
+                "digest.render",
+                "-c",
+                "Alternate.toml",
+            ],
+        ] {
+            let matches = parse(args);
+            assert_eq!(global_value(&matches, "config"), Some("Alternate.toml"));
+            assert!(for_scripts(&matches));
+        }
+        assert_eq!(global_value(&parse(&["ar-bot", "preview"]), "config"), None);
+        assert!(!for_scripts(&parse(&["ar-bot", "preview"])));
+    }
+    #[test]
+    fn test_set_overrides_every_level() {
+        let args: Vec<String> = [
+            "ar-bot",
+            "--set",
+            "digest.render=markdown",
+            "digest",
+            "--set=auto.min_ids=4",
+            "--",
+            "--set",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert!(app().get_matches_from_safe(&args[..5]).is_ok());
+        assert_eq!(
+            set_overrides(&args),
+            vec!["digest.render=markdown", "auto.min_ids=4"]
+        );
+    }
+    #[test]
+    fn test_profile_flags() {
+        let matches = parse(&["ar-bot", "preview", "--profile", "east"]);
+        assert_eq!(global_value(&matches, "profile"), Some("east"));
+        assert!(global_flag(
+            &parse(&["ar-bot", "--all-profiles", "digest"]),
+            "all_profiles"
+        ));
+        assert!(!global_flag(&matches, "all_profiles"));
+        assert!(app()
+            .get_matches_from_safe(["ar-bot", "--all-profiles", "--profile", "east", "preview"])
+            .is_err());
+    }
+    #[test]
+    fn test_exit_codes() {
+        let config: Error = ErrorKind::Config("no config".into()).into();
+        let delivery = Err::<(), Error>(ErrorKind::Delivery("SMTP said no".into()).into())
+            .chain_err(|| "Could not send digest")
+            .unwrap_err();
+        let plain: Error = "something else".into();
+        assert_eq!(config.exit_code(), EXIT_CONFIG);
+        assert_eq!(delivery.exit_code(), EXIT_DELIVERY);
+        assert_eq!(plain.exit_code(), EXIT_ERROR);
+
+        let report = error_json(&delivery);
+        assert_eq!(report["error"]["category"], "delivery");
+        assert_eq!(report["error"]["exit_code"], EXIT_DELIVERY);
+        assert_eq!(report["error"]["message"], "Could not send digest");
+        assert_eq!(report["error"]["causes"][0], "SMTP said no");
+    }
+    #[test]
+    fn test_confirm() {
+        let dir = TempBrain::new();
+        let mut ctx = dir.context();
+        for (name, id, product) in &[
+            ("a.txt", 164408, "CG_TRANS"),
+            ("b.txt", 164409, "CG_TUITION"),
+        ] {
+            let alert = format!(
+                "From:\tiMIS <iMIS@jccgb.org>\r\nSent:\tWednesday, July 18, 2018 1:00 PM\r\nTo:\tSome People\r\n\
+                 Subject:\tInvoice Charge Change\r\n\r\nThe Grossman Invoice For iMIS ID {} For the Product {} \
+                 Has Changed\r\nYou need to verify the Autodraft is now correct",
+                id, product
+            );
+            std::fs::write(dir.join(name), alert).unwrap();
+        }
+        ctx.read_fs().unwrap();
+
+        let mut review = Review::new(&ctx);
+        let mut out = Vec::new();
+        assert!(!confirm(&mut review, "n\n".as_bytes(), &mut out).unwrap());
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("use up 2 emails: 2 alerts for 2 IDs"));
+
+        let mut out = Vec::new();
+        let answers = "e\n164409 99999\ny\n";
+        assert!(confirm(&mut review, answers.as_bytes(), &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Nothing pending for iMIS ID 99999"));
+        assert!(out.contains("Excluded: iMIS ID 164409"));
+        assert_eq!(review.inputs(), vec![dir.join("a.txt")]);
+
+        // Running out of input is a no
+        assert!(!confirm(&mut review, "".as_bytes(), Vec::new()).unwrap());
+    }
+}
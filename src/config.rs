@@ -1,42 +1,264 @@
 // config.rs handles loading and eventually writing to the app configuration
-use crate::{errors::*, util::file_contents_from_str_path};
+use crate::{
+    batch::{Product, TimeDetail},
+    errors::*,
+    export::Export,
+    page::Renderer,
+    util::{file_contents_from_str_path, write_atomic},
+    validate::{self, Diagnostic, Severity, Spans},
+};
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
-use std::{fmt, path::PathBuf};
-use toml;
+use std::{
+    collections::BTreeMap,
+    env::{current_dir, var_os, vars},
+    fmt,
+    fs::write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::Value;
+use toml_edit::{DocumentMut, Item, TableLike};
 
-static DEFAULT_CONFIG: &'static str = "Bot.toml";
+/// The config file used when none is given
+pub static DEFAULT_CONFIG: &str = "Bot.toml";
 
-// Eventually, allow for config manipulation via commandline
-// Leaving Serialize in here for now
+// Serialize is what `ar-bot config show` and `config get` print from
 
+/// Everything read from Bot.toml, or whichever file was passed with -c
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(skip_serializing)]
     pub config_path: Option<String>, // Workaround so we can fill it in during initialiation without an intermediate struct
+    // The [profile.NAME] laid over everything else, if one was picked
+    #[serde(skip)]
+    pub profile: Option<String>,
     pub directory: Directory,
+    #[serde(default)]
+    pub digest: Digest,
+    pub smtp: Option<Smtp>,
+    pub imap: Option<Imap>,
+    pub auto: Option<Auto>,
+    #[serde(default)]
+    pub anomalies: Anomalies,
 }
 
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Digest {
+    #[serde(default)]
+    pub render: Renderer,
+    // Directory of tera templates to use instead of the built-in ones
+    pub template_dir: Option<PathBuf>,
+    // How alert times are squashed, in the digest and in preview
+    #[serde(default)]
+    pub times: TimeDetail,
+    // Cut one digest per group instead of one for everything - see --split
+    #[serde(default)]
+    pub split: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<ProductGroup>,
+}
+
+/// Products checked by the same people, e.g. both camps' transportation
+/// With `split`, each group gets a digest of its own, sent to its own recipients
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductGroup {
+    pub name: String,
+    pub products: Vec<Product>,
+    // Empty sends it to `to` under [smtp]
+    #[serde(default)]
+    pub to: Vec<String>,
+}
+
+impl ProductGroup {
+    /// The name as it goes in file names: digest-TIMESTAMP-SLUG.html
+    pub fn slug(&self) -> String {
+        let lower = self.name.to_lowercase();
+        let words: Vec<&str> = lower
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect();
+        words.join("-")
+    }
+}
+
+/// Where --send delivers the digest
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Smtp {
+    pub host: String,
+    // Defaults to the usual port for the security setting
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: Security,
+    pub username: Option<String>,
+    // Falls back to AR_BOT_SMTP_PASSWORD if a username is given without one
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_subject")]
+    pub subject: String,
+}
+
+fn default_subject() -> String {
+    "AR-Bot Digest".into()
+}
+
+/// When `ar-bot auto` cuts a digest on its own
+/// It's due once any threshold is reached, as long as we're inside the schedule
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auto {
+    pub min_ids: Option<usize>,
+    pub min_alerts: Option<usize>,
+    pub max_age_hours: Option<i64>,
+    // mon, tue, ...  Empty means every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    // HH:MM
+    pub not_before: Option<String>,
+    #[serde(default)]
+    pub send: bool,
+    #[serde(default)]
+    pub exports: Vec<Export>,
+    // How often --daemon checks
+    #[serde(default = "default_interval")]
+    pub interval_minutes: u64,
+}
+
+fn default_interval() -> u64 {
+    15
+}
+
+/// When an entry is flagged as looking wrong - see anomaly.rs.  0 turns a check off
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Anomalies {
+    // More alerts than this for one ID and product within repeat_window_hours, counting earlier digests
+    #[serde(default = "default_repeat_alerts")]
+    pub repeat_alerts: usize,
+    #[serde(default = "default_repeat_window")]
+    pub repeat_window_hours: i64,
+    // Alerts for at least this many products for one ID
+    #[serde(default = "default_many_products")]
+    pub many_products: usize,
+    // At least this many IDs alerting in the same minute - a bulk job rather than people
+    #[serde(default = "default_same_minute")]
+    pub same_minute: usize,
+    // Product codes ar-bot doesn't know
+    #[serde(default = "default_unknown_products")]
+    pub unknown_products: bool,
+}
+
+fn default_repeat_alerts() -> usize {
+    3
+}
+
+fn default_repeat_window() -> i64 {
+    24 * 7
+}
+
+fn default_many_products() -> usize {
+    3
+}
+
+fn default_same_minute() -> usize {
+    5
+}
+
+fn default_unknown_products() -> bool {
+    true
+}
+
+impl Default for Anomalies {
+    fn default() -> Self {
+        Anomalies {
+            repeat_alerts: default_repeat_alerts(),
+            repeat_window_hours: default_repeat_window(),
+            many_products: default_many_products(),
+            same_minute: default_same_minute(),
+            unknown_products: default_unknown_products(),
+        }
+    }
+}
+
+/// Where `ar-bot fetch` picks up alerts from
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Imap {
+    pub host: String,
+    // Defaults to 993 for tls, 143 otherwise
+    pub port: Option<u16>,
+    #[serde(default = "default_imap_security")]
+    pub security: Security,
+    pub username: String,
+    // Falls back to AR_BOT_IMAP_PASSWORD
+    pub password: Option<String>,
+    #[serde(default = "default_folder")]
+    pub folder: String,
+    // If set, fetched alerts are moved here once a digest is cut
+    pub processed_folder: Option<String>,
+}
+
+fn default_imap_security() -> Security {
+    Security::Tls
+}
+
+fn default_folder() -> String {
+    "INBOX".into()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    // Plain text - only for a relay on localhost or a test sink
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Directory {
     pub compressed: bool,
     pub path: PathBuf,
+    // A lock left this long by a run on another machine is taken over
+    #[serde(default = "default_stale_lock")]
+    pub stale_lock_minutes: u64,
 }
 
-impl Config {
-    fn add_config_path(&mut self, s: &str) -> Result<()> {
-        self.config_path = Some(s.into());
-        Ok(())
+fn default_stale_lock() -> u64 {
+    60
+}
+
+impl Directory {
+    /// How old a lock on the brain from another machine has to be before it's taken over
+    pub fn stale_lock(&self) -> Duration {
+        Duration::from_secs(self.stale_lock_minutes * 60)
     }
-    // TODO add more self mutations here, use Clap subcommands to provide easy access
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             config_path: Some("Bot.toml".into()),
+            profile: None,
             directory: Directory {
                 compressed: false,
                 path: "brain".into(),
+                stale_lock_minutes: default_stale_lock(),
             },
+            digest: Digest::default(),
+            smtp: None,
+            imap: None,
+            auto: None,
+            anomalies: Anomalies::default(),
         }
     }
 }
@@ -48,9 +270,24 @@ impl fmt::Display for Config {
             .unwrap_or_else(|| "None given".into());
         write!(
             f,
-            "Ar-Bot Configuration:\n* Configuration file path: {}\n* Directory Settings:\n* * {}",
-            c_p, self.directory,
-        )
+            "Ar-Bot Configuration:\n* Configuration file path: {}\n* Directory Settings:\n* * {}\n* Digest Settings:\n* * {}",
+            c_p, self.directory, self.digest,
+        )?;
+        if let Some(profile) = &self.profile {
+            write!(f, "\n* Profile: {}", profile)?;
+        }
+        match &self.smtp {
+            Some(smtp) => write!(f, "\n* SMTP Settings:\n* * {}", smtp),
+            None => write!(f, "\n* SMTP Settings:\n* * Not configured"),
+        }?;
+        match &self.imap {
+            Some(imap) => write!(f, "\n* IMAP Settings:\n* * {}", imap),
+            None => write!(f, "\n* IMAP Settings:\n* * Not configured"),
+        }?;
+        match &self.auto {
+            Some(auto) => write!(f, "\n* Auto Digest Settings:\n* * {}", auto),
+            None => write!(f, "\n* Auto Digest Settings:\n* * Not configured"),
+        }
     }
 }
 
@@ -69,35 +306,1208 @@ impl fmt::Display for Directory {
     }
 }
 
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Rendered as {} with {:?} times",
+            self.render.extension(),
+            self.times
+        )?;
+        match &self.template_dir {
+            Some(dir) => write!(f, " from templates in {:?}", dir),
+            None => write!(f, " from the built-in templates"),
+        }?;
+        if !self.groups.is_empty() {
+            let names: Vec<&str> = self.groups.iter().map(|g| g.name.as_str()).collect();
+            let split = if self.split {
+                "split into"
+            } else {
+                "not split - groups"
+            };
+            write!(f, ", {} {}", split, names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Smtp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sending from {} to {} via {} ({:?})",
+            self.from,
+            self.to.join(", "),
+            self.host,
+            self.security
+        )
+    }
+}
+
+impl fmt::Display for Auto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut thresholds = Vec::new();
+        if let Some(n) = self.min_ids {
+            thresholds.push(format!("{} IDs", n));
+        }
+        if let Some(n) = self.min_alerts {
+            thresholds.push(format!("{} alerts", n));
+        }
+        if let Some(n) = self.max_age_hours {
+            thresholds.push(format!("an alert {} hours old", n));
+        }
+        if thresholds.is_empty() {
+            thresholds.push("never - no thresholds set".into());
+        }
+        write!(f, "Cutting at {}", thresholds.join(" or "))?;
+        if !self.days.is_empty() {
+            write!(f, " on {}", self.days.join(", "))?;
+        }
+        if let Some(t) = &self.not_before {
+            write!(f, " from {}", t)?;
+        }
+        if self.send {
+            write!(f, ", then sending")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Imap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Fetching {} as {} from {} ({:?})",
+            self.folder, self.username, self.host, self.security
+        )?;
+        if let Some(processed) = &self.processed_folder {
+            write!(f, ", moving to {} after each digest", processed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a setting came from - see `ar-bot config show --origin`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Origin {
+    Default,
+    System(PathBuf),
+    User(PathBuf),
+    Project(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::System(p) => write!(f, "system file {}", p.display()),
+            Origin::User(p) => write!(f, "user file {}", p.display()),
+            Origin::Project(p) => write!(f, "project file {}", p.display()),
+            Origin::Env(var) => write!(f, "environment variable {}", var),
+            Origin::Cli => write!(f, "--set on the command line"),
+        }
+    }
+}
+
+/// Every setting's dotted key, and where its value came from
+pub type Origins = BTreeMap<String, Origin>;
+
+// These read from the environment on their own, and don't make an [smtp] or [imap] section by themselves
+static PASSWORD_VARS: &[&str] = &["AR_BOT_SMTP_PASSWORD", "AR_BOT_IMAP_PASSWORD"];
+
+// Relative paths in a config file are relative to that file, not to wherever ar-bot was run from
+static PATH_KEYS: &[&str] = &["directory.path", "digest.template_dir"];
+
+/// Every layer of configuration, lowest priority first
+/// Each file that exists is merged over the one before, then the environment, then --set
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    pub system: Option<PathBuf>,
+    pub user: Option<PathBuf>,
+    pub project: Option<PathBuf>,
+    // -c was given, so the project file has to exist
+    pub project_required: bool,
+    // Name and value of every AR_BOT_* variable
+    pub env: Vec<(String, String)>,
+    // KEY=VALUE from --set
+    pub overrides: Vec<String>,
+    // The [profile.NAME] to lay over the files, from --profile
+    pub profile: Option<String>,
+}
+
+impl Sources {
+    /// The usual places: /etc/ar-bot/Bot.toml, ar-bot/Bot.toml under the XDG config dir,
+    /// then the file given with -c, or else the nearest Bot.toml in this directory or above it
+    pub fn discover(config_file: Option<&str>, overrides: &[&str]) -> Result<Self> {
+        let system = if cfg!(unix) {
+            Some(PathBuf::from("/etc/ar-bot").join(DEFAULT_CONFIG))
+        } else {
+            None
+        };
+        let user = var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .or_else(|| var_os("APPDATA").map(PathBuf::from))
+            .map(|dir| dir.join("ar-bot").join(DEFAULT_CONFIG));
+        let project = match config_file {
+            Some(f) => Some(PathBuf::from(f)),
+            None => find_project_file()?,
+        };
+        Ok(Sources {
+            system,
+            user,
+            project,
+            project_required: config_file.is_some(),
+            env: vars()
+                .filter(|(k, _)| k.starts_with("AR_BOT_") && !PASSWORD_VARS.contains(&k.as_str()))
+                .collect(),
+            overrides: overrides.iter().map(|o| o.to_string()).collect(),
+            profile: None,
+        })
+    }
+}
+
+// Bot.toml in the working directory, or the closest parent directory that has one
+fn find_project_file() -> Result<Option<PathBuf>> {
+    let cwd = current_dir().chain_err(|| "Could not read the working directory")?;
+    let mut prefix = PathBuf::new();
+    for _ in cwd.ancestors() {
+        let candidate = prefix.join(DEFAULT_CONFIG);
+        if candidate.is_file() {
+            return Ok(Some(candidate));
+        }
+        prefix.push("..");
+    }
+    Ok(None)
+}
+
+// How a value from the environment or --set is read: as TOML if it's a number, boolean or list, otherwise as a string
+// Quote it to force a string: AR_BOT_SMTP_SUBJECT='"2024"'
+fn parse_setting(s: &str) -> Value {
+    match format!("value = {}", s)
+        .parse::<Value>()
+        .ok()
+        .and_then(|v| v.get("value").cloned())
+    {
+        Some(Value::Datetime(_)) | None => Value::String(s.into()),
+        Some(v) => v,
+    }
+}
+
+fn merge(
+    into: &mut Value,
+    from: Value,
+    prefix: &str,
+    origin: &Origin,
+    origins: &mut Origins,
+) -> Result<()> {
+    let table = into
+        .as_table_mut()
+        .chain_err(|| ErrorKind::Config(format!("{} is not a table", prefix)))?;
+    let from = match from {
+        Value::Table(t) => t,
+        _ => return Err(ErrorKind::Config(format!("{} should be a table", prefix)).into()),
+    };
+    for (k, v) in from {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match (table.get_mut(&k), v) {
+            (Some(existing @ Value::Table(_)), Value::Table(t)) => {
+                merge(existing, Value::Table(t), &key, origin, origins)?
+            }
+            (_, v) => {
+                if let Value::Table(t) = &v {
+                    for leaf in leaves(&key, t) {
+                        origins.insert(leaf, origin.clone());
+                    }
+                } else {
+                    origins.insert(key, origin.clone());
+                }
+                table.insert(k, v);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn leaves(prefix: &str, table: &toml::value::Table) -> Vec<String> {
+    let mut ret = Vec::new();
+    for (k, v) in table {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match v {
+            Value::Table(t) => ret.extend(leaves(&key, t)),
+            _ => ret.push(key),
+        }
+    }
+    ret
+}
+
+// Sets a single dotted key, making tables on the way
+fn insert_setting(doc: &mut Value, key: &str, value: Value) -> Result<()> {
+    let mut from = value;
+    for part in key.rsplit('.') {
+        let mut t = toml::value::Table::new();
+        t.insert(part.to_string(), from);
+        from = Value::Table(t);
+    }
+    let mut ignored = Origins::new();
+    merge(doc, from, "", &Origin::Cli, &mut ignored)
+}
+
+fn read_layer(
+    path: &Path,
+    mut layer: Value,
+    origin: &Origin,
+    doc: &mut Value,
+    origins: &mut Origins,
+) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    resolve_paths(&mut layer, dir);
+    if let Some(Value::Table(profiles)) = layer.get_mut("profile") {
+        for (_, profile) in profiles.iter_mut() {
+            resolve_paths(profile, dir);
+        }
+    }
+    merge(doc, layer, "", origin, origins)
+}
+
+fn resolve_paths(table: &mut Value, dir: &Path) {
+    for (section, name) in PATH_KEYS.iter().filter_map(|k| k.split_once('.')) {
+        if let Some(Value::String(p)) = table.get_mut(section).and_then(|t| t.get_mut(name)) {
+            if Path::new(p.as_str()).is_relative() {
+                *p = dir.join(p.as_str()).to_string_lossy().into_owned();
+            }
+        }
+    }
+}
+
+// Takes every [profile.NAME] out of the merged settings, laying the named one over the rest
+// Settings from the profile keep the origin of the file they were in, and remember the key they were written as
+// Returns every profile's name
+fn apply_profile(
+    doc: &mut Value,
+    name: Option<&str>,
+    origins: &mut Origins,
+    aliases: &mut BTreeMap<String, String>,
+) -> Result<Vec<String>> {
+    let mut profiles = match doc.as_table_mut().and_then(|t| t.remove("profile")) {
+        Some(Value::Table(t)) => t,
+        Some(_) => {
+            let msg = "profile should be made of [profile.NAME] sections";
+            return Err(ErrorKind::Config(msg.into()).into());
+        }
+        None => toml::value::Table::new(),
+    };
+    let names: Vec<String> = profiles.keys().cloned().collect();
+    let written: Vec<String> = origins
+        .keys()
+        .filter(|k| k.starts_with("profile."))
+        .cloned()
+        .collect();
+    let mut profile_origins = Origins::new();
+    for key in written {
+        if let Some(o) = origins.remove(&key) {
+            profile_origins.insert(key, o);
+        }
+    }
+
+    if let Some(name) = name {
+        let profile = profiles.remove(name).chain_err(|| {
+            ErrorKind::Config(format!(
+                "No profile named {} - the config has {}",
+                name,
+                if names.is_empty() {
+                    "none".into()
+                } else {
+                    names.join(", ")
+                }
+            ))
+        })?;
+        let table = profile
+            .as_table()
+            .chain_err(|| ErrorKind::Config(format!("profile.{} should be a table", name)))?;
+        for leaf in leaves("", table) {
+            let as_written = format!("profile.{}.{}", name, leaf);
+            if let Some(o) = profile_origins.remove(&as_written) {
+                origins.insert(leaf.clone(), o);
+            }
+            aliases.insert(leaf, as_written);
+        }
+        merge(doc, profile, "", &Origin::Default, &mut Origins::new())?;
+    }
+    Ok(names)
+}
+
+/// What `check` found: the config if it loaded at all, where each setting came from, and every problem
+#[derive(Debug, Default)]
+pub struct Checked {
+    pub config: Option<Config>,
+    pub origins: Origins,
+    pub diagnostics: Vec<Diagnostic>,
+    // Every [profile.NAME], whether or not it was picked
+    pub profiles: Vec<String>,
+    // Settings taken from a profile, and the key they were written as in the file
+    aliases: BTreeMap<String, String>,
+}
+
+impl Checked {
+    pub fn errors(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.is_error()).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.diagnostics.len() - self.errors()
+    }
+
+    // Pins a problem with a dotted key to the file, line and column it was set at
+    fn report(
+        &mut self,
+        files: &[(Origin, Spans)],
+        key: &str,
+        severity: Severity,
+        message: String,
+    ) {
+        let origin = self.origin_of(key);
+        let written = self.aliases.get(key).map_or(key, |k| k.as_str());
+        let at = files
+            .iter()
+            .find(|(o, _)| Some(o) == origin.as_ref())
+            .and_then(|(_, spans)| spans.find(written));
+        self.diagnostics.push(Diagnostic {
+            severity,
+            origin,
+            line: at.map(|(l, _)| l),
+            column: at.map(|(_, c)| c),
+            key: if key.is_empty() {
+                None
+            } else {
+                Some(key.to_string())
+            },
+            message,
+        });
+    }
+
+    // Where a key was set - or for one that's missing, wherever the rest of its section was
+    fn origin_of(&self, key: &str) -> Option<Origin> {
+        let mut key = key;
+        loop {
+            if let Some(o) = self.origins.get(key) {
+                return Some(o.clone());
+            }
+            let prefix = format!("{}.", key);
+            if let Some((_, o)) = self
+                .origins
+                .iter()
+                .find(|(k, o)| k.starts_with(&prefix) && **o != Origin::Default)
+            {
+                return Some(o.clone());
+            }
+            key = &key[..key.rfind('.')?];
+        }
+    }
+}
+
+// toml tacks " for key `a.b`" onto errors from inside a table
+fn split_key_context(message: &str) -> (String, String) {
+    lazy_static! {
+        static ref KEY_RE: Regex =
+            Regex::new(r"^(?P<message>.*) for key `(?P<key>[^`]*)`$").unwrap();
+        static ref FIELD_RE: Regex =
+            Regex::new(r"^(unknown|missing) field `(?P<field>[^`]*)`").unwrap();
+    }
+    let (message, mut key) = match KEY_RE.captures(message) {
+        Some(caps) => (caps["message"].to_string(), caps["key"].to_string()),
+        None => (message.to_string(), String::new()),
+    };
+    // Point at the field itself rather than the table it's in
+    if let Some(caps) = FIELD_RE.captures(&message) {
+        if !key.is_empty() {
+            key.push('.');
+        }
+        key.push_str(&caps["field"]);
+    }
+    (key, message)
+}
+
+fn remove_setting(doc: &mut Value, key: &str) -> Option<Value> {
+    let parts: Vec<&str> = key.split('.').collect();
+    let (last, tables) = parts.split_last()?;
+    let mut table = doc.as_table_mut()?;
+    for part in tables {
+        table = table.get_mut(*part)?.as_table_mut()?;
+    }
+    table.remove(*last)
+}
+
+/// Merges every layer into one Config like `load`, but carries on past problems to find all of them
+/// Only a file that can't be read, or a malformed --set, is an Err
+pub fn check(sources: &Sources) -> Result<Checked> {
+    let mut checked = Checked::default();
+    let mut doc = Value::Table(toml::value::Table::new());
+    let mut config_path = None;
+    let mut looked = Vec::new();
+    let mut files = Vec::new();
+    // A file that didn't parse leaves settings missing that aren't worth reporting on their own
+    let mut broken = false;
+
+    let layers = [
+        (&sources.system, Origin::System as fn(PathBuf) -> Origin),
+        (&sources.user, Origin::User),
+        (&sources.project, Origin::Project),
+    ];
+    for (path, origin) in layers.iter() {
+        let path = match path {
+            Some(p) => p,
+            None => continue,
+        };
+        looked.push(path.display().to_string());
+        if !path.is_file() {
+            continue;
+        }
+        config_path = Some(path.to_string_lossy().into_owned());
+        let origin = origin(path.clone());
+        let p_str = path
+            .to_str()
+            .chain_err(|| "Config path is not valid unicode")?;
+        let text = file_contents_from_str_path(p_str)
+            .chain_err(|| ErrorKind::Config(format!("Could not read config file {}", p_str)))?;
+        match Spans::parse(&text) {
+            Ok(spans) => {
+                let layer: Value = toml::from_str(&text).chain_err(|| {
+                    ErrorKind::Config(format!("Could not read config file {}", p_str))
+                })?;
+                read_layer(path, layer, &origin, &mut doc, &mut checked.origins)?;
+                files.push((origin, spans));
+            }
+            Err((message, at)) => {
+                broken = true;
+                checked.diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    origin: Some(origin),
+                    line: at.map(|(l, _)| l),
+                    column: at.map(|(_, c)| c),
+                    key: None,
+                    message: message.lines().collect::<Vec<&str>>().join(" - "),
+                })
+            }
+        }
+    }
+    if let (true, Some(project)) = (sources.project_required, &sources.project) {
+        if !project.is_file() {
+            return Err(ErrorKind::Config(format!(
+                "Could not find config file {}",
+                project.display()
+            ))
+            .into());
+        }
+    }
+    if config_path.is_none() {
+        checked.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            origin: None,
+            line: None,
+            column: None,
+            key: None,
+            message: format!(
+                "no config file found - looked in {}.  `ar-bot config init` writes a fresh one",
+                looked.join(", ")
+            ),
+        });
+    }
+
+    checked.profiles = apply_profile(
+        &mut doc,
+        sources.profile.as_deref(),
+        &mut checked.origins,
+        &mut checked.aliases,
+    )?;
+
+    for (name, value) in &sources.env {
+        let rest = name["AR_BOT_".len()..].to_lowercase();
+        match rest.find('_') {
+            Some(i) => {
+                let key = format!("{}.{}", &rest[..i], &rest[i + 1..]);
+                insert_setting(&mut doc, &key, parse_setting(value))?;
+                checked.origins.insert(key, Origin::Env(name.clone()));
+            }
+            None => warn!("Ignoring {} - expected AR_BOT_SECTION_KEY", name),
+        }
+    }
+    for o in &sources.overrides {
+        let (key, value) = o
+            .split_once('=')
+            .chain_err(|| ErrorKind::Config(format!("--set {} should look like KEY=VALUE", o)))?;
+        insert_setting(&mut doc, key.trim(), parse_setting(value.trim()))?;
+        checked.origins.insert(key.trim().to_string(), Origin::Cli);
+    }
+
+    // Each failed attempt reports one problem - take out the bad value, or stand in a good one, and go again
+    let stand_ins =
+        Value::try_from(validate::full_config()).chain_err(|| "Could not read config as TOML")?;
+    let mut reported: Vec<String> = Vec::new();
+    let mut misspelled: Vec<String> = Vec::new();
+    let config = loop {
+        let e = match doc.clone().try_into::<Config>() {
+            Ok(config) => break Some(config),
+            Err(e) => e,
+        };
+        let (key, message) = split_key_context(&e.to_string());
+        if reported.contains(&key) {
+            break None;
+        }
+        // Other programs' AR_BOT_* variables aren't worth refusing to start over
+        let severity = match checked.origin_of(&key) {
+            Some(Origin::Env(_)) if message.starts_with("unknown field") => Severity::Warning,
+            _ => Severity::Error,
+        };
+        // A misspelled key already explains the missing one it was meant to be
+        let parent = key.rsplit_once('.').map_or("", |(p, _)| p).to_string();
+        if message.starts_with("unknown field") {
+            misspelled.push(parent);
+        } else if message.starts_with("missing field") && (broken || misspelled.contains(&parent)) {
+            reported.push(key.clone());
+        }
+        if !reported.contains(&key) {
+            checked.report(&files, &key, severity, message.clone());
+            reported.push(key.clone());
+        }
+        if message.starts_with("unknown field") {
+            remove_setting(&mut doc, &key);
+        } else {
+            match key.split('.').try_fold(&stand_ins, |v, part| v.get(part)) {
+                Some(v) => insert_setting(&mut doc, &key, v.clone())?,
+                None => break None,
+            }
+        }
+    };
+
+    if let Some(mut config) = config {
+        config.config_path = config_path;
+        config.profile = sources.profile.clone();
+        // Stand-ins aren't worth checking, but everything else still is
+        for (key, severity, message) in validate::semantics(&config) {
+            if !reported
+                .iter()
+                .any(|r| key == *r || key.starts_with(&format!("{}.", r)))
+            {
+                checked.report(&files, &key, severity, message);
+            }
+        }
+        // Anything still unaccounted for was filled in by serde
+        if let Value::Table(t) =
+            Value::try_from(&config).chain_err(|| "Could not read config as TOML")?
+        {
+            for leaf in leaves("", &t) {
+                checked.origins.entry(leaf).or_insert(Origin::Default);
+            }
+        }
+        checked.config = Some(config);
+    }
+
+    // File by file in layer order, top to bottom, then everything else
+    let order: Vec<Origin> = files.iter().map(|(o, _)| o.clone()).collect();
+    checked.diagnostics.sort_by_key(|d| {
+        let layer = d
+            .origin
+            .as_ref()
+            .and_then(|o| order.iter().position(|f| f == o))
+            .unwrap_or(order.len());
+        (layer, d.line, d.column)
+    });
+    Ok(checked)
+}
+
+/// Merges every layer into one Config, noting where each setting came from
+/// Warnings are logged, and any error fails the whole load with every problem listed
+pub fn load(sources: &Sources) -> Result<(Config, Origins)> {
+    let checked = check(sources)?;
+    for d in checked.diagnostics.iter().filter(|d| !d.is_error()) {
+        warn!("{}", d);
+    }
+    match checked.config {
+        Some(config) if checked.errors() == 0 => Ok((config, checked.origins)),
+        _ => {
+            let errors: Vec<String> = checked
+                .diagnostics
+                .iter()
+                .filter(|d| d.is_error())
+                .map(|d| format!("  {}", d))
+                .collect();
+            Err(ErrorKind::Config(format!(
+                "The config has {} problem{}:\n{}",
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" },
+                errors.join("\n")
+            ))
+            .into())
+        }
+    }
+}
+
+/// Loads the config from every layer, with the given file in place of the nearest Bot.toml
 pub fn init_config(s: Option<&str>) -> Result<Config> {
-    // FIXME why are we reading DEFAULT_CONFIG twice
-    let mut config: Config =
-        toml::from_str(&file_contents_from_str_path(s.unwrap_or(DEFAULT_CONFIG))
-            .chain_err(|| "Could not find config file")?)
-            .chain_err(|| "Could not read config file")?;
-    config.add_config_path(s.unwrap_or(DEFAULT_CONFIG))?;
-    Ok(config)
+    load(&Sources::discover(s, &[])?).map(|(config, _)| config)
+}
+
+/// Looks up a dotted key like `digest.render` in the loaded config, defaults included
+/// None if nothing is set there
+pub fn get_value(config: &Config, key: &str) -> Result<Option<Value>> {
+    let mut value = Value::try_from(config).chain_err(|| "Could not read config as TOML")?;
+    for part in key.split('.') {
+        match value.get(part) {
+            Some(v) => value = v.clone(),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value))
 }
 
+/// Sets a dotted key in the config file at path, adding tables as needed
+/// Comments, blank lines and key order are kept, along with any comment on the old value
+/// The value is taken as a string if that fits, otherwise as a TOML value like 587, true or ["a", "b"]
+/// Nothing is written unless the result still loads, with the key in it
+pub fn set_value(path: &str, key: &str, value: &str) -> Result<()> {
+    let contents = file_contents_from_str_path(path)
+        .chain_err(|| ErrorKind::Config("Could not find config file".into()))?;
+    let doc = contents
+        .parse::<DocumentMut>()
+        .chain_err(|| ErrorKind::Config("Could not read config file".into()))?;
+    let parts: Vec<&str> = key.split('.').collect();
+    // profile.NAME.digest.render is digest.render, once that profile is laid over the rest
+    let (profile, setting) = match parts.as_slice() {
+        ["profile", name, rest @ ..] if !rest.is_empty() => {
+            (Some(*name), &key["profile.".len() + name.len() + 1..])
+        }
+        _ => (None, key),
+    };
+
+    let mut candidates = vec![toml_edit::Value::from(value)];
+    if let Ok(literal) = value.parse::<toml_edit::Value>() {
+        candidates.push(literal);
+    }
+
+    let mut reason = String::new();
+    for candidate in candidates {
+        let mut new_doc = doc.clone();
+        set_in(&mut new_doc, &parts, candidate)?;
+        let text = new_doc.to_string();
+        match loads(&text, profile) {
+            Ok(config) => {
+                if get_value(&config, setting)?.is_none() {
+                    return Err(ErrorKind::Config(format!("{} isn't a config setting", key)).into());
+                }
+                // All at once, so a crash or a full disk can't leave half a config behind
+                write_atomic(Path::new(path), text.as_bytes())
+                    .chain_err(|| format!("Could not write {}", path))?;
+                return Ok(());
+            }
+            Err(e) => reason = e.to_string(),
+        }
+    }
+    Err(ErrorKind::Config(format!(
+        "{} = {} doesn't fit the config: {}",
+        key, value, reason
+    ))
+    .into())
+}
+
+// Whether a config file's text loads, with the given profile picked
+fn loads(text: &str, profile: Option<&str>) -> Result<Config> {
+    let mut doc: Value =
+        toml::from_str(text).chain_err(|| ErrorKind::Config("Could not read config".into()))?;
+    apply_profile(&mut doc, profile, &mut Origins::new(), &mut BTreeMap::new())?;
+    doc.try_into()
+        .map_err(|e| ErrorKind::Config(e.to_string()).into())
+}
+
+fn set_in(doc: &mut DocumentMut, parts: &[&str], value: toml_edit::Value) -> Result<()> {
+    let (last, tables) = parts.split_last().chain_err(|| "No key given")?;
+    let new_section = tables
+        .first()
+        .filter(|t| !doc.contains_key(t))
+        .map(|t| t.to_string());
+    let mut table: &mut dyn TableLike = doc.as_table_mut();
+    for (i, part) in tables.iter().enumerate() {
+        let created = !table.contains_key(part);
+        let item = table.entry(part).or_insert_with(toml_edit::table);
+        // No empty [profile] header above a new [profile.NAME.section]
+        if let (true, Some(t)) = (created && i + 1 < tables.len(), item.as_table_mut()) {
+            t.set_implicit(true);
+        }
+        table = item
+            .as_table_like_mut()
+            .chain_err(|| format!("{} is not a table", part))?;
+    }
+    match table.get_mut(last).and_then(|item| item.as_value_mut()) {
+        // Keep whatever comment was next to the old value
+        Some(old) => {
+            let decor = old.decor().clone();
+            *old = value;
+            *old.decor_mut() = decor;
+        }
+        None => {
+            table.insert(last, Item::Value(value));
+        }
+    }
+    // A new section goes at the end, after any commented-out example of it rather than before
+    if let Some(section) = new_section {
+        let mut trailing = doc.trailing().as_str().unwrap_or_default().to_string();
+        if !trailing.is_empty() {
+            trailing.push('\n');
+        }
+        if let Some(t) = doc.get_mut(&section).and_then(Item::as_table_mut) {
+            t.decor_mut().set_prefix(trailing);
+            doc.set_trailing("");
+        }
+    }
+    Ok(())
+}
+
+/// Writes a fresh, commented config file with every default spelled out
+/// Won't overwrite an existing file unless force is set
+pub fn write_default_config(path: &str, force: bool) -> Result<()> {
+    if !force && Path::new(path).exists() {
+        return Err(ErrorKind::Config(format!(
+            "{} already exists - pass --force to replace it",
+            path
+        ))
+        .into());
+    }
+    write(path, DEFAULT_CONFIG_FILE).chain_err(|| format!("Could not write {}", path))
+}
+
+/// What `ar-bot config init` writes
+pub static DEFAULT_CONFIG_FILE: &str = r#"# ar-bot configuration
+# Change settings with `ar-bot config set KEY VALUE` - it keeps these comments intact
+
+[directory]
+# Where alert emails are read from.  Digests and archived emails go in hx/ inside it
+path = "brain"
+# Compress archived emails (not supported yet)
+compressed = false
+# Only one ar-bot run works on the brain at a time.  A lock left behind by a run that died is
+# taken over straight away on the same machine, or after this many minutes from anywhere else
+stale_lock_minutes = 60
+
+[digest]
+# html, text or markdown
+render = "html"
+# A directory of tera templates to use instead of the built-in ones
+# template_dir = "my-templates"
+# Alert times: "all" as they came, "counted" to list repeats once with a count (1:00 PM ×5),
+# or "days" for one line a day (Jul 18 1:00–4:30 PM, 7 alerts)
+times = "counted"
+# Cut one digest per group below, each sent to its own recipients, instead of one for everything.
+# Products in no group share a digest of their own, sent to `to` under [smtp].  Same as --split
+split = false
+# [[digest.groups]]
+# name = "Camp Transportation"
+# products = ["CAMP_KALE_TRANS", "CAMP_KING_TRANS"]
+# to = ["transport@example.org"]   # leave out for `to` under [smtp]
+
+# Sending the digest with `ar-bot digest --send`
+# [smtp]
+# host = "smtp.example.org"
+# port = 587
+# security = "starttls"        # starttls, tls or none
+# username = "ar-bot"
+# password = "..."             # or set AR_BOT_SMTP_PASSWORD
+# from = "AR-Bot <ar-bot@example.org>"
+# to = ["finance@example.org"]
+# subject = "AR-Bot Digest"
+
+# Fetching alerts with `ar-bot fetch`
+# [imap]
+# host = "imap.example.org"
+# port = 993
+# security = "tls"             # tls, starttls or none
+# username = "ar-bot"
+# password = "..."             # or set AR_BOT_IMAP_PASSWORD
+# folder = "INBOX"
+# processed_folder = "Processed"
+
+# Cutting digests on a schedule with `ar-bot auto`
+# [auto]
+# min_ids = 20
+# min_alerts = 40
+# max_age_hours = 48
+# days = ["mon", "wed", "fri"]
+# not_before = "09:00"
+# send = false
+# exports = ["csv"]
+# interval_minutes = 15
+
+# Flagging entries that look wrong, in the digest and in preview.  0 turns a check off
+[anomalies]
+# More alerts than this for one ID and product within repeat_window_hours, counting earlier digests
+repeat_alerts = 3
+repeat_window_hours = 168
+# One ID alerting for this many products or more
+many_products = 3
+# This many IDs or more alerting in the same minute - a bulk job rather than people
+same_minute = 5
+# Product codes ar-bot doesn't know
+unknown_products = true
+
+# Profiles, picked with `--profile NAME` or all in turn with `--all-profiles`
+# Each one is laid over the settings above, so it only needs what's different
+# [profile.east.directory]
+# path = "east-brain"
+# [profile.east.smtp]
+# to = ["ar-east@example.org"]
+"#;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempBrain;
+
+    // What init_config loads from just this file - no /etc, XDG dir, AR_BOT_* variables or parent
+    // directories, so nothing on the machine running the tests gets in
+    fn file_config(path: &str) -> Config {
+        let sources = Sources {
+            system: None,
+            user: None,
+            project: Some(path.into()),
+            project_required: true,
+            env: vec![],
+            ..Sources::default()
+        };
+        load(&sources).unwrap().0
+    }
 
     #[test]
     fn test_init_config_default() {
-        assert_eq!(init_config(None).unwrap(), Config::default())
+        // The Bot.toml init_config(None) finds from the crate root, where cargo runs the tests
+        assert_eq!(file_config("Bot.toml"), Config::default())
     }
     #[test]
     fn test_init_config_alterate_file() {
         assert_eq!(
-            init_config(Some("Alternate.toml")).unwrap(),
+            file_config("Alternate.toml"),
             Config {
                 config_path: Some("Alternate.toml".into()),
+                profile: None,
                 directory: Directory {
                     compressed: true,
                     path: "storage".into(),
-                }
+                    stale_lock_minutes: 60,
+                },
+                digest: Digest {
+                    render: Renderer::Text,
+                    template_dir: None,
+                    times: TimeDetail::Counted,
+                    split: false,
+                    groups: Vec::new(),
+                },
+                smtp: None,
+                imap: None,
+                auto: None,
+                anomalies: Anomalies::default(),
+            }
+        )
+    }
+    #[test]
+    fn test_get_value() {
+        let config = file_config("Alternate.toml");
+        assert_eq!(
+            get_value(&config, "directory.path").unwrap(),
+            Some(Value::String("storage".into()))
+        );
+        assert_eq!(
+            get_value(&config, "digest.render").unwrap(),
+            Some(Value::String("text".into()))
+        );
+        assert_eq!(get_value(&config, "smtp.host").unwrap(), None);
+    }
+    #[test]
+    fn test_set_value() {
+        let dir = TempBrain::new();
+        let path = dir.join("Bot.toml");
+        let path = path.to_str().unwrap();
+        write(path, "[directory]\ncompressed = false\npath = \"brain\"\n").unwrap();
+
+        set_value(path, "directory.compressed", "true").unwrap();
+        set_value(path, "digest.render", "markdown").unwrap();
+        assert!(set_value(path, "digest.rendr", "markdown").is_err());
+        assert!(set_value(path, "digest.render", "pdf").is_err());
+        let config = file_config(path);
+
+        assert!(config.directory.compressed);
+        assert_eq!(config.digest.render, Renderer::Markdown);
+    }
+    #[test]
+    fn test_set_value_keeps_comments() {
+        let dir = TempBrain::new();
+        let path = dir.join("Bot.toml");
+        let path = path.to_str().unwrap();
+        write_default_config(path, false).unwrap();
+        assert!(write_default_config(path, false).is_err());
+
+        set_value(path, "digest.render", "text").unwrap();
+        set_value(path, "directory.path", "/var/ar-bot").unwrap();
+        let contents = file_contents_from_str_path(path).unwrap();
+
+        assert_eq!(
+            contents,
+            DEFAULT_CONFIG_FILE
+                .replace("render = \"html\"", "render = \"text\"")
+                .replace("path = \"brain\"", "path = \"/var/ar-bot\"")
+        );
+    }
+    #[test]
+    fn test_default_config_file() {
+        let config: Config = toml::from_str(DEFAULT_CONFIG_FILE).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                config_path: None,
+                ..Config::default()
             }
+        );
+        // Every commented-out section should load once uncommented
+        let uncommented: String = DEFAULT_CONFIG_FILE
+            .lines()
+            .map(|l| {
+                l.strip_prefix("# ")
+                    .filter(|l| l.starts_with('[') || l.contains(" = "))
+                    .unwrap_or(l)
+            })
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let config = loads(&uncommented, Some("east")).unwrap();
+        assert!(config.smtp.is_some() && config.imap.is_some() && config.auto.is_some());
+        assert_eq!(config.directory.path, PathBuf::from("east-brain"));
+    }
+    #[test]
+    fn test_set_value_new_section() {
+        let dir = TempBrain::new();
+        let path = dir.join("Bot.toml");
+        let path = path.to_str().unwrap();
+        write_default_config(path, false).unwrap();
+        set_value(path, "auto.min_ids", "20").unwrap();
+        let contents = file_contents_from_str_path(path).unwrap();
+
+        assert_eq!(
+            contents,
+            format!("{}\n[auto]\nmin_ids = 20\n", DEFAULT_CONFIG_FILE)
+        );
+    }
+    #[test]
+    fn test_load_layers() {
+        let dir = TempBrain::new();
+        std::fs::create_dir_all(dir.join("user")).unwrap();
+        let system = dir.join("system.toml");
+        let user = dir.join("user").join("Bot.toml");
+        write(
+            &system,
+            "[directory]\ncompressed = true\npath = \"/srv/brain\"\n[digest]\nrender = \"text\"\n",
+        )
+        .unwrap();
+        write(
+            &user,
+            "[directory]\ncompressed = true\npath = \"brain\"\n[auto]\nmin_ids = 5\n",
+        )
+        .unwrap();
+        let sources = Sources {
+            system: Some(system.clone()),
+            user: Some(user.clone()),
+            project: Some(dir.join("missing.toml")),
+            project_required: false,
+            env: vec![
+                ("AR_BOT_DIGEST_RENDER".into(), "markdown".into()),
+                ("AR_BOT_AUTO_MIN_IDS".into(), "10".into()),
+            ],
+            overrides: vec![
+                "auto.days=[\"mon\"]".into(),
+                "auto.not_before = 09:00".into(),
+            ],
+            profile: None,
+        };
+        let (config, origins) = load(&sources).unwrap();
+
+        assert_eq!(
+            config.config_path,
+            Some(user.to_string_lossy().into_owned())
+        );
+        // Relative to the user file, not the working directory
+        assert_eq!(config.directory.path, dir.join("user").join("brain"));
+        assert_eq!(config.digest.render, Renderer::Markdown);
+        let auto = config.auto.unwrap();
+        assert_eq!(auto.min_ids, Some(10));
+        assert_eq!(auto.days, vec!["mon".to_string()]);
+        assert_eq!(auto.not_before, Some("09:00".into()));
+
+        assert_eq!(origins["directory.compressed"], Origin::User(user));
+        assert_eq!(
+            origins["digest.render"],
+            Origin::Env("AR_BOT_DIGEST_RENDER".into())
+        );
+        assert_eq!(origins["auto.days"], Origin::Cli);
+        assert_eq!(origins["auto.interval_minutes"], Origin::Default);
+    }
+    #[test]
+    fn test_discover_layers() {
+        // The real lookup: the user file under $XDG_CONFIG_HOME, -c, AR_BOT_* variables and --set
+        // Only keys the system file can't outrank are checked, in case /etc/ar-bot/Bot.toml exists here
+        let dir = TempBrain::new();
+        let user_dir = dir.join("xdg").join("ar-bot");
+        std::fs::create_dir_all(&user_dir).unwrap();
+        write(
+            user_dir.join("Bot.toml"),
+            "[directory]\ncompressed = true\npath = \"brain\"\n[digest]\nrender = \"text\"\n[auto]\nmin_ids = 1\n",
+        )
+        .unwrap();
+        let project = dir.join("Project.toml");
+        write(
+            &project,
+            "[directory]\ncompressed = false\npath = \"brain\"\n[auto]\nmin_ids = 2\n",
+        )
+        .unwrap();
+        std::env::set_var("XDG_CONFIG_HOME", dir.join("xdg"));
+        std::env::set_var("AR_BOT_DIGEST_RENDER", "markdown");
+        std::env::set_var("AR_BOT_AUTO_MIN_IDS", "3");
+        let sources = Sources::discover(project.to_str(), &["auto.min_ids=4"]);
+        let fallback = Sources::discover(project.to_str(), &[]);
+        std::env::remove_var("XDG_CONFIG_HOME");
+        std::env::remove_var("AR_BOT_DIGEST_RENDER");
+        std::env::remove_var("AR_BOT_AUTO_MIN_IDS");
+        let (config, origins) = load(&sources.unwrap()).unwrap();
+        let (without_set, _) = load(&fallback.unwrap()).unwrap();
+
+        // The project file over the user file, and paths relative to the project file
+        assert!(!config.directory.compressed);
+        assert_eq!(origins["directory.compressed"], Origin::Project(project));
+        assert_eq!(config.directory.path, dir.join("brain"));
+        // The environment over the files
+        assert_eq!(config.digest.render, Renderer::Markdown);
+        assert_eq!(
+            origins["digest.render"],
+            Origin::Env("AR_BOT_DIGEST_RENDER".into())
+        );
+        // --set over everything
+        assert_eq!(config.auto.unwrap().min_ids, Some(4));
+        assert_eq!(origins["auto.min_ids"], Origin::Cli);
+        assert_eq!(without_set.auto.unwrap().min_ids, Some(3));
+    }
+    #[test]
+    fn test_load_missing_required_project() {
+        let sources = Sources {
+            project: Some("Missing.toml".into()),
+            project_required: true,
+            ..Sources::default()
+        };
+        assert!(load(&sources).is_err());
+    }
+    #[test]
+    fn test_check_finds_everything() {
+        let dir = TempBrain::new();
+        let project = dir.join("Bot.toml");
+        write(
+            &project,
+            "[directory]\ncompressed = \"no\"\npath = \"brain\"\n\n[digest]\nrendr = \"text\"\n\n[auto]\nmin_ids = 3\nnot_before = \"9am\"\n",
+        )
+        .unwrap();
+        let sources = Sources {
+            project: Some(project.clone()),
+            env: vec![("AR_BOT_UNRELATED_THING".into(), "1".into())],
+            ..Sources::default()
+        };
+        let checked = check(&sources).unwrap();
+        let broken = dir.join("Broken.toml");
+        write(&broken, "[directory]\npath = \"brain\"\ncompressed = \n").unwrap();
+        let syntax = check(&Sources {
+            project: Some(broken),
+            ..Sources::default()
+        })
+        .unwrap();
+
+        let found: Vec<(Option<&str>, Option<usize>, bool)> = checked
+            .diagnostics
+            .iter()
+            .map(|d| (d.key.as_deref(), d.line, d.is_error()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some("directory.compressed"), Some(2), true),
+                (Some("digest.rendr"), Some(6), true),
+                (Some("auto.not_before"), Some(10), true),
+                (Some("unrelated"), None, false),
+            ]
+        );
+        assert_eq!(
+            checked.diagnostics[0].origin,
+            Some(Origin::Project(project))
+        );
+        assert_eq!(checked.errors(), 3);
+        assert!(load(&sources).is_err());
+
+        assert_eq!(syntax.diagnostics.len(), 1);
+        assert_eq!(
+            (syntax.diagnostics[0].line, syntax.diagnostics[0].column),
+            (Some(3), Some(14))
+        );
+    }
+    #[test]
+    fn test_default_config_file_checks_clean() {
+        let dir = TempBrain::new();
+        let project = dir.join("Bot.toml");
+        write(&project, DEFAULT_CONFIG_FILE).unwrap();
+        let checked = check(&Sources {
+            project: Some(project),
+            ..Sources::default()
+        })
+        .unwrap();
+
+        assert_eq!(checked.diagnostics, Vec::new());
+    }
+    #[test]
+    fn test_profiles() {
+        let dir = TempBrain::new();
+        let project = dir.join("Bot.toml");
+        write(
+            &project,
+            "[directory]\ncompressed = false\npath = \"brain\"\n\n[profile.east.directory]\npath = \"east\"\n\n[profile.west.digest]\nrender = \"txt\"\n",
         )
+        .unwrap();
+        let sources = |profile: Option<&str>| Sources {
+            project: Some(project.clone()),
+            profile: profile.map(String::from),
+            ..Sources::default()
+        };
+        let (base, _) = load(&sources(None)).unwrap();
+        let (east, origins) = load(&sources(Some("east"))).unwrap();
+        let west = check(&sources(Some("west"))).unwrap();
+        let missing = load(&sources(Some("north")));
+        set_value(
+            project.to_str().unwrap(),
+            "profile.west.digest.render",
+            "text",
+        )
+        .unwrap();
+        let (fixed, _) = load(&sources(Some("west"))).unwrap();
+
+        assert_eq!(base.directory.path, dir.join("brain"));
+        assert_eq!(base.profile, None);
+        assert_eq!(east.directory.path, dir.join("east"));
+        assert_eq!(east.profile, Some("east".into()));
+        assert_eq!(origins["directory.path"], Origin::Project(project));
+        assert!(!origins.keys().any(|k| k.starts_with("profile")));
+        assert_eq!(west.profiles, vec!["east".to_string(), "west".to_string()]);
+        // Pointed at where it was written, inside the profile
+        assert_eq!(west.diagnostics[0].key.as_deref(), Some("digest.render"));
+        assert_eq!(west.diagnostics[0].line, Some(9));
+        assert!(missing.is_err());
+        assert_eq!(fixed.digest.render, Renderer::Text);
     }
 }
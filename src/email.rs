@@ -11,7 +11,8 @@ use std::fmt;
 // Instead of just the contents, this should have the proper fields
 // filename, date, id, product
 // except that's exactly what a batch::Entry is
-#[derive(Debug)]
+/// One raw alert, named after the file it was read from
+#[derive(Clone, Debug)]
 pub struct Email {
     pub filename: String,
     pub contents: String
@@ -43,7 +44,7 @@ impl std::str::FromStr for Email {
         email_contents.push_str(s);
         println!("RAWFROMSTR: {}", email_contents);
         Ok(Email {
-            filename: format!("Saturday, July 21, 2018 4:39 PM.html"),
+            filename: "Saturday, July 21, 2018 4:39 PM.html".to_string(),
             contents: email_contents,
         })
     }
@@ -0,0 +1,187 @@
+//! Every fallible function in the crate returns `errors::Result`
+//!
+//! Most errors are plain messages, but the ones worth telling apart from outside - a bad config,
+//! an alert that won't parse, a digest that couldn't be archived or delivered - carry an
+//! [`ErrorKind`] saying so, and [`Error::category`] finds it anywhere in the chain.
+#![allow(unexpected_cfgs)]
+use error_chain::error_chain;
+use std::{error::Error as StdError, fmt};
+
+error_chain! {
+    errors {
+        /// The command line parsed, but asks for something that can't be done
+        Usage(msg: String) {
+            description("usage error")
+            display("{}", msg)
+        }
+        /// The config is missing, unreadable or invalid
+        Config(msg: String) {
+            description("configuration error")
+            display("{}", msg)
+        }
+        /// An alert email, or a batch, couldn't be read
+        Parse(msg: String) {
+            description("parse error")
+            display("{}", msg)
+        }
+        /// A digest template is missing or didn't render
+        Template(msg: String) {
+            description("template error")
+            display("{}", msg)
+        }
+        /// The digest or its inputs couldn't be written to hx/
+        Archive(msg: String) {
+            description("archive error")
+            display("{}", msg)
+        }
+        /// Talking to the SMTP or IMAP server failed
+        Delivery(msg: String) {
+            description("delivery error")
+            display("{}", msg)
+        }
+        /// Another run holds the lock on the brain
+        Locked(msg: String) {
+            description("brain locked")
+            display("{}", msg)
+        }
+    }
+}
+
+// Exit codes - an error exits with the one for its category, see Error::exit_code()
+// The command line promises these in --help, so don't renumber them
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+/// The command line didn't parse
+pub const EXIT_USAGE: i32 = 2;
+/// search matched nothing, or config get found no such setting
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_CONFIG: i32 = 4;
+pub const EXIT_IO: i32 = 5;
+pub const EXIT_PARSE: i32 = 6;
+pub const EXIT_TEMPLATE: i32 = 7;
+pub const EXIT_ARCHIVE: i32 = 8;
+pub const EXIT_DELIVERY: i32 = 9;
+/// Another run has the brain - try again once it's done
+pub const EXIT_LOCKED: i32 = 10;
+
+/// What kind of failure an error comes down to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Usage,
+    Config,
+    Io,
+    Parse,
+    Template,
+    Archive,
+    Delivery,
+    Locked,
+    // Anything else
+    Other,
+}
+
+impl Category {
+    /// The process exit code for a failure of this kind
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Category::Usage => EXIT_USAGE,
+            Category::Config => EXIT_CONFIG,
+            Category::Io => EXIT_IO,
+            Category::Parse => EXIT_PARSE,
+            Category::Template => EXIT_TEMPLATE,
+            Category::Archive => EXIT_ARCHIVE,
+            Category::Delivery => EXIT_DELIVERY,
+            Category::Locked => EXIT_LOCKED,
+            Category::Other => EXIT_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Category::Usage => "usage",
+            Category::Config => "config",
+            Category::Io => "io",
+            Category::Parse => "parse",
+            Category::Template => "template",
+            Category::Archive => "archive",
+            Category::Delivery => "delivery",
+            Category::Locked => "locked",
+            Category::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Error {
+    /// The outermost kind in the chain that says, or Io if it comes down to a std::io::Error
+    pub fn category(&self) -> Category {
+        let mut io = false;
+        let mut next: Option<&(dyn StdError + 'static)> = Some(self);
+        while let Some(link) = next {
+            next = link.source();
+            if let Some(e) = link.downcast_ref::<Error>() {
+                match e.kind() {
+                    ErrorKind::Usage(_) => return Category::Usage,
+                    ErrorKind::Config(_) => return Category::Config,
+                    ErrorKind::Parse(_) => return Category::Parse,
+                    ErrorKind::Template(_) => return Category::Template,
+                    ErrorKind::Archive(_) => return Category::Archive,
+                    ErrorKind::Delivery(_) => return Category::Delivery,
+                    ErrorKind::Locked(_) => return Category::Locked,
+                    _ => {}
+                }
+            }
+            io |= link.is::<std::io::Error>();
+        }
+        if io {
+            Category::Io
+        } else {
+            Category::Other
+        }
+    }
+
+    /// The exit code for this error, going by its category
+    pub fn exit_code(&self) -> i32 {
+        self.category().exit_code()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_category() {
+        let plain: Error = "plain".into();
+        assert_eq!(plain.category(), Category::Other);
+
+        let disk: Result<()> = Err(io::Error::other("disk full")).chain_err(|| "Could not write");
+        assert_eq!(disk.unwrap_err().category(), Category::Io);
+
+        // The outermost kind wins, however deep the io::Error underneath is
+        let archive = Err::<(), _>(io::Error::other("disk full"))
+            .chain_err(|| "Could not write")
+            .chain_err(|| ErrorKind::Archive("Could not archive".into()))
+            .chain_err(|| "Could not cut digest");
+        assert_eq!(archive.unwrap_err().category(), Category::Archive);
+
+        let config: Error = ErrorKind::Config("bad".into()).into();
+        assert_eq!(config.category(), Category::Config);
+        assert_eq!(config.to_string(), "bad");
+    }
+    #[test]
+    fn test_exit_code() {
+        let locked: Error = ErrorKind::Locked("busy".into()).into();
+        assert_eq!(locked.exit_code(), EXIT_LOCKED);
+        let plain: Error = "plain".into();
+        assert_eq!(plain.exit_code(), EXIT_ERROR);
+        assert_eq!(Category::Io.exit_code(), EXIT_IO);
+        let usage: Error = ErrorKind::Usage("both".into()).into();
+        assert_eq!(
+            (usage.category().to_string(), usage.exit_code()),
+            ("usage".into(), EXIT_USAGE)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// export.rs renders the digest as a spreadsheet, staged by the journal alongside digest-TIMESTAMP.html
+use crate::{
+    batch::{alert_time_order, parse_alert_time, Batch},
+    errors::*,
+};
+use rust_xlsxwriter::{Format, Workbook};
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+static HEADERS: [&str; 9] = [
+    "iMIS ID",
+    "Product Code",
+    "Product",
+    "Alerts",
+    "First Alert",
+    "Last Alert",
+    "Was",
+    "New",
+    "Initials",
+];
+
+/// Spreadsheet formats a digest can also be written as
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Export {
+    Csv,
+    Xlsx,
+}
+
+impl Export {
+    /// File extension, also used as the command line value
+    pub fn extension(self) -> &'static str {
+        match self {
+            Export::Csv => "csv",
+            Export::Xlsx => "xlsx",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Export::Csv => "text/csv",
+            Export::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        }
+    }
+
+    /// The whole file, built in memory
+    pub fn to_bytes(self, batch: &Batch) -> Result<Vec<u8>> {
+        let rows = DigestRow::from_batch(batch);
+        match self {
+            Export::Csv => csv_bytes(&rows),
+            Export::Xlsx => xlsx_bytes(&rows),
+        }
+    }
+}
+
+impl FromStr for Export {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Export::Csv),
+            "xlsx" => Ok(Export::Xlsx),
+            _ => Err(format!("Unknown export format {}", s).into()),
+        }
+    }
+}
+
+// One row per (iMIS ID, product).  Was, New and Initials are left blank for whoever is checking the autodraft
+#[derive(Debug, PartialEq, Serialize)]
+struct DigestRow {
+    id: u32,
+    code: String,
+    product: String,
+    alerts: usize,
+    first_alert: String,
+    last_alert: String,
+    was: String,
+    new: String,
+    initials: String,
+}
+
+impl DigestRow {
+    // Rows are sorted by id, then product code, so the sheet matches from one run to the next
+    fn from_batch(batch: &Batch) -> Vec<Self> {
+        let mut rows = Vec::new();
+        for entry in batch.entries.values() {
+            for (product, times) in &entry.alerts {
+                // Times we can't parse go after the rest, in the order they were read in
+                let mut sorted = times.clone();
+                sorted.sort_by(|a, b| alert_time_order(parse_alert_time(a), parse_alert_time(b)));
+                rows.push(DigestRow {
+                    id: entry.id,
+                    code: product.code().into(),
+                    product: product.to_string(),
+                    alerts: times.len(),
+                    first_alert: sorted.first().cloned().unwrap_or_default(),
+                    last_alert: sorted.last().cloned().unwrap_or_default(),
+                    was: String::new(),
+                    new: String::new(),
+                    initials: String::new(),
+                });
+            }
+        }
+        rows.sort_by(|a, b| (a.id, &a.code).cmp(&(b.id, &b.code)));
+        rows
+    }
+}
+
+fn csv_bytes(rows: &[DigestRow]) -> Result<Vec<u8>> {
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    wtr.write_record(HEADERS.iter())
+        .chain_err(|| ErrorKind::Archive("Could not write CSV header".into()))?;
+    for row in rows {
+        wtr.serialize(row)
+            .chain_err(|| ErrorKind::Archive("Could not write CSV row".into()))?;
+    }
+    wtr.into_inner()
+        .chain_err(|| ErrorKind::Archive("Could not write CSV export".into()))
+}
+
+fn xlsx_bytes(rows: &[DigestRow]) -> Result<Vec<u8>> {
+    let mut workbook = Workbook::new();
+    let bold = Format::new().set_bold();
+    let sheet = workbook.add_worksheet();
+    sheet
+        .set_name("Digest")
+        .chain_err(|| ErrorKind::Archive("Could not name XLSX sheet".into()))?;
+
+    for (col, header) in HEADERS.iter().enumerate() {
+        sheet
+            .write_string_with_format(0, col as u16, *header, &bold)
+            .chain_err(|| ErrorKind::Archive("Could not write XLSX header".into()))?;
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let r = i as u32 + 1;
+        sheet
+            .write_number(r, 0, row.id)
+            .and_then(|s| s.write_string(r, 1, &row.code))
+            .and_then(|s| s.write_string(r, 2, &row.product))
+            .and_then(|s| s.write_number(r, 3, row.alerts as u32))
+            .and_then(|s| s.write_string(r, 4, &row.first_alert))
+            .and_then(|s| s.write_string(r, 5, &row.last_alert))
+            .chain_err(|| ErrorKind::Archive("Could not write XLSX row".into()))?;
+    }
+    for (col, width) in [10, 18, 28, 8, 34, 34, 16, 16, 10].iter().enumerate() {
+        sheet
+            .set_column_width(col as u16, *width)
+            .chain_err(|| ErrorKind::Archive("Could not size XLSX columns".into()))?;
+    }
+    sheet
+        .set_freeze_panes(1, 0)
+        .chain_err(|| ErrorKind::Archive("Could not freeze XLSX header".into()))?;
+
+    workbook
+        .save_to_buffer()
+        .chain_err(|| ErrorKind::Archive("Could not write XLSX export".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch::Entry, email::Email, util::*};
+
+    #[test]
+    fn test_digest_rows() {
+        let mut batch = Batch::test_second_email_str(TEST_DIF_BOTH);
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_COOL_STR).unwrap()).unwrap())
+            .unwrap();
+        let rows = DigestRow::from_batch(&batch);
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].id, rows[0].alerts), (12345, 2));
+        assert_eq!((rows[1].id, rows[1].alerts), (22345, 1));
+        assert_eq!(rows[0].first_alert, "Saturday, July 21, 2018 4:39 PM");
+    }
+    #[test]
+    fn test_digest_rows_unparsed_time() {
+        let mut batch = Batch::test();
+        for be in batch.entries.values_mut() {
+            for times in be.alerts.values_mut() {
+                times.insert(0, "sometime last week".into());
+                times.push("Friday, July 20, 2018 9:00 AM".into());
+            }
+        }
+        let rows = DigestRow::from_batch(&batch);
+        // The one that didn't parse goes last, not first
+        assert_eq!(rows[0].alerts, 3);
+        assert_eq!(rows[0].first_alert, "Friday, July 20, 2018 9:00 AM");
+        assert_eq!(rows[0].last_alert, "sometime last week");
+    }
+    #[test]
+    fn test_csv_bytes() {
+        let bytes = Export::Csv.to_bytes(&Batch::test()).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "iMIS ID,Product Code,Product,Alerts,First Alert,Last Alert,Was,New,Initials\n\
+             12345,COOL_PROD,Non-builtin product COOL_PROD,1,\"Saturday, July 21, 2018 4:39 PM\",\"Saturday, July 21, 2018 4:39 PM\",,,\n"
+        )
+    }
+}
@@ -0,0 +1,465 @@
+// history.rs reads back what earlier digests left behind in hx/
+use crate::{
+    batch::{Batch, BatchEntry, Entry, Product, UserID},
+    brain::{read_email_file, Context},
+    email::Email,
+    errors::*,
+    parser::Registry,
+    util::write_atomic,
+};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// One digest cut in the past: the files written for it, and the emails archived from it
+#[derive(Debug, Default, PartialEq)]
+pub struct Record {
+    pub timestamp: i64,
+    // digest-TIMESTAMP.html, .csv, .eml and so on, sorted by name
+    // A split digest has digest-TIMESTAMP-GROUP.html and so on for each group as well
+    pub files: Vec<PathBuf>,
+    // hx/TIMESTAMP/ - digests cut before inputs were archived don't have one
+    pub archive: Option<PathBuf>,
+}
+
+impl Record {
+    /// When the digest was cut, in local time
+    pub fn generated(&self) -> String {
+        local_time(self.timestamp, "%Y-%m-%d %H:%M")
+    }
+
+    /// Every email archived when this digest was cut, in filename order
+    pub fn emails(&self) -> Result<Vec<Email>> {
+        let mut ret = Vec::new();
+        if let Some(archive) = &self.archive {
+            for path in sorted_dir(archive)? {
+                ret.extend(read_email_file(&path)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Rebuilds the batch this digest was cut from
+    pub fn batch(&self) -> Result<Batch> {
+        let mut batch = Batch::new();
+        for email in self.emails()? {
+            batch.add_entry(Entry::from_email(&email)?)?;
+        }
+        Ok(batch)
+    }
+}
+
+fn local_time(timestamp: i64, format: &str) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(t) => t.format(format).to_string(),
+        None => "unknown".into(),
+    }
+}
+
+fn sorted_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for f in read_dir(path).chain_err(|| format!("Could not read {:?}", path))? {
+        ret.push(
+            f.chain_err(|| format!("Could not read entry in {:?}", path))?
+                .path(),
+        );
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Every digest in hx/, oldest first
+pub fn list(hx_path: &Path) -> Result<Vec<Record>> {
+    lazy_static! {
+        static ref DIGEST_RE: Regex = Regex::new(r"^digest-(?P<timestamp>\d+)[.-]").unwrap();
+        static ref ARCHIVE_RE: Regex = Regex::new(r"^\d+$").unwrap();
+    }
+
+    let mut records: BTreeMap<i64, Record> = BTreeMap::new();
+    if !hx_path.exists() {
+        return Ok(Vec::new());
+    }
+    for path in sorted_dir(hx_path)? {
+        let name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        if path.is_dir() && ARCHIVE_RE.is_match(&name) {
+            let timestamp = name
+                .parse()
+                .chain_err(|| format!("Bad archive name {}", name))?;
+            records.entry(timestamp).or_default().archive = Some(path);
+        } else if let Some(caps) = DIGEST_RE.captures(&name) {
+            let timestamp = caps["timestamp"]
+                .parse()
+                .chain_err(|| format!("Bad digest name {}", name))?;
+            records.entry(timestamp).or_default().files.push(path);
+        }
+    }
+    Ok(records
+        .into_iter()
+        .map(|(timestamp, mut r)| {
+            r.timestamp = timestamp;
+            r
+        })
+        .collect())
+}
+
+/// The digest cut at exactly this timestamp
+pub fn find(hx_path: &Path, timestamp: i64) -> Result<Record> {
+    list(hx_path)?
+        .into_iter()
+        .find(|r| r.timestamp == timestamp)
+        .chain_err(|| format!("No digest {} in {:?}", timestamp, hx_path))
+}
+
+// Sheets are initialed on paper, so ar-bot only knows one was verified once someone says so
+// hx/TIMESTAMP.verified lists the IDs marked verified on that digest, one per line
+fn verified_path(hx_path: &Path, timestamp: i64) -> PathBuf {
+    hx_path.join(format!("{}.verified", timestamp))
+}
+
+/// The IDs marked verified on a digest with `history verify` - empty if none were
+pub fn verified(hx_path: &Path, timestamp: i64) -> Result<BTreeSet<UserID>> {
+    let path = verified_path(hx_path, timestamp);
+    if !path.exists() {
+        return Ok(BTreeSet::new());
+    }
+    let contents = read_to_string(&path).chain_err(|| format!("Could not read {:?}", path))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            l.parse()
+                .chain_err(|| format!("Bad iMIS ID {} in {:?}", l, path))
+        })
+        .collect()
+}
+
+/// Records that IDs on a digest's sheets were checked and initialed - every ID in it if none are given
+/// Returns the IDs that weren't marked already
+pub fn mark_verified(hx_path: &Path, timestamp: i64, ids: &[UserID]) -> Result<Vec<UserID>> {
+    let record = find(hx_path, timestamp)?;
+    if record.archive.is_none() {
+        return Err(format!(
+            "Digest {} has no archive, so there's no telling which IDs were in it",
+            timestamp
+        )
+        .into());
+    }
+    let in_digest: BTreeSet<UserID> = record.batch()?.entries.keys().copied().collect();
+    for id in ids {
+        if !in_digest.contains(id) {
+            return Err(format!("iMIS ID {} isn't in digest {}", id, timestamp).into());
+        }
+    }
+    let marking: BTreeSet<UserID> = if ids.is_empty() {
+        in_digest
+    } else {
+        ids.iter().copied().collect()
+    };
+
+    let mut all = verified(hx_path, timestamp)?;
+    let new: Vec<UserID> = marking.into_iter().filter(|id| !all.contains(id)).collect();
+    all.extend(&new);
+    let lines: Vec<String> = all.iter().map(|id| format!("{}\n", id)).collect();
+    let path = verified_path(hx_path, timestamp);
+    write_atomic(&path, lines.concat().as_bytes())
+        .chain_err(|| format!("Could not write {:?}", path))?;
+    Ok(new)
+}
+
+/// One earlier digest an iMIS ID was in, and what for
+#[derive(Debug, PartialEq)]
+pub struct Appearance {
+    pub timestamp: i64,
+    // Each product, and its alert times in that digest
+    pub alerts: Vec<(Product, Vec<String>)>,
+    // Marked verified with `history verify` since
+    pub verified: bool,
+}
+
+impl Appearance {
+    /// When the digest was cut, in local time
+    pub fn generated(&self) -> String {
+        local_time(self.timestamp, "%Y-%m-%d %H:%M")
+    }
+
+    fn products(&self) -> String {
+        let codes: Vec<&str> = self.alerts.iter().map(|(p, _)| p.code()).collect();
+        codes.join(", ")
+    }
+
+    /// Whether its sheet was marked verified, as shown in notes and `history id`
+    pub fn status(&self) -> &'static str {
+        if self.verified {
+            "verified"
+        } else {
+            "unverified"
+        }
+    }
+}
+
+/// Every earlier digest each iMIS ID was in, read back from the archives in hx/
+#[derive(Debug, Default)]
+pub struct History {
+    // Oldest first
+    ids: HashMap<UserID, Vec<Appearance>>,
+}
+
+impl History {
+    /// Reads every archive in hx/ - emails in them that aren't alerts are skipped
+    pub fn read(hx_path: &Path) -> Result<Self> {
+        let registry = Registry::default();
+        let mut ids: HashMap<UserID, Vec<Appearance>> = HashMap::new();
+        for record in list(hx_path)? {
+            let verified = verified(hx_path, record.timestamp)?;
+            let mut batch = Batch::new();
+            for email in record.emails()? {
+                if let Ok(entry) = registry.parse(&email) {
+                    batch.add_entry(entry)?;
+                }
+            }
+            for (id, be) in batch.entries {
+                ids.entry(id).or_default().push(Appearance {
+                    timestamp: record.timestamp,
+                    alerts: be.alerts_vec(),
+                    verified: verified.contains(&id),
+                });
+            }
+        }
+        Ok(History { ids })
+    }
+
+    /// Every earlier digest this ID was in, oldest first
+    pub fn of(&self, id: UserID) -> &[Appearance] {
+        self.ids.get(&id).map_or(&[], |a| a.as_slice())
+    }
+
+    /// One line on where this entry's been seen before, for the preview and the digest
+    /// None for an ID no earlier digest had
+    pub fn note(&self, entry: &BatchEntry) -> Option<String> {
+        // The last few are what matters on paper
+        const SHOWN: usize = 3;
+
+        let seen = self.of(entry.id);
+        if seen.is_empty() {
+            return None;
+        }
+        let mut dates: Vec<String> = seen
+            .iter()
+            .rev()
+            .take(SHOWN)
+            .map(|a| {
+                format!(
+                    "{} ({}; {})",
+                    local_time(a.timestamp, "%Y-%m-%d"),
+                    a.products(),
+                    a.status()
+                )
+            })
+            .collect();
+        if seen.len() > SHOWN {
+            dates.push(format!("and {} before that", seen.len() - SHOWN));
+        }
+        let mut ret = format!(
+            "In {} earlier digest{}: {}",
+            seen.len(),
+            if seen.len() == 1 { "" } else { "s" },
+            dates.join(", ")
+        );
+
+        // The same product alerting again usually means the autodraft is set up wrong
+        let mut again: Vec<&str> = entry
+            .alerts
+            .keys()
+            .filter(|p| seen.iter().any(|a| a.alerts.iter().any(|(q, _)| q == *p)))
+            .map(|p| p.code())
+            .collect();
+        if !again.is_empty() {
+            again.sort_unstable();
+            ret.push_str(&format!(
+                ".  {} alerted before too - check the autodraft",
+                again.join(", ")
+            ));
+        }
+        Some(ret)
+    }
+}
+
+/// One email matching a search
+#[derive(Debug)]
+pub struct Hit {
+    // The digest it was archived with, or None if it's still pending
+    pub digest: Option<i64>,
+    pub email: Email,
+}
+
+/// Every email whose contents match, pending ones first and then each digest's archive, oldest first
+pub fn search(ctx: &Context, re: &Regex, pending_only: bool) -> Result<Vec<Hit>> {
+    let mut hits: Vec<Hit> = ctx
+        .brain
+        .emails
+        .iter()
+        .filter(|e| re.is_match(&e.contents))
+        .map(|e| Hit {
+            digest: None,
+            email: e.clone(),
+        })
+        .collect();
+    if !pending_only {
+        for record in list(&ctx.hx_path())? {
+            for email in record.emails()? {
+                if re.is_match(&email.contents) {
+                    hits.push(Hit {
+                        digest: Some(record.timestamp),
+                        email,
+                    });
+                }
+            }
+        }
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::TempBrain, util::*};
+    use std::{
+        fs::{create_dir_all, write},
+        str::FromStr,
+    };
+
+    // A brain with one pending alert, and two digests in hx/ - only the second has an archive, and was split
+    fn test_ctx(dir: &TempBrain) -> Context {
+        create_dir_all(dir.join("hx").join("1532000100")).unwrap();
+        write(dir.join("hx").join("digest-1532000000.html"), "old").unwrap();
+        write(dir.join("hx").join("digest-1532000100.html"), "new").unwrap();
+        write(dir.join("hx").join("digest-1532000100.csv"), "new").unwrap();
+        write(
+            dir.join("hx")
+                .join("digest-1532000100-camp-transportation.html"),
+            "split",
+        )
+        .unwrap();
+        for (path, alert) in &[
+            (
+                dir.join("hx").join("1532000100").join("a.txt"),
+                TEST_COOL_STR,
+            ),
+            (dir.join("hx").join("1532000100").join("b.txt"), TEST_DIF_ID),
+            (dir.join("pending.txt"), TEST_DIF_PROD),
+        ] {
+            write(path, &Email::from_str(alert).unwrap().contents).unwrap();
+        }
+        dir.context()
+    }
+
+    #[test]
+    fn test_list() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let records = list(&ctx.hx_path()).unwrap();
+
+        assert_eq!(
+            records.iter().map(|r| r.timestamp).collect::<Vec<i64>>(),
+            vec![1532000000, 1532000100]
+        );
+        assert_eq!(records[0].archive, None);
+        assert_eq!(records[1].files.len(), 3);
+        assert!(records[1].archive.is_some());
+    }
+    #[test]
+    fn test_find_batch() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let batch = find(&ctx.hx_path(), 1532000100).unwrap().batch().unwrap();
+        let missing = find(&ctx.hx_path(), 1);
+
+        assert_eq!(batch.entries.len(), 2);
+        assert!(missing.is_err());
+    }
+    #[test]
+    fn test_history() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let history = History::read(&ctx.hx_path()).unwrap();
+        let mut pending = Batch::new();
+        pending
+            .add_entry(Entry::from_email(&ctx.brain.emails[0]).unwrap())
+            .unwrap();
+        let seen_product = Batch::test();
+
+        assert_eq!(
+            history.of(12345),
+            &[Appearance {
+                timestamp: 1532000100,
+                alerts: vec![(
+                    Product::from_str("COOL_PROD").unwrap(),
+                    vec!["Saturday, July 21, 2018 4:39 PM".into()]
+                )],
+                verified: false,
+            }]
+        );
+        assert!(history.of(99999).is_empty());
+        let date = local_time(1532000100, "%Y-%m-%d");
+        assert_eq!(
+            history.note(&pending.entries[&12345]).unwrap(),
+            format!("In 1 earlier digest: {} (COOL_PROD; unverified)", date)
+        );
+        assert_eq!(
+            history.note(&seen_product.entries[&12345]).unwrap(),
+            format!(
+                "In 1 earlier digest: {} (COOL_PROD; unverified).  COOL_PROD alerted before too - check the autodraft",
+                date
+            )
+        );
+    }
+    #[test]
+    fn test_mark_verified() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let hx = ctx.hx_path();
+        let one = mark_verified(&hx, 1532000100, &[12346]);
+        let again = mark_verified(&hx, 1532000100, &[12346]);
+        let history = History::read(&hx).unwrap();
+        let rest = mark_verified(&hx, 1532000100, &[]);
+        let stranger = mark_verified(&hx, 1532000100, &[99999]);
+        let unarchived = mark_verified(&hx, 1532000000, &[]);
+        let all = verified(&hx, 1532000100);
+
+        assert_eq!(one.unwrap(), vec![12346]);
+        assert!(again.unwrap().is_empty());
+        assert!(history.of(12346)[0].verified);
+        assert!(!history.of(12345)[0].verified);
+        assert_eq!(rest.unwrap(), vec![12345]);
+        assert!(stranger.is_err());
+        assert!(unarchived.is_err());
+        assert_eq!(
+            all.unwrap().into_iter().collect::<Vec<UserID>>(),
+            vec![12345, 12346]
+        );
+    }
+    #[test]
+    fn test_search() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let all = search(&ctx, &Regex::new("12345").unwrap(), false).unwrap();
+        let pending = search(&ctx, &Regex::new("12345").unwrap(), true).unwrap();
+        let none = search(&ctx, &Regex::new("99999").unwrap(), false).unwrap();
+
+        assert_eq!(
+            all.iter().map(|h| h.digest).collect::<Vec<Option<i64>>>(),
+            vec![None, Some(1532000100)]
+        );
+        assert_eq!(pending.len(), 1);
+        assert!(none.is_empty());
+    }
+}
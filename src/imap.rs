@@ -0,0 +1,644 @@
+// imap.rs fetches alerts straight from a mailbox into the brain
+// The imap crate's stable release is tied to native-tls, so OpenSSL wherever this runs, and its rustls
+// support is still alpha; async-imap needs an async runtime.  We already carry rustls for lettre, and the
+// job is a handful of commands, so this speaks just enough of RFC 3501 for it: LOGIN, SELECT,
+// UID SEARCH/FETCH/STORE and UID MOVE (or COPY, then UID EXPUNGE where UIDPLUS is supported)
+use crate::{
+    config::{Imap, Security},
+    email::Email,
+    errors::*,
+    parser::Registry,
+    util::write_atomic,
+};
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
+use std::{
+    convert::TryFrom,
+    env::var,
+    fs::read_dir,
+    io::{prelude::*, BufReader},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+// Read when [imap] has no password, so it can stay out of Bot.toml
+static PASSWORD_VAR: &str = "AR_BOT_IMAP_PASSWORD";
+
+lazy_static! {
+    // Fetched messages are saved as imap-UIDVALIDITY-UID.txt so we can find them on the server again
+    static ref FETCHED_RE: Regex = Regex::new(r"^imap-(?P<validity>\d+)-(?P<uid>\d+)\.txt$").unwrap();
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+// One untagged response line, with any literals it carried
+#[derive(Debug, Default)]
+struct Untagged {
+    text: String,
+    literals: Vec<Vec<u8>>,
+}
+
+struct Session {
+    stream: BufReader<Box<dyn ReadWrite>>,
+    tag: u32,
+}
+
+impl Session {
+    fn connect(imap: &Imap) -> Result<Self> {
+        let port = imap.port.unwrap_or(match imap.security {
+            Security::Tls => 993,
+            _ => 143,
+        });
+        let tcp = TcpStream::connect((imap.host.as_str(), port)).chain_err(|| {
+            ErrorKind::Delivery(format!("Could not connect to {}:{}", imap.host, port))
+        })?;
+        let stream: Box<dyn ReadWrite> = match imap.security {
+            Security::None => Box::new(tcp),
+            Security::Tls => tls(&imap.host, tcp)?,
+            Security::Starttls => {
+                let mut tcp = tcp;
+                read_line_unbuffered(&mut tcp)?;
+                tcp.write_all(b"S STARTTLS\r\n")
+                    .chain_err(|| ErrorKind::Delivery("Could not send STARTTLS".into()))?;
+                loop {
+                    let line = read_line_unbuffered(&mut tcp)?;
+                    if line.starts_with("S OK") {
+                        break;
+                    } else if line.starts_with("S ") {
+                        return Err(ErrorKind::Delivery(format!(
+                            "Server refused STARTTLS: {}",
+                            line.trim()
+                        ))
+                        .into());
+                    }
+                }
+                tls(&imap.host, tcp)?
+            }
+        };
+        let mut session = Session {
+            stream: BufReader::new(stream),
+            tag: 0,
+        };
+        // STARTTLS already ate the greeting
+        if imap.security != Security::Starttls {
+            let greeting = session.read_line()?;
+            if !greeting.starts_with("* OK") && !greeting.starts_with("* PREAUTH") {
+                return Err(ErrorKind::Delivery(format!(
+                    "Unexpected IMAP greeting: {}",
+                    greeting.trim()
+                ))
+                .into());
+            }
+        }
+        Ok(session)
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self
+            .stream
+            .read_line(&mut line)
+            .chain_err(|| ErrorKind::Delivery("Could not read from IMAP server".into()))?
+            == 0
+        {
+            return Err(ErrorKind::Delivery("IMAP server hung up".into()).into());
+        }
+        Ok(line)
+    }
+
+    // Sends a command and collects untagged responses until its tagged OK
+    fn command(&mut self, cmd: &str) -> Result<Vec<Untagged>> {
+        lazy_static! {
+            static ref LITERAL_RE: Regex = Regex::new(r"\{(?P<len>\d+)\}\r\n$").unwrap();
+        }
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        trace!("IMAP > {} {}", tag, cmd);
+        self.stream
+            .get_mut()
+            .write_all(format!("{} {}\r\n", tag, cmd).as_bytes())
+            .chain_err(|| ErrorKind::Delivery("Could not write to IMAP server".into()))?;
+
+        let mut responses = Vec::new();
+        let mut current: Option<Untagged> = None;
+        loop {
+            let line = self.read_line()?;
+            trace!("IMAP < {}", line.trim_end());
+            let mut untagged = match current.take() {
+                Some(u) => u,
+                None if line.starts_with(&format!("{} ", tag)) => {
+                    let status = &line[tag.len() + 1..];
+                    if status.starts_with("OK") {
+                        return Ok(responses);
+                    }
+                    return Err(ErrorKind::Delivery(format!(
+                        "IMAP {} failed: {}",
+                        cmd_name(cmd),
+                        status.trim()
+                    ))
+                    .into());
+                }
+                None => Untagged::default(),
+            };
+            untagged.text.push_str(line.trim_end_matches("\r\n"));
+            if let Some(caps) = LITERAL_RE.captures(&line) {
+                let len: usize = caps["len"]
+                    .parse()
+                    .chain_err(|| ErrorKind::Delivery("Bad IMAP literal length".into()))?;
+                let mut literal = vec![0; len];
+                self.stream
+                    .read_exact(&mut literal)
+                    .chain_err(|| ErrorKind::Delivery("Could not read IMAP literal".into()))?;
+                untagged.literals.push(literal);
+                current = Some(untagged);
+            } else {
+                responses.push(untagged);
+            }
+        }
+    }
+
+    fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        self.command(&format!("LOGIN {} {}", quote(username), quote(password)))
+            .map(|_| ())
+    }
+
+    // Returns the folder's UIDVALIDITY
+    fn select(&mut self, folder: &str) -> Result<u32> {
+        lazy_static! {
+            static ref VALIDITY_RE: Regex = Regex::new(r"\[UIDVALIDITY (?P<v>\d+)\]").unwrap();
+        }
+        for r in self.command(&format!("SELECT {}", quote(folder)))? {
+            if let Some(caps) = VALIDITY_RE.captures(&r.text) {
+                return caps["v"]
+                    .parse()
+                    .chain_err(|| ErrorKind::Delivery("Bad UIDVALIDITY".into()));
+            }
+        }
+        Err(ErrorKind::Delivery(format!("Server didn't give a UIDVALIDITY for {}", folder)).into())
+    }
+
+    fn uid_search(&mut self, criteria: &str) -> Result<Vec<u32>> {
+        let mut uids = Vec::new();
+        for r in self.command(&format!("UID SEARCH {}", criteria))? {
+            if let Some(rest) = r.text.strip_prefix("* SEARCH") {
+                for n in rest.split_whitespace() {
+                    uids.push(
+                        n.parse().chain_err(|| {
+                            ErrorKind::Delivery("Bad UID in SEARCH response".into())
+                        })?,
+                    );
+                }
+            }
+        }
+        Ok(uids)
+    }
+
+    // Fetches the whole message without marking it \Seen
+    fn uid_fetch(&mut self, uid: u32) -> Result<Vec<u8>> {
+        self.command(&format!("UID FETCH {} BODY.PEEK[]", uid))?
+            .into_iter()
+            .find_map(|mut r| r.literals.pop())
+            .chain_err(|| ErrorKind::Delivery(format!("Server sent no body for UID {}", uid)))
+    }
+
+    fn has_capability(&mut self, cap: &str) -> Result<bool> {
+        Ok(self.command("CAPABILITY")?.iter().any(|r| {
+            r.text
+                .split_whitespace()
+                .any(|c| c.eq_ignore_ascii_case(cap))
+        }))
+    }
+
+    fn logout(&mut self) {
+        // The server is allowed to just hang up, so don't care how this goes
+        let _ = self.command("LOGOUT");
+    }
+}
+
+fn tls(host: &str, tcp: TcpStream) -> Result<Box<dyn ReadWrite>> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .chain_err(|| ErrorKind::Delivery(format!("{} is not a valid TLS server name", host)))?;
+    let conn = rustls::ClientConnection::new(Arc::new(config), server_name)
+        .chain_err(|| ErrorKind::Delivery("Could not start TLS".into()))?;
+    Ok(Box::new(rustls::StreamOwned::new(conn, tcp)))
+}
+
+// Before STARTTLS we can't buffer, or we'd swallow the start of the handshake
+fn read_line_unbuffered(tcp: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if tcp
+            .read(&mut byte)
+            .chain_err(|| ErrorKind::Delivery("Could not read from IMAP server".into()))?
+            == 0
+        {
+            return Err(ErrorKind::Delivery("IMAP server hung up".into()).into());
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Keeps passwords out of error messages
+fn cmd_name(cmd: &str) -> &str {
+    cmd.split_whitespace().next().unwrap_or(cmd)
+}
+
+fn password(imap: &Imap) -> Result<String> {
+    match &imap.password {
+        Some(p) => Ok(p.clone()),
+        None => var(PASSWORD_VAR).chain_err(|| {
+            ErrorKind::Config(format!(
+                "No password under [imap], and {} is not set",
+                PASSWORD_VAR
+            ))
+        }),
+    }
+}
+
+// Rewrites an RFC 5322 message in the shape the alert rules expect, as if it had been saved from Outlook
+fn to_brain_format(raw: &[u8]) -> Result<String> {
+    let raw = String::from_utf8_lossy(raw).replace("\r\n", "\n");
+    let (head, body) = match raw.find("\n\n") {
+        Some(i) => (&raw[..i], &raw[i + 2..]),
+        None => (raw.as_str(), ""),
+    };
+
+    // Unfold continuation lines, then pick out the headers we care about
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(i) = line.find(':') {
+            headers.push((line[..i].to_lowercase(), line[i + 1..].trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("")
+    };
+
+    let sent = DateTime::parse_from_rfc2822(header("date"))
+        .chain_err(|| {
+            ErrorKind::Parse(format!("Could not read message date {:?}", header("date")))
+        })?
+        .with_timezone(&Local)
+        .format("%A, %B %-d, %Y %-I:%M %p");
+    let body = if header("content-transfer-encoding").eq_ignore_ascii_case("quoted-printable") {
+        decode_quoted_printable(body)
+    } else {
+        body.to_string()
+    };
+
+    Ok(format!(
+        "From:\t{}\r\nSent:\t{}\r\nTo:\t{}\r\nSubject:\t{}\r\n\r\n{}",
+        header("from"),
+        sent,
+        header("to"),
+        header("subject"),
+        body.replace('\n', "\r\n")
+    ))
+}
+
+fn decode_quoted_printable(s: &str) -> String {
+    let s = s.replace("=\n", "");
+    let bytes = s.as_bytes();
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'=' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// What `fetch` did with each unseen message
+#[derive(Debug, Default, PartialEq)]
+pub struct FetchSummary {
+    pub fetched: usize,
+    // Unseen messages that aren't alerts - left unseen on the server
+    pub skipped: usize,
+    // Already in the brain from an earlier fetch
+    pub existing: usize,
+}
+
+/// Downloads unseen alerts from `[imap]` into the brain directory and marks them \Seen
+pub fn fetch(imap: &Imap, brain_path: &Path) -> Result<FetchSummary> {
+    let mut session = Session::connect(imap)?;
+    session.login(&imap.username, &password(imap)?)?;
+    let validity = session.select(&imap.folder)?;
+    let mut summary = FetchSummary::default();
+    let registry = Registry::default();
+
+    for uid in session.uid_search("UNSEEN")? {
+        let filename = format!("imap-{}-{}.txt", validity, uid);
+        let mut path = brain_path.to_path_buf();
+        path.push(&filename);
+        if path.exists() {
+            summary.existing += 1;
+            continue;
+        }
+
+        let contents = match to_brain_format(&session.uid_fetch(uid)?) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("SKIP: UID {} - {}", uid, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        if !registry.matches(&Email::new(&filename, &contents)?) {
+            debug!("SKIP: UID {} doesn't look like an alert", uid);
+            summary.skipped += 1;
+            continue;
+        }
+
+        info!("FETCH: UID {} -> {:?}", uid, path);
+        write_atomic(&path, contents.as_bytes()).chain_err(|| "Could not write fetched email")?;
+        session.command(&format!("UID STORE {} +FLAGS.SILENT (\\Seen)", uid))?;
+        summary.fetched += 1;
+    }
+
+    session.logout();
+    Ok(summary)
+}
+
+/// Moves every message we fetched that's in dir over to processed_folder
+/// dir is the brain, or the archive of the digest that was just cut from it
+/// Returns how many were moved, or None if processed_folder isn't set
+pub fn move_processed(imap: &Imap, dir: &Path) -> Result<Option<usize>> {
+    let target = match &imap.processed_folder {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let mut fetched: Vec<(u32, u32)> = Vec::new();
+    for f in read_dir(dir).chain_err(|| format!("Could not read {:?}", dir))? {
+        let name = f
+            .chain_err(|| format!("Could not read entry in {:?}", dir))?
+            .file_name();
+        if let Some(caps) = name.to_str().and_then(|n| FETCHED_RE.captures(n)) {
+            fetched.push((
+                caps["validity"]
+                    .parse()
+                    .chain_err(|| "Bad UIDVALIDITY in filename")?,
+                caps["uid"].parse().chain_err(|| "Bad UID in filename")?,
+            ));
+        }
+    }
+    if fetched.is_empty() {
+        return Ok(Some(0));
+    }
+
+    let mut session = Session::connect(imap)?;
+    session.login(&imap.username, &password(imap)?)?;
+    let validity = session.select(&imap.folder)?;
+    let uids: Vec<String> = fetched
+        .iter()
+        .filter(|(v, _)| *v == validity)
+        .map(|(_, uid)| uid.to_string())
+        .collect();
+    if uids.len() < fetched.len() {
+        warn!(
+            "{} fetched emails are from an older UIDVALIDITY of {} and can't be moved",
+            fetched.len() - uids.len(),
+            imap.folder
+        );
+    }
+    if !uids.is_empty() {
+        let set = uids.join(",");
+        if session.has_capability("MOVE")? {
+            session.command(&format!("UID MOVE {} {}", set, quote(target)))?;
+        } else {
+            session.command(&format!("UID COPY {} {}", set, quote(target)))?;
+            session.command(&format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", set))?;
+            // A bare EXPUNGE would take everything anyone flagged \Deleted in a shared folder with it
+            if session.has_capability("UIDPLUS")? {
+                session.command(&format!("UID EXPUNGE {}", set))?;
+            } else {
+                warn!(
+                    "{} has neither MOVE nor UIDPLUS - {} copied emails are flagged \\Deleted in {} but not expunged",
+                    imap.host,
+                    uids.len(),
+                    imap.folder
+                );
+            }
+        }
+    }
+    session.logout();
+    Ok(Some(uids.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch::Entry, test_util::TempBrain};
+    use std::{
+        collections::BTreeSet,
+        fs::File,
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver},
+        thread,
+    };
+
+    static ALERT: &str = "From: iMIS <iMIS@jccgb.org>\r\nTo: Some People <people@jccgb.org>\r\nSubject: Invoice Charge Change for Grossman Autodraft\r\nDate: Wed, 18 Jul 2018 13:00:00 +0000\r\nContent-Type: text/plain\r\n\r\nThe Grossman Invoice For iMIS ID 164408 For the Product CG_TRANS Has Changed\r\nYou need to verify the Autodraft is now correct\r\n";
+    static NOT_ALERT: &str = "From: someone@example.org\r\nSubject: lunch?\r\nDate: Wed, 18 Jul 2018 13:00:00 +0000\r\n\r\nTacos?\r\n";
+
+    fn test_imap(port: u16) -> Imap {
+        Imap {
+            host: "127.0.0.1".into(),
+            port: Some(port),
+            security: Security::None,
+            username: "ar-bot".into(),
+            password: Some("hunter2".into()),
+            folder: "INBOX".into(),
+            processed_folder: Some("Processed".into()),
+        }
+    }
+
+    fn uid_set(set: &str) -> Vec<u32> {
+        set.split(',').map(|u| u.parse().unwrap()).collect()
+    }
+
+    // A stand-in IMAP server holding UID 1 (an alert) and UID 2 (not one), both unseen
+    // UID 3 is something another client already flagged \Deleted and hasn't expunged yet
+    // Every command it gets is sent back down the channel, then the UIDs left in the mailbox once it's logged out of
+    fn imap_stub(capabilities: &'static str) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut w = stream;
+            w.write_all(b"* OK stub ready\r\n").unwrap();
+            let mut mailbox: BTreeSet<u32> = (1..=3).collect();
+            let mut deleted: BTreeSet<u32> = BTreeSet::new();
+            deleted.insert(3);
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let mut parts = line.trim_end().splitn(2, ' ');
+                let tag = parts.next().unwrap().to_string();
+                let cmd = parts.next().unwrap_or("").to_string();
+                tx.send(cmd.clone()).unwrap();
+                let upper = cmd.to_uppercase();
+                if upper.starts_with("SELECT") {
+                    w.write_all(b"* 2 EXISTS\r\n* OK [UIDVALIDITY 77] ok\r\n")
+                        .unwrap();
+                } else if upper.starts_with("UID SEARCH") {
+                    w.write_all(b"* SEARCH 1 2\r\n").unwrap();
+                } else if upper.starts_with("UID FETCH") {
+                    let (seq, body) = if upper.starts_with("UID FETCH 1 ") {
+                        (1, ALERT)
+                    } else {
+                        (2, NOT_ALERT)
+                    };
+                    w.write_all(
+                        format!(
+                            "* {} FETCH (UID {} BODY[] {{{}}}\r\n{})\r\n",
+                            seq,
+                            seq,
+                            body.len(),
+                            body
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+                } else if upper.starts_with("CAPABILITY") {
+                    w.write_all(format!("* CAPABILITY {}\r\n", capabilities).as_bytes())
+                        .unwrap();
+                } else if let Some(set) = upper.strip_prefix("UID STORE ") {
+                    if upper.contains("\\DELETED") {
+                        deleted.extend(uid_set(set.split(' ').next().unwrap()));
+                    }
+                } else if let Some(set) = upper.strip_prefix("UID EXPUNGE ") {
+                    for uid in uid_set(set) {
+                        if deleted.contains(&uid) {
+                            mailbox.remove(&uid);
+                        }
+                    }
+                } else if upper == "EXPUNGE" {
+                    mailbox = mailbox.difference(&deleted).copied().collect();
+                } else if upper.starts_with("LOGOUT") {
+                    let left: Vec<String> = mailbox.iter().map(|u| u.to_string()).collect();
+                    tx.send(format!("MAILBOX {}", left.join(","))).unwrap();
+                    w.write_all(b"* BYE\r\n").unwrap();
+                    w.write_all(format!("{} OK bye\r\n", tag).as_bytes())
+                        .unwrap();
+                    break;
+                }
+                w.write_all(format!("{} OK done\r\n", tag).as_bytes())
+                    .unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn test_fetch_from_stub() {
+        let (port, rx) = imap_stub("IMAP4rev1");
+        let brain = TempBrain::new();
+        let summary = fetch(&test_imap(port), &brain).unwrap();
+        let saved =
+            crate::util::file_contents_from_str_path(brain.join("imap-77-1.txt").to_str().unwrap());
+        let others = read_dir(&brain).unwrap().count();
+
+        assert_eq!(
+            summary,
+            FetchSummary {
+                fetched: 1,
+                skipped: 1,
+                existing: 0
+            }
+        );
+        assert_eq!(others, 1);
+        let saved = saved.unwrap();
+        let entry = Entry::from_email(&Email::new("imap-77-1.txt", &saved).unwrap()).unwrap();
+        assert_eq!(entry.id, 164408);
+        let cmds: Vec<String> = rx.iter().collect();
+        assert!(cmds.contains(&"UID STORE 1 +FLAGS.SILENT (\\Seen)".to_string()));
+        assert!(!cmds.iter().any(|c| c.starts_with("UID STORE 2")));
+    }
+    #[test]
+    fn test_move_processed_with_move() {
+        let (port, rx) = imap_stub("IMAP4rev1 MOVE");
+        let brain = TempBrain::new();
+        File::create(brain.join("imap-77-1.txt")).unwrap();
+        File::create(brain.join("imap-76-9.txt")).unwrap();
+        File::create(brain.join("saved-by-hand.txt")).unwrap();
+        let moved = move_processed(&test_imap(port), &brain).unwrap();
+
+        assert_eq!(moved, Some(1));
+        let cmds: Vec<String> = rx.iter().collect();
+        assert!(cmds.contains(&"UID MOVE 1 \"Processed\"".to_string()));
+    }
+    #[test]
+    fn test_move_processed_with_uidplus() {
+        let (port, rx) = imap_stub("IMAP4rev1 UIDPLUS");
+        let brain = TempBrain::new();
+        File::create(brain.join("imap-77-1.txt")).unwrap();
+        move_processed(&test_imap(port), &brain).unwrap();
+
+        let cmds: Vec<String> = rx.iter().collect();
+        assert!(cmds.contains(&"UID COPY 1 \"Processed\"".to_string()));
+        assert!(cmds.contains(&"UID EXPUNGE 1".to_string()));
+        assert!(!cmds.contains(&"EXPUNGE".to_string()));
+        // Only what we copied is gone - the other client's \Deleted message is still there
+        assert_eq!(cmds.last().unwrap(), "MAILBOX 2,3");
+    }
+    #[test]
+    fn test_move_processed_without_move() {
+        let (port, rx) = imap_stub("IMAP4rev1");
+        let brain = TempBrain::new();
+        File::create(brain.join("imap-77-1.txt")).unwrap();
+        move_processed(&test_imap(port), &brain).unwrap();
+
+        let cmds: Vec<String> = rx.iter().collect();
+        assert!(cmds.contains(&"UID COPY 1 \"Processed\"".to_string()));
+        assert!(cmds.contains(&"UID STORE 1 +FLAGS.SILENT (\\Deleted)".to_string()));
+        // Nothing is expunged, so nothing of anyone else's goes with it
+        assert!(!cmds
+            .iter()
+            .any(|c| c.ends_with("EXPUNGE") || c.starts_with("UID EXPUNGE")));
+        assert_eq!(cmds.last().unwrap(), "MAILBOX 1,2,3");
+    }
+    #[test]
+    fn test_decode_quoted_printable() {
+        assert_eq!(
+            decode_quoted_printable("Has Chan=\nged =3D done"),
+            "Has Changed = done"
+        )
+    }
+}
@@ -0,0 +1,508 @@
+// journal.rs cuts a digest as one transaction, so a crash or a full disk never leaves half of one
+//
+// Everything the digest writes is staged in hx/.staging-TIMESTAMP/ and synced to disk first.
+// Writing journal.json there is the commit: it lists the files to move into hx/ and the inputs to
+// archive into hx/TIMESTAMP/, so recover() can always finish the job from it.
+// A staging dir without a journal never committed, and is thrown away - the brain was never touched.
+use crate::{
+    brain::Context,
+    errors::*,
+    export::Export,
+    imap,
+    page::{render_digest, render_split, DigestOutput, Renderer},
+    review::Review,
+    smtp,
+    util::{sync_dir, write_atomic},
+};
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{
+        create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, rename,
+    },
+    path::{Path, PathBuf},
+};
+
+static JOURNAL_FILE: &str = "journal.json";
+
+// What's committed, by file name
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Journal {
+    timestamp: i64,
+    // From the staging dir into hx/
+    outputs: Vec<String>,
+    // From the brain into hx/TIMESTAMP/
+    inputs: Vec<String>,
+}
+
+fn staging_path(hx_path: &Path, timestamp: i64) -> PathBuf {
+    hx_path.join(format!(".staging-{}", timestamp))
+}
+
+fn archive_path(hx_path: &Path, timestamp: i64) -> PathBuf {
+    hx_path.join(timestamp.to_string())
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|f| f.to_str())
+        .map(|f| f.to_string())
+        .chain_err(|| format!("No usable file name in {:?}", path))
+}
+
+/// One digest being cut
+/// Dropped without commit() - on an error, say - everything staged is thrown away
+#[derive(Debug)]
+pub struct Transaction {
+    timestamp: i64,
+    brain_path: PathBuf,
+    hx_path: PathBuf,
+    staging: PathBuf,
+    outputs: Vec<String>,
+    committed: bool,
+}
+
+impl Transaction {
+    /// Starts staging the digest cut at timestamp
+    pub fn begin(ctx: &Context, timestamp: i64) -> Result<Self> {
+        let hx_path = ctx.hx_path();
+        if !hx_path.exists() {
+            warn!("No history found!  Creating...");
+            create_dir(&hx_path)
+                .chain_err(|| ErrorKind::Archive("Could not create history dir".into()))?;
+        }
+        let staging = staging_path(&hx_path, timestamp);
+        if staging.exists() || archive_path(&hx_path, timestamp).exists() {
+            return Err(ErrorKind::Archive(format!(
+                "A digest was already cut at {} - wait a second and try again",
+                timestamp
+            ))
+            .into());
+        }
+        create_dir(&staging).chain_err(|| {
+            ErrorKind::Archive(format!("Could not create staging dir {:?}", staging))
+        })?;
+        debug!("BEGIN: {:?}", staging);
+        Ok(Transaction {
+            timestamp,
+            brain_path: ctx.config.directory.path.clone(),
+            hx_path,
+            staging,
+            outputs: Vec::new(),
+            committed: false,
+        })
+    }
+
+    /// Writes one file to land in hx/ on commit - path is where it'll end up
+    pub fn stage(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        let name = file_name(path)?;
+        write_atomic(&self.staging.join(&name), contents)
+            .chain_err(|| ErrorKind::Archive(format!("Could not stage {}", name)))?;
+        self.outputs.push(name);
+        Ok(())
+    }
+
+    /// Commits the digest, archiving inputs from the brain along with it
+    /// Once the journal is on disk the digest is cut - if moving the files fails, recover() finishes it
+    /// Returns the archive dir, hx/TIMESTAMP/
+    pub fn commit(mut self, inputs: &[PathBuf]) -> Result<PathBuf> {
+        let journal = Journal {
+            timestamp: self.timestamp,
+            outputs: self.outputs.clone(),
+            inputs: inputs
+                .iter()
+                .map(|p| file_name(p))
+                .collect::<Result<Vec<String>>>()?,
+        };
+        let contents =
+            serde_json::to_string_pretty(&journal).chain_err(|| "Could not write journal")?;
+        sync_dir(&self.staging)
+            .and_then(|_| write_atomic(&self.staging.join(JOURNAL_FILE), contents.as_bytes()))
+            .and_then(|_| sync_dir(&self.staging))
+            .chain_err(|| ErrorKind::Archive("Could not write journal".into()))?;
+        self.committed = true;
+        debug!("COMMIT: {:?}", self.staging);
+        apply(&journal, &self.brain_path, &self.hx_path)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        info!(
+            "Rolling back digest {} - nothing was archived",
+            self.timestamp
+        );
+        if let Err(e) = remove_dir_all(&self.staging) {
+            warn!("Could not remove staging dir {:?}: {}", self.staging, e);
+        }
+    }
+}
+
+// Moves everything a committed journal lists into place, then clears out its staging dir
+// Anything already moved is skipped, so it's safe to run again after a crash part way through
+fn apply(journal: &Journal, brain_path: &Path, hx_path: &Path) -> Result<PathBuf> {
+    let staging = staging_path(hx_path, journal.timestamp);
+    let archive = archive_path(hx_path, journal.timestamp);
+    create_dir_all(&archive)
+        .chain_err(|| ErrorKind::Archive("Could not create archive dir".into()))?;
+
+    let inputs = journal
+        .inputs
+        .iter()
+        .map(|n| (brain_path.join(n), archive.join(n)));
+    let outputs = journal
+        .outputs
+        .iter()
+        .map(|n| (staging.join(n), hx_path.join(n)));
+    for (from, to) in inputs.chain(outputs) {
+        if from.exists() {
+            debug!("ARCHIVE: {:?} -> {:?}", from, to);
+            rename(&from, &to)
+                .chain_err(|| ErrorKind::Archive(format!("Could not archive {:?}", from)))?;
+        } else if !to.exists() {
+            warn!("{:?} went missing before it could be archived", from);
+        }
+    }
+    for dir in &[&archive, hx_path, brain_path] {
+        sync_dir(dir).chain_err(|| ErrorKind::Archive(format!("Could not sync {:?}", dir)))?;
+    }
+
+    remove_file(staging.join(JOURNAL_FILE))
+        .and_then(|_| remove_dir_all(&staging))
+        .chain_err(|| ErrorKind::Archive(format!("Could not remove staging dir {:?}", staging)))?;
+    Ok(archive)
+}
+
+/// How the finished digest leaves the building, if at all
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Keep,
+    Send,
+    // Write the email to hx/ instead of sending it
+    DryRun,
+}
+
+/// Everything one cut_digest() left behind
+#[derive(Debug)]
+pub struct Cut {
+    // One for the whole batch, or one per product group and the rest when it was split
+    pub outputs: Vec<DigestOutput>,
+    // hx/TIMESTAMP/, holding every email the digest used up
+    pub archive: PathBuf,
+    // Who each digest was sent to, in the order of outputs - empty unless it was sent
+    pub sent: Vec<Vec<String>>,
+    // The .eml written for each digest on a dry run
+    pub emls: Vec<PathBuf>,
+    // How many fetched emails went to the IMAP processed_folder, if there's one
+    pub moved: Option<usize>,
+}
+
+/// Cuts a digest of everything the review leaves in, all at once or not at all:
+/// 1. Writes digest-TIMESTAMP.html (and any exports) under hx/ - split, one per product group as well
+/// 2. Moves every email it used into hx/TIMESTAMP/
+///
+/// Everything is rendered and staged first, and sent before the commit - if any of it fails, nothing is
+/// archived and the brain is as it was.  Anything excluded or unmatched stays in the brain for next time.
+/// Once it's archived, the fetched emails it used are moved to the IMAP processed_folder, if there is one.
+pub fn cut_digest(
+    ctx: &Context,
+    review: &Review,
+    renderer: Renderer,
+    exports: &[Export],
+    delivery: Delivery,
+    split: bool,
+) -> Result<Cut> {
+    let batch = review.batch()?;
+    let outputs = if split {
+        render_split(ctx, &batch, renderer, exports)?
+    } else {
+        vec![render_digest(ctx, &batch, renderer, exports)?]
+    };
+    let mut txn = Transaction::begin(ctx, outputs[0].timestamp)?;
+    for output in &outputs {
+        txn.stage(&output.path, output.digest.as_bytes())?;
+        for (_, path, contents) in &output.exports {
+            txn.stage(path, contents)?;
+        }
+    }
+
+    let mut sent = Vec::new();
+    let mut emls = Vec::new();
+    if delivery != Delivery::Keep {
+        let smtp_config = ctx
+            .config
+            .smtp
+            .as_ref()
+            .chain_err(|| "Can't send the digest without an [smtp] section in the config")?;
+        for output in &outputs {
+            if delivery == Delivery::DryRun {
+                let (eml_path, contents) = smtp::dry_run(smtp_config, output)?;
+                txn.stage(&eml_path, &contents)?;
+                emls.push(eml_path);
+            } else {
+                // Can't be taken back - if anything fails after this, the next digest sends these again
+                if let Err(e) = smtp::send(smtp_config, output) {
+                    if !sent.is_empty() {
+                        warn!("{} digests were sent before this one failed - they'll go again with the next", sent.len());
+                    }
+                    return Err(e);
+                }
+                sent.push(smtp::recipients(smtp_config, output).to_vec());
+            }
+        }
+    }
+
+    let archive = txn.commit(&review.inputs())?;
+    for output in &outputs {
+        info!("Digest written to {:?}", output.path);
+    }
+    info!("Emails archived to {:?}", archive);
+    if ctx.config.directory.compressed {
+        warn!(
+            "Compression isn't supported yet - {:?} is left uncompressed",
+            archive
+        );
+    }
+
+    // The archive holds exactly the fetched emails this digest used
+    let moved = match &ctx.config.imap {
+        Some(imap_config) => imap::move_processed(imap_config, &archive)
+            .chain_err(|| "The digest was cut, but its emails are still in the IMAP folder")?,
+        None => None,
+    };
+    if let Some(moved) = moved {
+        info!("Moved {} fetched emails to their processed folder", moved);
+    }
+    Ok(Cut {
+        outputs,
+        archive,
+        sent,
+        emls,
+        moved,
+    })
+}
+
+/// What recover() did with one digest a run left behind
+#[derive(Debug, PartialEq)]
+pub enum Recovery {
+    // It had committed - its files and archive are in place now
+    Finished(i64),
+    // It hadn't - its inputs are still pending
+    RolledBack(i64),
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recovery::Finished(ts) => {
+                write!(
+                    f,
+                    "Finished digest {} - it had committed, and is archived in hx/{}/ now",
+                    ts, ts
+                )
+            }
+            Recovery::RolledBack(ts) => {
+                write!(
+                    f,
+                    "Rolled back digest {} - it never committed, so its inputs are still pending",
+                    ts
+                )
+            }
+        }
+    }
+}
+
+/// Finishes every committed digest in hx/ that a run didn't get to the end of, and throws away the rest
+/// Only safe with the brain locked
+pub fn recover(brain_path: &Path, hx_path: &Path) -> Result<Vec<Recovery>> {
+    lazy_static! {
+        static ref STAGING_RE: Regex = Regex::new(r"^\.staging-(?P<timestamp>\d+)$").unwrap();
+    }
+
+    let mut ret = Vec::new();
+    if !hx_path.is_dir() {
+        return Ok(ret);
+    }
+    let mut staged = Vec::new();
+    for f in read_dir(hx_path).chain_err(|| format!("Could not read {:?}", hx_path))? {
+        let path = f
+            .chain_err(|| format!("Could not read entry in {:?}", hx_path))?
+            .path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+        if let Some(caps) = STAGING_RE.captures(&name) {
+            staged.push((
+                caps["timestamp"]
+                    .parse::<i64>()
+                    .chain_err(|| "Bad staging dir name")?,
+                path,
+            ));
+        }
+    }
+    staged.sort();
+
+    for (timestamp, staging) in staged {
+        let journal_path = staging.join(JOURNAL_FILE);
+        if journal_path.exists() {
+            let journal: Journal = read_to_string(&journal_path)
+                .chain_err(|| format!("Could not read {:?}", journal_path))
+                .and_then(|s| {
+                    serde_json::from_str(&s)
+                        .chain_err(|| ErrorKind::Parse(format!("Bad journal {:?}", journal_path)))
+                })?;
+            apply(&journal, brain_path, hx_path)?;
+            ret.push(Recovery::Finished(timestamp));
+        } else {
+            remove_dir_all(&staging).chain_err(|| {
+                ErrorKind::Archive(format!("Could not remove staging dir {:?}", staging))
+            })?;
+            ret.push(Recovery::RolledBack(timestamp));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{email::Email, test_util::TempBrain, util::TEST_COOL_STR};
+    use std::{
+        fs::{read_to_string, write},
+        str::FromStr,
+    };
+
+    fn test_ctx(brain: &TempBrain) -> Context {
+        let ctx = brain.context();
+        write(brain.join("a.txt"), "alert").unwrap();
+        ctx
+    }
+
+    #[test]
+    fn test_commit() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let hx = ctx.hx_path();
+        let mut txn = Transaction::begin(&ctx, 1_532_000_000).unwrap();
+        txn.stage(&hx.join("digest-1532000000.txt"), b"DIGEST")
+            .unwrap();
+        let archive = txn.commit(&ctx.input_paths().unwrap()).unwrap();
+
+        assert_eq!(
+            read_to_string(hx.join("digest-1532000000.txt")).unwrap(),
+            "DIGEST"
+        );
+        assert!(archive.join("a.txt").exists());
+        assert!(ctx.input_paths().unwrap().is_empty());
+        assert!(!staging_path(&hx, 1_532_000_000).exists());
+    }
+    #[test]
+    fn test_cut_digest() {
+        let brain = TempBrain::new();
+        let mut ctx = test_ctx(&brain);
+        write(
+            brain.join("b.txt"),
+            &Email::from_str(TEST_COOL_STR).unwrap().contents,
+        )
+        .unwrap();
+        ctx.read_fs().unwrap();
+
+        // Nowhere to send it, so nothing is cut
+        let unsendable = cut_digest(
+            &ctx,
+            &Review::new(&ctx),
+            Renderer::Text,
+            &[],
+            Delivery::Send,
+            false,
+        );
+        let pending = ctx.input_paths().unwrap().len();
+        let cut = cut_digest(
+            &ctx,
+            &Review::new(&ctx),
+            Renderer::Text,
+            &[Export::Csv],
+            Delivery::Keep,
+            false,
+        )
+        .unwrap();
+        let left = ctx.input_paths().unwrap();
+        let written = cut.outputs[0].path.exists() && cut.outputs[0].exports[0].1.exists();
+        let archived = cut.archive.join("b.txt").exists();
+
+        assert!(unsendable.is_err());
+        assert_eq!(pending, 2);
+        assert_eq!(cut.outputs.len(), 1);
+        assert!(written);
+        assert!(archived);
+        // a.txt isn't an alert, so it's still pending
+        assert_eq!(left, vec![brain.join("a.txt")]);
+        assert!(cut.sent.is_empty() && cut.emls.is_empty());
+        assert_eq!(cut.moved, None);
+    }
+    #[test]
+    fn test_rollback() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let hx = ctx.hx_path();
+        {
+            let mut txn = Transaction::begin(&ctx, 1_532_000_000).unwrap();
+            txn.stage(&hx.join("digest-1532000000.txt"), b"DIGEST")
+                .unwrap();
+            // Something failed before commit
+        }
+        assert!(!staging_path(&hx, 1_532_000_000).exists());
+        assert!(!hx.join("digest-1532000000.txt").exists());
+        assert_eq!(ctx.input_paths().unwrap().len(), 1);
+    }
+    #[test]
+    fn test_recover() {
+        let brain = TempBrain::new();
+        let ctx = test_ctx(&brain);
+        let hx = ctx.hx_path();
+        create_dir(&hx).unwrap();
+
+        // Committed, and then the run died with a.txt moved but the digest still staged
+        let committed = staging_path(&hx, 100);
+        create_dir(&committed).unwrap();
+        write(committed.join("digest-100.txt"), "DIGEST").unwrap();
+        write(brain.join("b.txt"), "alert").unwrap();
+        let journal = Journal {
+            timestamp: 100,
+            outputs: vec!["digest-100.txt".into()],
+            inputs: vec!["a.txt".into(), "b.txt".into()],
+        };
+        write(
+            committed.join(JOURNAL_FILE),
+            serde_json::to_string(&journal).unwrap(),
+        )
+        .unwrap();
+        create_dir(archive_path(&hx, 100)).unwrap();
+        rename(brain.join("a.txt"), archive_path(&hx, 100).join("a.txt")).unwrap();
+
+        // Died while staging
+        let uncommitted = staging_path(&hx, 200);
+        create_dir(&uncommitted).unwrap();
+        write(uncommitted.join("digest-200.txt"), "HALF").unwrap();
+
+        let recovered = recover(&brain, &hx).unwrap();
+        assert_eq!(
+            recovered,
+            vec![Recovery::Finished(100), Recovery::RolledBack(200)]
+        );
+        assert!(hx.join("digest-100.txt").exists());
+        assert!(archive_path(&hx, 100).join("a.txt").exists());
+        assert!(archive_path(&hx, 100).join("b.txt").exists());
+        assert!(!committed.exists() && !uncommitted.exists());
+        assert!(!hx.join("digest-200.txt").exists());
+        assert!(recover(&brain, &hx).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,56 @@
+//! Batching of auto-generated iMIS autodraft alert emails into a printable digest.
+//!
+//! This is everything the `ar-bot` command line tool does, for embedding elsewhere:
+//!
+//! * [`email::Email`] holds one raw alert, and [`batch::Entry::from_email`] pulls the iMIS ID,
+//!   product and alert time out of it
+//! * [`parser::AlertParser`] is one alert format, and a [`parser::Registry`] tries several in order
+//! * [`batch::Batch`] folds entries together so there's one [`batch::BatchEntry`] per ID
+//! * [`brain::Context`] loads a [`config::Config`] and reads the brain directory into a
+//!   [`brain::Brain`]
+//! * [`page::Renderer`] and [`page::render_digest`] render a batch as html, text or Markdown
+//! * [`history::History`] reads back which earlier digests each ID was in, and
+//!   [`anomaly::check`] flags entries that look wrong
+//! * [`review::Review`] sorts the brain into what a digest will use up and what stays pending
+//! * [`tui::browse`] shows the batch full-screen, to hold entries back before a digest
+//! * [`journal::cut_digest`] renders, sends and archives a digest all at once, or not at all, on top of
+//!   [`journal::Transaction`]
+//! * [`errors::Error::category`] says whether a failure was the config, a file, an alert that
+//!   wouldn't parse, a template, the archive or delivery, and [`errors::Error::exit_code`] turns that into
+//!   the binary's exit code
+//!
+//! ```
+//! use ar_bot::{batch::{Batch, Entry}, email::Email, page::Renderer};
+//!
+//! let alert = "From:\tiMIS <iMIS@jccgb.org>\r\nSent:\tWednesday, July 18, 2018 1:00 PM\r\nTo:\tSome People\r\nSubject:\tInvoice Charge Change for Grossman Autodraft\r\n\r\nThe Grossman Invoice For iMIS ID 164408 For the Product CG_TRANS Has Changed\r\nYou need to verify the Autodraft is now correct";
+//!
+//! let mut batch = Batch::new();
+//! batch.add_entry(Entry::from_email(&Email::new("alert.txt", alert)?)?)?;
+//! assert!(Renderer::Text.render(&batch)?.contains("iMIS ID: 164408"));
+//! # Ok::<(), ar_bot::errors::Error>(())
+//! ```
+#![recursion_limit = "1024"]
+
+pub mod anomaly;
+pub mod auto;
+pub mod batch;
+pub mod brain;
+pub mod config;
+pub mod email;
+pub mod errors;
+pub mod export;
+pub mod history;
+pub mod imap;
+pub mod journal;
+pub mod lock;
+pub mod page;
+pub mod parser;
+pub mod review;
+pub mod smtp;
+pub mod template;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod tui;
+pub mod util;
+pub mod validate;
+pub mod watch;
@@ -0,0 +1,225 @@
+// lock.rs keeps two ar-bot runs from reading and archiving the same brain at once
+// It's advisory - a lock file in the brain saying who has it - so it works on a shared drive
+use crate::errors::*;
+use chrono::{Local, TimeZone};
+use log::*;
+use std::{
+    fmt,
+    fs::{read_to_string, remove_file, OpenOptions},
+    io::{prelude::*, ErrorKind as IoErrorKind},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+/// The lock file's name inside the brain - never read as an input
+pub static LOCK_FILE: &str = ".ar-bot.lock";
+
+/// The run holding a lock, as written in the lock file
+#[derive(Debug, PartialEq)]
+pub struct Holder {
+    pub host: String,
+    pub pid: u32,
+    // Unix timestamp
+    pub since: i64,
+}
+
+impl Holder {
+    fn current() -> Self {
+        Holder {
+            host: this_host(),
+            pid: process::id(),
+            since: Local::now().timestamp(),
+        }
+    }
+
+    // host, pid and since, one per line
+    fn parse(s: &str) -> Option<Self> {
+        let mut lines = s.lines();
+        Some(Holder {
+            host: lines.next()?.to_string(),
+            pid: lines.next()?.parse().ok()?,
+            since: lines.next()?.parse().ok()?,
+        })
+    }
+
+    // Only knowable for a run on this machine, and only where there's a /proc to look in
+    fn is_running(&self) -> Option<bool> {
+        let proc_dir = Path::new("/proc");
+        if self.host != this_host() || !proc_dir.is_dir() {
+            return None;
+        }
+        Some(proc_dir.join(self.pid.to_string()).exists())
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since = match Local.timestamp_opt(self.since, 0).single() {
+            Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+            None => "an unknown time".into(),
+        };
+        write!(
+            f,
+            "ar-bot on {} (pid {}) since {}",
+            self.host, self.pid, since
+        )
+    }
+}
+
+fn this_host() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown host".into())
+}
+
+/// An advisory lock on one brain directory, released when dropped
+#[derive(Debug)]
+pub struct BrainLock {
+    path: PathBuf,
+}
+
+impl BrainLock {
+    /// Takes the lock on the brain, or fails naming whoever has it
+    /// A lock left by a run that's no longer going, or older than stale_after, is taken over
+    pub fn acquire(brain_path: &Path, stale_after: Duration) -> Result<Self> {
+        let path = brain_path.join(LOCK_FILE);
+        // Once more after clearing out a stale lock
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut f) => {
+                    let holder = Holder::current();
+                    f.write_all(
+                        format!("{}\n{}\n{}\n", holder.host, holder.pid, holder.since).as_bytes(),
+                    )
+                    .and_then(|_| f.sync_all())
+                    .chain_err(|| format!("Could not write lock file {:?}", path))?;
+                    debug!("LOCK: {:?}", path);
+                    return Ok(BrainLock { path });
+                }
+                Err(e) if e.kind() == IoErrorKind::AlreadyExists => {
+                    let contents = read_to_string(&path).unwrap_or_default();
+                    let holder = Holder::parse(&contents);
+                    match stale_reason(&path, holder.as_ref(), stale_after) {
+                        Some(reason) => {
+                            warn!("Taking over the lock on {:?} - {}", brain_path, reason);
+                            // Another run may have beaten us to it - only clear out the lock we looked at
+                            if read_to_string(&path).unwrap_or_default() == contents {
+                                match remove_file(&path) {
+                                    Err(e) if e.kind() != IoErrorKind::NotFound => {
+                                        return Err(e).chain_err(|| {
+                                            format!("Could not remove stale lock file {:?}", path)
+                                        })
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        None => {
+                            return Err(locked(brain_path, &path, holder.as_ref(), stale_after))
+                        }
+                    }
+                }
+                Err(e) => {
+                    return Err(e).chain_err(|| format!("Could not create lock file {:?}", path))
+                }
+            }
+        }
+        let holder = read_to_string(&path).ok().and_then(|s| Holder::parse(&s));
+        Err(locked(brain_path, &path, holder.as_ref(), stale_after))
+    }
+}
+
+impl Drop for BrainLock {
+    fn drop(&mut self) {
+        debug!("UNLOCK: {:?}", self.path);
+        if let Err(e) = remove_file(&self.path) {
+            warn!("Could not remove lock file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Why a lock can be taken over, or None if whoever holds it may still be working
+fn stale_reason(path: &Path, holder: Option<&Holder>, stale_after: Duration) -> Option<String> {
+    if let Some(h) = holder {
+        if h.is_running() == Some(false) {
+            return Some(format!("{} is no longer running", h));
+        }
+    }
+    // A lock file that's still being written has no holder yet - go by when it was made
+    let age = match holder {
+        Some(h) => Duration::from_secs((Local::now().timestamp() - h.since).max(0) as u64),
+        None => path
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .unwrap_or_default(),
+    };
+    if age > stale_after {
+        let who = holder.map_or("the lock".to_string(), |h| h.to_string());
+        Some(format!(
+            "{} is older than {} minutes",
+            who,
+            stale_after.as_secs() / 60
+        ))
+    } else {
+        None
+    }
+}
+
+fn locked(brain_path: &Path, path: &Path, holder: Option<&Holder>, stale_after: Duration) -> Error {
+    let who = holder.map_or("another ar-bot run".to_string(), |h| h.to_string());
+    ErrorKind::Locked(format!(
+        "The brain at {:?} is locked by {} - try again once it's done.  \
+         If that run died, the lock goes stale after {} minutes, or delete {:?}",
+        brain_path,
+        who,
+        stale_after.as_secs() / 60,
+        path
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempBrain;
+    use std::fs::write;
+
+    #[test]
+    fn test_lock_held() {
+        let brain = TempBrain::new();
+        let hour = Duration::from_secs(3600);
+        let lock = BrainLock::acquire(&brain, hour).unwrap();
+        let held = BrainLock::acquire(&brain, hour).unwrap_err();
+        assert_eq!(held.category(), Category::Locked);
+        assert!(held
+            .to_string()
+            .contains(&format!("(pid {})", process::id())));
+
+        drop(lock);
+        assert!(!brain.join(LOCK_FILE).exists());
+        let again = BrainLock::acquire(&brain, hour);
+        assert!(again.is_ok());
+        drop(again);
+    }
+    #[test]
+    fn test_lock_stale() {
+        let brain = TempBrain::new();
+        let hour = Duration::from_secs(3600);
+        // On another machine, so only its age counts
+        let old = Local::now().timestamp() - 7200;
+        write(brain.join(LOCK_FILE), format!("elsewhere\n1\n{}\n", old)).unwrap();
+        let lock = BrainLock::acquire(&brain, hour).unwrap();
+        let holder = Holder::parse(&read_to_string(brain.join(LOCK_FILE)).unwrap()).unwrap();
+        assert_eq!(holder.pid, process::id());
+        drop(lock);
+
+        let recent = Local::now().timestamp() - 60;
+        write(brain.join(LOCK_FILE), format!("elsewhere\n1\n{}\n", recent)).unwrap();
+        let held = BrainLock::acquire(&brain, hour).unwrap_err();
+        assert!(held.to_string().contains("ar-bot on elsewhere (pid 1)"));
+    }
+}
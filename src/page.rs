@@ -1,44 +1,444 @@
 // page.rs handles the askama templates
+use crate::{
+    anomaly::{self, Anomaly},
+    batch::{Batch, BatchEntry, TimeDetail, UserID},
+    brain::Context,
+    config::{Anomalies, ProductGroup},
+    errors::*,
+    export::Export,
+    history::History,
+    template,
+};
 use askama::Template;
 use chrono::prelude::*;
-use crate::{batch::{Batch, BatchEntry}, brain::Context, errors::*};
-use std::{fs::File, io::prelude::*};
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
 #[derive(Template)]
 #[template(path = "digest.html")]
-struct DigestTemplate {
+struct DigestTemplate<'a> {
     entries: Vec<BatchEntry>,
+    annotations: &'a Annotations,
 }
 
+#[derive(Template)]
+#[template(path = "digest.txt")]
+struct DigestTextTemplate<'a> {
+    entries: Vec<BatchEntry>,
+    annotations: &'a Annotations,
+}
+
+#[derive(Template)]
+#[template(path = "digest.md", escape = "none")]
+struct DigestMarkdownTemplate<'a> {
+    entries: Vec<BatchEntry>,
+    annotations: &'a Annotations,
+}
+
+/// What's shown under each ID besides its alerts: the earlier digests it was in, and anything that looks wrong
+#[derive(Debug, Default)]
+pub struct Annotations {
+    notes: HashMap<UserID, String>,
+    anomalies: HashMap<UserID, Vec<Anomaly>>,
+}
+
+impl Annotations {
+    pub fn new(batch: &Batch, history: &History, thresholds: &Anomalies) -> Self {
+        Annotations {
+            notes: batch
+                .entries
+                .values()
+                .filter_map(|e| history.note(e).map(|n| (e.id, n)))
+                .collect(),
+            anomalies: anomaly::check(batch, history, thresholds),
+        }
+    }
+
+    /// Where the ID was seen before - empty for an ID no earlier digest had
+    pub fn note(&self, id: UserID) -> &str {
+        self.notes.get(&id).map_or("", |n| n.as_str())
+    }
+
+    pub fn anomalies(&self, id: UserID) -> &[Anomaly] {
+        self.anomalies.get(&id).map_or(&[], |a| a.as_slice())
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "report.html")]
 struct ReportTemplate<'a> {
     date: &'a str,
 }
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "skel.html")]
 struct SkelTemplate {}
 
-// write_digest writes the digest to hx/
-pub fn write_digest(ctx: &Context) -> Result<()> {
-    let batch = Batch::from_brain(&ctx.brain)?;
-    let mut digest_path = ctx.hx_path();
-    digest_path.push(format!("digest-{}.html", Local::now().timestamp()));
-    let mut digest_file = File::create(digest_path).chain_err(|| "Could not create digest file")?;
-    let mut entries = Vec::new();
-
-    for entry in batch.entries.values() {
-        entries.push(entry.clone());
-    }
-    let digest = DigestTemplate { entries };
-    digest_file
-        .write_all(
-            digest
-                .render()
-                .expect("Could not render digest template")
-                .as_bytes(),
+/// Which template the digest is rendered with
+/// Set with `render` under `[digest]` in the config, or --render on the command line
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    #[default]
+    Html,
+    // Fixed-width plain text, for the line printer or an email body
+    Text,
+    // For the wiki
+    Markdown,
+}
+
+impl Renderer {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Renderer::Html => "html",
+            Renderer::Text => "txt",
+            Renderer::Markdown => "md",
+        }
+    }
+
+    pub fn render(self, batch: &Batch) -> Result<String> {
+        self.render_with(batch, &Annotations::default(), TimeDetail::default())
+    }
+
+    /// Renders the batch with each ID's history note and anomaly badges, and its times squashed
+    pub fn render_with(
+        self,
+        batch: &Batch,
+        annotations: &Annotations,
+        detail: TimeDetail,
+    ) -> Result<String> {
+        let mut entries: Vec<BatchEntry> =
+            batch.entries.values().map(|e| e.squashed(detail)).collect();
+        entries.sort_by_key(|e| e.id);
+        match self {
+            Renderer::Html => DigestTemplate {
+                entries,
+                annotations,
+            }
+            .render(),
+            Renderer::Text => DigestTextTemplate {
+                entries,
+                annotations,
+            }
+            .render(),
+            Renderer::Markdown => DigestMarkdownTemplate {
+                entries,
+                annotations,
+            }
+            .render(),
+        }
+        .chain_err(|| ErrorKind::Template("Could not render digest template".into()))
+    }
+}
+
+impl FromStr for Renderer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "html" => Ok(Renderer::Html),
+            "text" => Ok(Renderer::Text),
+            "markdown" => Ok(Renderer::Markdown),
+            _ => Err(format!("Unknown digest renderer {}", s).into()),
+        }
+    }
+}
+
+/// A digest rendered in memory, along with where each file goes in hx/ once it's cut
+#[derive(Debug)]
+pub struct DigestOutput {
+    pub renderer: Renderer,
+    pub timestamp: i64,
+    pub digest: String,
+    pub path: PathBuf,
+    // Each export, where it goes and what's in it
+    pub exports: Vec<(Export, PathBuf, Vec<u8>)>,
+    // The products this digest was split out for, if it was - None for everything else
+    pub group: Option<ProductGroup>,
+}
+
+/// render_digest renders the digest, and any requested exports sharing its timestamp, without writing anything
+/// Nothing touches the disk until every one has rendered - see journal::Transaction for cutting it
+/// IDs seen in earlier digests in hx/ are noted as such, and anything odd is flagged
+pub fn render_digest(
+    ctx: &Context,
+    batch: &Batch,
+    renderer: Renderer,
+    exports: &[Export],
+) -> Result<DigestOutput> {
+    let annotations = annotate(ctx, batch)?;
+    render_one(
+        ctx,
+        batch,
+        renderer,
+        exports,
+        &annotations,
+        Local::now().timestamp(),
+        None,
+    )
+}
+
+/// One digest per group under `[digest]` that has anything pending, then one for every product in no group
+/// They share a timestamp, so they're cut and archived as one digest
+pub fn render_split(
+    ctx: &Context,
+    batch: &Batch,
+    renderer: Renderer,
+    exports: &[Export],
+) -> Result<Vec<DigestOutput>> {
+    let timestamp = Local::now().timestamp();
+    // Worked out on the whole batch, so splitting it doesn't hide anything
+    let annotations = annotate(ctx, batch)?;
+    let groups = &ctx.config.digest.groups;
+    let mut ret = Vec::new();
+    for group in groups {
+        let part = batch.filter_products(|p| group.products.contains(p));
+        if part.entries.is_empty() {
+            info!(
+                "Nothing pending for {} - no digest for it this time",
+                group.name
+            );
+        } else {
+            ret.push(render_one(
+                ctx,
+                &part,
+                renderer,
+                exports,
+                &annotations,
+                timestamp,
+                Some(group),
+            )?);
+        }
+    }
+    let rest = batch.filter_products(|p| !groups.iter().any(|g| g.products.contains(p)));
+    // An empty batch still gets the one digest it always would have
+    if !rest.entries.is_empty() || ret.is_empty() {
+        ret.push(render_one(
+            ctx,
+            &rest,
+            renderer,
+            exports,
+            &annotations,
+            timestamp,
+            None,
+        )?);
+    }
+    Ok(ret)
+}
+
+/// What `preview` prints for a batch in text: the entries, then what's flagged and what's been seen before
+pub fn preview_text(batch: &Batch, annotations: &Annotations, detail: TimeDetail) -> String {
+    let mut ret = format!("{}\n\n", batch.to_text(detail));
+    let mut ids: Vec<UserID> = batch.entries.keys().copied().collect();
+    ids.sort_unstable();
+    let flagged: Vec<UserID> = ids
+        .iter()
+        .copied()
+        .filter(|id| !annotations.anomalies(*id).is_empty())
+        .collect();
+    if !flagged.is_empty() {
+        ret.push_str("Flagged:\n");
+        for id in flagged {
+            for anomaly in annotations.anomalies(id) {
+                ret.push_str(&format!("  {}: [{}] {}\n", id, anomaly.badge(), anomaly));
+            }
+        }
+        ret.push('\n');
+    }
+    let seen: Vec<UserID> = ids
+        .into_iter()
+        .filter(|id| !annotations.note(*id).is_empty())
+        .collect();
+    if !seen.is_empty() {
+        ret.push_str("Seen before:\n");
+        for id in seen {
+            ret.push_str(&format!("  {}: {}\n", id, annotations.note(id)));
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// The history notes and anomaly badges for a batch, from hx/ and the thresholds in the config
+pub fn annotate(ctx: &Context, batch: &Batch) -> Result<Annotations> {
+    let history = History::read(&ctx.hx_path())?;
+    Ok(Annotations::new(batch, &history, &ctx.config.anomalies))
+}
+
+fn render_one(
+    ctx: &Context,
+    batch: &Batch,
+    renderer: Renderer,
+    exports: &[Export],
+    annotations: &Annotations,
+    timestamp: i64,
+    group: Option<&ProductGroup>,
+) -> Result<DigestOutput> {
+    let hx_path = ctx.hx_path();
+    let digest = match &ctx.config.digest.template_dir {
+        Some(dir) => template::render(dir, renderer, batch, annotations, ctx.config.digest.times)?,
+        None => renderer.render_with(batch, annotations, ctx.config.digest.times)?,
+    };
+    // digest-TIMESTAMP-SLUG for a group, so history still finds them all under one timestamp
+    let stem = match group {
+        Some(g) => format!("digest-{}-{}", timestamp, g.slug()),
+        None => format!("digest-{}", timestamp),
+    };
+
+    let mut rendered = Vec::new();
+    for export in exports {
+        let export_path = hx_path.join(format!("{}.{}", stem, export.extension()));
+        rendered.push((*export, export_path, export.to_bytes(batch)?));
+    }
+    Ok(DigestOutput {
+        renderer,
+        timestamp,
+        digest,
+        path: hx_path.join(format!("{}.{}", stem, renderer.extension())),
+        exports: rendered,
+        group: group.cloned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{Entry, Product},
+        email::Email,
+        test_util::TempBrain,
+        util::*,
+    };
+
+    #[test]
+    fn test_render_text() {
+        let mut batch = Batch::test_second_email_str(TEST_DIF_BOTH);
+        batch
+            .add_entry(Entry::from_email(&Email::from_str(TEST_DIF_PROD).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(
+            Renderer::Text.render(&batch).unwrap(),
+            "DIGEST
+------------------------------------------------------------------------
+iMIS ID: 12345        Individual's Name: ___________________
+  Non-builtin product COOL_PROD (COOL_PROD)
+    alert at Saturday, July 21, 2018 4:39 PM
+    Was: __________ @ __________   New: __________ @ __________
+    INIT: _____
+  Non-builtin product COOL_PROOD (COOL_PROOD)
+    alert at Saturday, July 21, 2018 4:39 PM
+    Was: __________ @ __________   New: __________ @ __________
+    INIT: _____
+------------------------------------------------------------------------
+iMIS ID: 22345        Individual's Name: ___________________
+  Non-builtin product COL_PROD (COL_PROD)
+    alert at Saturday, July 21, 2018 4:39 PM
+    Was: __________ @ __________   New: __________ @ __________
+    INIT: _____
+------------------------------------------------------------------------
+"
+        )
+    }
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(
+            Renderer::Markdown.render(&Batch::test()).unwrap(),
+            "# Digest
+
+## iMIS ID 12345
+
+Individual's Name: ___________________
+
+| Product | Alerts | Was | New | INIT |
+| --- | --- | --- | --- | --- |
+| Non-builtin product COOL_PROD (`COOL_PROD`) | Saturday, July 21, 2018 4:39 PM | __________ @ __________ | __________ @ __________ | _____ |
+"
         )
-        .chain_err(|| "Could not write digest")?;
-    Ok(())
+    }
+    #[test]
+    fn test_render_times() {
+        let days = Renderer::Text
+            .render_with(&Batch::sample(), &Annotations::default(), TimeDetail::Days)
+            .unwrap();
+        assert!(days.contains("    alert at Jul 18 1:00–4:30 PM, 2 alerts\n"));
+        let html = Renderer::Html
+            .render_with(&Batch::sample(), &Annotations::default(), TimeDetail::Days)
+            .unwrap();
+        assert!(html.contains("Jul 19 9:15 AM"));
+    }
+    #[test]
+    fn test_render_history() {
+        let dir = TempBrain::new();
+        let archive = dir.join("1532000100");
+        std::fs::create_dir_all(&archive).unwrap();
+        std::fs::write(
+            archive.join("a.txt"),
+            &Email::from_str(TEST_COOL_STR).unwrap().contents,
+        )
+        .unwrap();
+        let history = History::read(&dir).unwrap();
+        let thresholds = Anomalies {
+            unknown_products: false,
+            ..Anomalies::default()
+        };
+        let annotations = Annotations::new(&Batch::test(), &history, &thresholds);
+
+        let text = Renderer::Text
+            .render_with(&Batch::test(), &annotations, TimeDetail::All)
+            .unwrap();
+        let note = text.lines().nth(3).unwrap();
+        assert!(note.starts_with("  In 1 earlier digest: "));
+        assert!(note.ends_with(
+            "(COOL_PROD; unverified).  COOL_PROD alerted before too - check the autodraft"
+        ));
+        let html = Renderer::Html
+            .render_with(&Batch::test(), &annotations, TimeDetail::All)
+            .unwrap();
+        assert!(html.contains("<p class=\"history\">In 1 earlier digest: "));
+        let plain = Renderer::Html.render(&Batch::test()).unwrap();
+        assert!(!plain.contains("class=\"history\""));
+    }
+    #[test]
+    fn test_render_split() {
+        let dir = TempBrain::new();
+        let mut config = dir.config();
+        config.digest.groups = vec![
+            ProductGroup {
+                name: "Grossman".into(),
+                products: vec![Product::CgBilling, Product::CgTrans],
+                to: Vec::new(),
+            },
+            ProductGroup {
+                name: "Camp King".into(),
+                products: vec![Product::CampKingTuit],
+                to: Vec::new(),
+            },
+        ];
+        let ctx = Context::initialize(config).unwrap();
+        let outputs = render_split(&ctx, &Batch::sample(), Renderer::Text, &[Export::Csv]).unwrap();
+
+        // Nothing for Camp King, so no digest for it
+        let names: Vec<String> = outputs
+            .iter()
+            .map(|o| o.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        let ts = outputs[0].timestamp;
+        assert_eq!(
+            names,
+            vec![
+                format!("digest-{}-grossman.txt", ts),
+                format!("digest-{}.txt", ts)
+            ]
+        );
+        assert!(outputs[0].digest.contains("100001") && !outputs[0].digest.contains("100002"));
+        assert!(
+            outputs[1].digest.contains("CAMP_KALE_TUIT") && !outputs[1].digest.contains("100001")
+        );
+        assert!(outputs[0].exports[0]
+            .1
+            .ends_with(format!("digest-{}-grossman.csv", ts)));
+    }
 }
@@ -0,0 +1,282 @@
+// parser.rs turns raw alert emails into batch entries, one alert format per parser
+use crate::{
+    batch::{BatchKey, Entry, Product, UserID},
+    email::Email,
+    errors::*,
+};
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
+use std::str::FromStr;
+
+/// One alert format
+/// `Key` is what alerts are grouped on in the batch, `Item` is what's listed under it
+pub trait AlertParser {
+    type Key: BatchKey;
+    type Item: BatchKey;
+
+    /// Short name used in logs and error messages
+    fn name(&self) -> &str;
+
+    /// Whether the message is in this parser's format
+    fn matches(&self, email: &Email) -> bool;
+
+    /// Pulls the grouping key, item and alert time out of a message `matches` accepted
+    fn parse(&self, email: &Email) -> Result<Entry<Self::Key, Self::Item>>;
+}
+
+/// The iMIS "Invoice Charge Change" autodraft alert, grouped by iMIS ID
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Autodraft;
+
+// Outlook exports like real3.txt have bare \n line endings, and wrap the body wherever
+lazy_static! {
+    static ref AD_RE: Regex = Regex::new(r"From:.+\s+Sent:\s+(?P<date>[^\r\n]+)\r?\nTo:.+\s+Subject:.+\s+The \w+ Invoice For iMIS ID (?P<id>\d+) For the Product (?P<product>\w+) Has Changed\s+You need to verify\s+the Autodraft is now correct").unwrap();
+}
+
+impl AlertParser for Autodraft {
+    type Key = UserID;
+    type Item = Product;
+
+    fn name(&self) -> &str {
+        "autodraft"
+    }
+
+    fn matches(&self, email: &Email) -> bool {
+        AD_RE.is_match(&email.contents)
+    }
+
+    fn parse(&self, email: &Email) -> Result<Entry> {
+        let s = &email.contents;
+        match AD_RE.captures(s) {
+            Some(ad_captures) => {
+                debug!("MATCH: {}", s);
+                Ok(Entry {
+                    id: ad_captures["id"]
+                        .parse::<u32>()
+                        .chain_err(|| ErrorKind::Parse("Could not read iMIS id".into()))?,
+                    product: Product::from_str(&ad_captures["product"])?,
+                    time: ad_captures["date"].to_string(),
+                })
+            }
+            None => {
+                debug!("{}", s);
+                Err(ErrorKind::Parse(format!(
+                    "{} doesn't look like an autodraft alert",
+                    email.filename
+                ))
+                .into())
+            }
+        }
+    }
+}
+
+/// Parsers sharing one key and item type, tried in the order they were registered
+/// The first one whose `matches` accepts a message parses it
+pub struct Registry<K = UserID, I = Product> {
+    parsers: Vec<Box<dyn AlertParser<Key = K, Item = I>>>,
+}
+
+impl<K: BatchKey, I: BatchKey> Registry<K, I> {
+    /// A registry with no parsers - nothing will match until one is registered
+    pub fn empty() -> Self {
+        Registry {
+            parsers: Vec::new(),
+        }
+    }
+
+    /// Adds a parser after the ones already registered
+    pub fn register<P>(&mut self, parser: P) -> &mut Self
+    where
+        P: AlertParser<Key = K, Item = I> + 'static,
+    {
+        self.parsers.push(Box::new(parser));
+        self
+    }
+
+    /// The first registered parser that accepts the message, if any
+    pub fn find(&self, email: &Email) -> Option<&dyn AlertParser<Key = K, Item = I>> {
+        self.parsers
+            .iter()
+            .find(|p| p.matches(email))
+            .map(|p| p.as_ref())
+    }
+
+    /// Whether any registered parser accepts the message
+    pub fn matches(&self, email: &Email) -> bool {
+        self.find(email).is_some()
+    }
+
+    /// Parses the message with the first parser that accepts it
+    pub fn parse(&self, email: &Email) -> Result<Entry<K, I>> {
+        match self.find(email) {
+            Some(parser) => {
+                debug!("PARSER: {} - {}", email.filename, parser.name());
+                parser.parse(email)
+            }
+            None => {
+                let names: Vec<&str> = self.parsers.iter().map(|p| p.name()).collect();
+                Err(ErrorKind::Parse(format!(
+                    "{} doesn't look like any known alert (tried {})",
+                    email.filename,
+                    names.join(", ")
+                ))
+                .into())
+            }
+        }
+    }
+}
+
+// The built-in registry: just the autodraft alert
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+        registry.register(Autodraft);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{Batch, EntryClass},
+        util::*,
+    };
+
+    // A made-up enrollment change alert, grouped by course code with the student as the item
+    struct Enrollment;
+
+    impl AlertParser for Enrollment {
+        type Key = String;
+        type Item = String;
+
+        fn name(&self) -> &str {
+            "enrollment"
+        }
+
+        fn matches(&self, email: &Email) -> bool {
+            email.contents.starts_with("Enrollment change")
+        }
+
+        fn parse(&self, email: &Email) -> Result<Entry<String, String>> {
+            let fields: Vec<&str> = email.contents.split_whitespace().collect();
+            match fields.as_slice() {
+                ["Enrollment", "change", "for", course, "student", student, "at", time @ ..] => {
+                    Ok(Entry {
+                        id: course.to_string(),
+                        product: student.to_string(),
+                        time: time.join(" "),
+                    })
+                }
+                _ => Err(ErrorKind::Parse(format!(
+                    "{} is a malformed enrollment alert",
+                    email.filename
+                ))
+                .into()),
+            }
+        }
+    }
+
+    // Takes anything at all, so it only sees what the parsers ahead of it turned down
+    struct Anything;
+
+    impl AlertParser for Anything {
+        type Key = String;
+        type Item = String;
+
+        fn name(&self) -> &str {
+            "anything"
+        }
+
+        fn matches(&self, _email: &Email) -> bool {
+            true
+        }
+
+        fn parse(&self, email: &Email) -> Result<Entry<String, String>> {
+            Ok(Entry {
+                id: email.filename.clone(),
+                product: email.contents.clone(),
+                time: String::new(),
+            })
+        }
+    }
+
+    fn enrollment(contents: &str) -> Email {
+        Email::new("enrollment.txt", contents).unwrap()
+    }
+
+    #[test]
+    fn test_default_registry_parses_autodraft() {
+        let email = Email::from_str(TEST_COOL_STR).unwrap();
+        assert_eq!(
+            Registry::default().parse(&email).unwrap(),
+            Entry::from_email(&email).unwrap()
+        );
+        assert!(!Registry::default().matches(&enrollment(
+            "Enrollment change for BIO-101 student 44 at 9:00 AM"
+        )));
+    }
+    #[test]
+    fn test_autodraft_outlook_export() {
+        let email = Email::new("real3.txt", include_str!("../real3.txt")).unwrap();
+        assert_eq!(
+            Autodraft.parse(&email).unwrap(),
+            Entry {
+                id: 164408,
+                product: Product::CgTrans,
+                time: "Wednesday, July 18, 2018 1:00 PM".into(),
+            }
+        );
+    }
+    #[test]
+    fn test_registry_tries_in_order() {
+        let mut registry: Registry<String, String> = Registry::empty();
+        let email = enrollment("Enrollment change for BIO-101 student 44 at 9:00 AM");
+        assert!(registry.parse(&email).is_err());
+        registry.register(Enrollment).register(Anything);
+        assert_eq!(registry.find(&email).unwrap().name(), "enrollment");
+        assert_eq!(
+            registry.find(&enrollment("Something else")).unwrap().name(),
+            "anything"
+        );
+        assert_eq!(
+            registry.parse(&email).unwrap(),
+            Entry {
+                id: "BIO-101".to_string(),
+                product: "44".to_string(),
+                time: "9:00 AM".to_string(),
+            }
+        );
+    }
+    #[test]
+    fn test_batch_other_format() {
+        let mut registry = Registry::empty();
+        registry.register(Enrollment);
+        let mut batch = Batch::new();
+        let mut classes = Vec::new();
+        for alert in &[
+            "Enrollment change for BIO-101 student 44 at 9:00 AM",
+            "Enrollment change for BIO-101 student 45 at 9:05 AM",
+            "Enrollment change for BIO-101 student 44 at 9:10 AM",
+            "Enrollment change for CHEM-200 student 44 at 9:15 AM",
+        ] {
+            classes.push(
+                batch
+                    .add_entry(registry.parse(&enrollment(alert)).unwrap())
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            classes,
+            vec![
+                EntryClass::New,
+                EntryClass::NewProduct("BIO-101".to_string()),
+                EntryClass::Duplicate(("BIO-101".to_string(), "44".to_string())),
+                EntryClass::New,
+            ]
+        );
+        assert_eq!(batch.entries.len(), 2);
+        assert_eq!(Batch::from_json(&batch.to_json().unwrap()).unwrap(), batch);
+    }
+}
@@ -0,0 +1,256 @@
+// review.rs works out what a digest will use up before it's cut, and lets some of it wait for the next one
+use crate::{
+    batch::{Batch, Entry, Product, UserID},
+    brain::Context,
+    errors::*,
+    parser::Registry,
+};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// One thing to leave out of a digest: everything for an iMIS ID, or every alert for a product
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exclusion {
+    Id(UserID),
+    Product(Product),
+}
+
+impl FromStr for Exclusion {
+    type Err = Error;
+
+    // A number is an iMIS ID, anything else a product code
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Nothing to exclude".into());
+        }
+        match s.parse::<UserID>() {
+            Ok(id) => Ok(Exclusion::Id(id)),
+            Err(_) => Ok(Exclusion::Product(Product::from_str(&s.to_uppercase())?)),
+        }
+    }
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exclusion::Id(id) => write!(f, "iMIS ID {}", id),
+            Exclusion::Product(p) => write!(f, "{}", p.code()),
+        }
+    }
+}
+
+/// Everything in the brain, sorted into what the next digest will use up and what it'll leave
+#[derive(Debug)]
+pub struct Review {
+    // Each alert that parsed, and the file in the brain it came from
+    alerts: Vec<(String, Entry)>,
+    // Messages no parser recognized, and why
+    pub unmatched: Vec<(String, String)>,
+    pub excluded: BTreeSet<Exclusion>,
+    brain_path: PathBuf,
+}
+
+/// The counts shown before a digest is cut
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    // Files in the brain the digest archives
+    pub emails: usize,
+    pub ids: usize,
+    pub alerts: usize,
+    // Left in the brain because something in them was excluded
+    pub held: usize,
+    pub unmatched: usize,
+}
+
+// read_email_file names the emails from a file holding several file#1, file#2...
+fn source_file(filename: &str) -> &str {
+    match filename.rsplit_once('#') {
+        Some((file, n)) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => file,
+        _ => filename,
+    }
+}
+
+impl Review {
+    /// Sorts every email in the brain, with nothing excluded yet
+    pub fn new(ctx: &Context) -> Self {
+        let registry = Registry::default();
+        let mut alerts = Vec::new();
+        let mut unmatched = Vec::new();
+        for email in &ctx.brain.emails {
+            let file = source_file(&email.filename).to_string();
+            match registry.parse(email) {
+                Ok(entry) => alerts.push((file, entry)),
+                Err(e) => unmatched.push((email.filename.clone(), e.to_string())),
+            }
+        }
+        Review {
+            alerts,
+            unmatched,
+            excluded: BTreeSet::new(),
+            brain_path: ctx.config.directory.path.clone(),
+        }
+    }
+
+    fn is_excluded(&self, entry: &Entry) -> bool {
+        self.excluded.contains(&Exclusion::Id(entry.id))
+            || self
+                .excluded
+                .contains(&Exclusion::Product(entry.product.clone()))
+    }
+
+    /// Whether anything pending is for this ID or product
+    pub fn mentions(&self, exclusion: &Exclusion) -> bool {
+        self.alerts.iter().any(|(_, entry)| match exclusion {
+            Exclusion::Id(id) => entry.id == *id,
+            Exclusion::Product(p) => entry.product == *p,
+        })
+    }
+
+    // Files with an excluded alert in them
+    // A file is archived whole, so one excluded alert holds back everything else in it too
+    fn excluded_files(&self) -> HashSet<&str> {
+        self.alerts
+            .iter()
+            .filter(|(_, entry)| self.is_excluded(entry))
+            .map(|(file, _)| file.as_str())
+            .collect()
+    }
+
+    // Files that stay in the brain: anything excluded, and anything unmatched
+    fn held_files(&self) -> HashSet<&str> {
+        let mut ret = self.excluded_files();
+        ret.extend(self.unmatched.iter().map(|(name, _)| source_file(name)));
+        ret
+    }
+
+    /// The batch the digest will be cut from
+    pub fn batch(&self) -> Result<Batch> {
+        let held = self.held_files();
+        let mut batch = Batch::new();
+        for (file, entry) in &self.alerts {
+            if !held.contains(file.as_str()) {
+                batch.add_entry(entry.clone())?;
+            }
+        }
+        Ok(batch)
+    }
+
+    /// The files in the brain the digest will archive
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        let held = self.held_files();
+        let files: BTreeSet<&str> = self
+            .alerts
+            .iter()
+            .map(|(file, _)| file.as_str())
+            .filter(|f| !held.contains(f))
+            .collect();
+        files.into_iter().map(|f| self.brain_path.join(f)).collect()
+    }
+
+    pub fn summary(&self) -> Result<Summary> {
+        let batch = self.batch()?;
+        let alerts = batch
+            .entries
+            .values()
+            .flat_map(|e| e.alerts.values())
+            .map(|times| times.len())
+            .sum();
+        Ok(Summary {
+            emails: self.inputs().len(),
+            ids: batch.entries.len(),
+            alerts,
+            held: self.excluded_files().len(),
+            unmatched: self.unmatched.len(),
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "This digest will use up {} emails: {} alerts for {} IDs.",
+            self.emails, self.alerts, self.ids
+        )?;
+        if self.held > 0 {
+            write!(f, "\n{} emails are excluded and stay pending.", self.held)?;
+        }
+        if self.unmatched > 0 {
+            write!(
+                f,
+                "\n{} messages don't look like any alert, and stay in the brain.",
+                self.unmatched
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{email::Email, test_util::TempBrain, util::*};
+    use std::fs::write;
+
+    fn test_review(dir: &TempBrain) -> Review {
+        let mut ctx = dir.context();
+        for (name, alert) in &[
+            ("a.txt", TEST_COOL_STR),
+            ("b.txt", TEST_DIF_PROD),
+            ("c.txt", TEST_DIF_BOTH),
+        ] {
+            write(dir.join(name), &Email::from_str(alert).unwrap().contents).unwrap();
+        }
+        write(dir.join("junk.txt"), "Out of office").unwrap();
+        ctx.read_fs().unwrap();
+        Review::new(&ctx)
+    }
+
+    #[test]
+    fn test_review_everything() {
+        let dir = TempBrain::new();
+        let review = test_review(&dir);
+        assert_eq!(review.unmatched.len(), 1);
+        assert_eq!(
+            review.inputs(),
+            vec![dir.join("a.txt"), dir.join("b.txt"), dir.join("c.txt")]
+        );
+        assert_eq!(
+            review.summary().unwrap(),
+            Summary {
+                emails: 3,
+                ids: 2,
+                alerts: 3,
+                held: 0,
+                unmatched: 1
+            }
+        );
+    }
+    #[test]
+    fn test_review_excluded() {
+        let dir = TempBrain::new();
+        let mut review = test_review(&dir);
+        review
+            .excluded
+            .insert(Exclusion::from_str("22345").unwrap());
+        review
+            .excluded
+            .insert(Exclusion::from_str("cool_prood").unwrap());
+        let batch = review.batch().unwrap();
+        assert_eq!(batch.entries.keys().collect::<Vec<&UserID>>(), vec![&12345]);
+        assert_eq!(batch.entries[&12345].alerts.len(), 1);
+        assert_eq!(review.inputs(), vec![dir.join("a.txt")]);
+        assert_eq!(review.summary().unwrap().held, 2);
+    }
+    #[test]
+    fn test_source_file() {
+        assert_eq!(source_file("alerts.txt#2"), "alerts.txt");
+        assert_eq!(source_file("alerts.txt"), "alerts.txt");
+        assert_eq!(source_file("issue#a.txt"), "issue#a.txt");
+    }
+}
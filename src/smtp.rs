@@ -0,0 +1,237 @@
+// smtp.rs sends the finished digest out by email
+use crate::{
+    config::{Security, Smtp},
+    errors::*,
+    page::{DigestOutput, Renderer},
+};
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
+};
+use log::*;
+use std::{env::var, path::PathBuf};
+
+// Read when [smtp] has a username but no password, so the password can stay out of Bot.toml
+static PASSWORD_VAR: &str = "AR_BOT_SMTP_PASSWORD";
+
+/// Who gets this digest: its group's recipients if it has any, otherwise everyone under `[smtp]`
+pub fn recipients<'a>(smtp: &'a Smtp, output: &'a DigestOutput) -> &'a [String] {
+    match &output.group {
+        Some(group) if !group.to.is_empty() => &group.to,
+        _ => &smtp.to,
+    }
+}
+
+/// Builds the message: the digest as the body, with any exports attached
+pub fn build_message(smtp: &Smtp, output: &DigestOutput) -> Result<Message> {
+    let recipients = recipients(smtp, output);
+    if recipients.is_empty() {
+        return Err(ErrorKind::Config("No recipients given under [smtp]".into()).into());
+    }
+    let subject = match &output.group {
+        Some(group) => format!("{} - {}", smtp.subject, group.name),
+        None => smtp.subject.clone(),
+    };
+    let mut builder = Message::builder()
+        .from(
+            smtp.from
+                .parse()
+                .chain_err(|| ErrorKind::Config(format!("Bad from address {}", smtp.from)))?,
+        )
+        .subject(subject);
+    for to in recipients {
+        let to = to
+            .parse()
+            .chain_err(|| ErrorKind::Config(format!("Bad recipient address {}", to)))?;
+        builder = builder.to(to);
+    }
+
+    let body_type = match output.renderer {
+        Renderer::Html => ContentType::TEXT_HTML,
+        Renderer::Text | Renderer::Markdown => ContentType::TEXT_PLAIN,
+    };
+    let mut parts = MultiPart::mixed().singlepart(
+        SinglePart::builder()
+            .header(body_type)
+            .body(output.digest.clone()),
+    );
+    for (export, path, contents) in &output.exports {
+        let filename = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .chain_err(|| "Could not read export filename")?;
+        let content_type = ContentType::parse(export.content_type())
+            .chain_err(|| "Bad attachment content type")?;
+        parts =
+            parts.singlepart(Attachment::new(filename.into()).body(contents.clone(), content_type));
+    }
+
+    builder
+        .multipart(parts)
+        .chain_err(|| ErrorKind::Delivery("Could not build digest email".into()))
+}
+
+fn transport(smtp: &Smtp) -> Result<SmtpTransport> {
+    let mut builder = match smtp.security {
+        Security::None => SmtpTransport::builder_dangerous(&smtp.host),
+        Security::Starttls => SmtpTransport::starttls_relay(&smtp.host).chain_err(|| {
+            ErrorKind::Delivery(format!("Could not set up STARTTLS to {}", smtp.host))
+        })?,
+        Security::Tls => SmtpTransport::relay(&smtp.host)
+            .chain_err(|| ErrorKind::Delivery(format!("Could not set up TLS to {}", smtp.host)))?,
+    };
+    if let Some(port) = smtp.port {
+        builder = builder.port(port);
+    }
+    if let Some(username) = &smtp.username {
+        let password = match &smtp.password {
+            Some(p) => p.clone(),
+            None => var(PASSWORD_VAR).chain_err(|| {
+                ErrorKind::Config(format!(
+                    "[smtp] has a username but no password, and {} is not set",
+                    PASSWORD_VAR
+                ))
+            })?,
+        };
+        builder = builder.credentials(Credentials::new(username.clone(), password));
+    }
+    Ok(builder.build())
+}
+
+/// Emails the digest to everyone under `[smtp]`, or its group's recipients
+pub fn send(smtp: &Smtp, output: &DigestOutput) -> Result<()> {
+    let message = build_message(smtp, output)?;
+    info!(
+        "Sending digest to {} via {}",
+        recipients(smtp, output).join(", "),
+        smtp.host
+    );
+    transport(smtp)?
+        .send(&message)
+        .chain_err(|| ErrorKind::Delivery(format!("Could not send digest via {}", smtp.host)))?;
+    Ok(())
+}
+
+/// The email that would have been sent, as an .eml file to keep instead of sending it
+/// It goes next to the digest, as digest-TIMESTAMP.eml
+pub fn dry_run(smtp: &Smtp, output: &DigestOutput) -> Result<(PathBuf, Vec<u8>)> {
+    let message = build_message(smtp, output)?;
+    let eml_path = output.path.with_extension("eml");
+    Ok((eml_path, message.formatted()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProductGroup;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc::channel,
+        thread,
+    };
+
+    fn test_smtp(port: Option<u16>) -> Smtp {
+        Smtp {
+            host: "127.0.0.1".into(),
+            port,
+            security: Security::None,
+            username: None,
+            password: None,
+            from: "AR-Bot <ar-bot@example.org>".into(),
+            to: vec!["checker@example.org".into()],
+            subject: "Digest".into(),
+        }
+    }
+
+    fn test_output() -> DigestOutput {
+        DigestOutput {
+            renderer: Renderer::Text,
+            timestamp: 1_532_000_000,
+            digest: "DIGEST\n".into(),
+            path: PathBuf::from("digest-1532000000.txt"),
+            exports: Vec::new(),
+            group: None,
+        }
+    }
+
+    // Just enough of an SMTP server to take one message and hand back what was in DATA
+    fn smtp_sink() -> (u16, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut data = String::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.to_uppercase().get(..4) {
+                    Some("EHLO") => b"250 sink\r\n",
+                    Some("DATA") => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    Some("QUIT") => {
+                        writer.write_all(b"221 bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).unwrap();
+            }
+            tx.send(data).unwrap();
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn test_send_to_sink() {
+        let (port, rx) = smtp_sink();
+        send(&test_smtp(Some(port)), &test_output()).unwrap();
+        let data = rx.recv().unwrap();
+        assert!(data.contains("To: checker@example.org"));
+        assert!(data.contains("Subject: Digest"));
+        assert!(data.contains("DIGEST"));
+    }
+    #[test]
+    fn test_no_recipients() {
+        let mut smtp = test_smtp(None);
+        smtp.to.clear();
+        assert!(build_message(&smtp, &test_output()).is_err())
+    }
+    #[test]
+    fn test_group_recipients() {
+        let mut output = test_output();
+        output.path = PathBuf::from("digest-1532000000-camp-transportation.txt");
+        output.group = Some(ProductGroup {
+            name: "Camp Transportation".into(),
+            products: Vec::new(),
+            to: vec!["transport@example.org".into()],
+        });
+        let (eml_path, contents) = dry_run(&test_smtp(None), &output).unwrap();
+        let eml = String::from_utf8(contents).unwrap();
+        assert_eq!(
+            eml_path,
+            PathBuf::from("digest-1532000000-camp-transportation.eml")
+        );
+        assert!(eml.contains("To: transport@example.org"));
+        assert!(eml.contains("Subject: Digest - Camp Transportation"));
+    }
+}
@@ -0,0 +1,297 @@
+// template.rs renders digests from user-supplied templates at runtime, using tera
+// The context mirrors what the built-in askama templates see, so a copy of templates/ is a good starting point
+use crate::{
+    batch::{alert_time_order, parse_alert_time, Batch, TimeDetail},
+    errors::*,
+    page::{Annotations, Renderer},
+};
+use chrono::prelude::*;
+use log::*;
+use serde_derive::Serialize;
+use std::{error::Error as StdError, path::Path};
+use tera::Tera;
+
+#[derive(Debug, Serialize)]
+struct DigestContext {
+    entries: Vec<EntryContext>,
+    dates: Dates,
+}
+
+// alerts_vec matches BatchEntry::alerts_vec(): a list of (product, times), read as alert.0 and alert.1
+// alerts is the same list with names, and the raw product code as well
+// note is where the ID was seen in earlier digests, empty if it wasn't - see History::note
+// anomalies are its badges, see anomaly.rs
+#[derive(Debug, Serialize)]
+struct EntryContext {
+    id: u32,
+    alerts_vec: Vec<(String, Vec<String>)>,
+    alerts: Vec<AlertContext>,
+    note: String,
+    anomalies: Vec<AnomalyContext>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnomalyContext {
+    badge: String,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AlertContext {
+    product: String,
+    code: String,
+    times: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Dates {
+    generated: String,
+    first_alert: String,
+    last_alert: String,
+}
+
+impl DigestContext {
+    fn from_batch(batch: &Batch, annotations: &Annotations, detail: TimeDetail) -> Self {
+        let mut entries: Vec<EntryContext> = batch
+            .entries
+            .values()
+            .map(|e| {
+                let e = e.squashed(detail);
+                let alerts_vec: Vec<(String, Vec<String>)> = e
+                    .alerts_vec()
+                    .into_iter()
+                    .map(|(p, ts)| (p.to_string(), ts))
+                    .collect();
+                let alerts = e
+                    .alerts_vec()
+                    .into_iter()
+                    .map(|(p, times)| AlertContext {
+                        product: p.to_string(),
+                        code: p.code().into(),
+                        times,
+                    })
+                    .collect();
+                EntryContext {
+                    id: e.id,
+                    alerts_vec,
+                    alerts,
+                    note: annotations.note(e.id).into(),
+                    anomalies: annotations
+                        .anomalies(e.id)
+                        .iter()
+                        .map(|a| AnomalyContext {
+                            badge: a.badge().into(),
+                            detail: a.to_string(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        entries.sort_by_key(|e| e.id);
+
+        let mut times: Vec<&String> = batch
+            .entries
+            .values()
+            .flat_map(|e| e.alerts.values().flatten())
+            .collect();
+        times.sort_by(|a, b| alert_time_order(parse_alert_time(a), parse_alert_time(b)));
+
+        DigestContext {
+            entries,
+            dates: Dates {
+                generated: Local::now().format("%A, %B %-d, %Y %-I:%M %p").to_string(),
+                first_alert: times.first().map(|t| t.to_string()).unwrap_or_default(),
+                last_alert: times.last().map(|t| t.to_string()).unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// The file in the template dir each renderer looks for
+pub fn template_name(renderer: Renderer) -> String {
+    format!("digest.{}", renderer.extension())
+}
+
+// Loads every template in dir, so digest templates can extend or include the others
+// Only the text extensions, so a logo or anything else sitting beside them isn't read as a template
+fn load(dir: &Path) -> Result<Tera> {
+    if !dir.is_dir() {
+        return Err(
+            ErrorKind::Template(format!("Template directory {:?} does not exist", dir)).into(),
+        );
+    }
+    let glob = dir.join("**").join("*.{html,txt,md}");
+    let glob = glob
+        .to_str()
+        .chain_err(|| ErrorKind::Template("Template directory path is not valid unicode".into()))?;
+    Tera::new(glob).map_err(|e| describe(&e))
+}
+
+pub fn render(
+    dir: &Path,
+    renderer: Renderer,
+    batch: &Batch,
+    annotations: &Annotations,
+    detail: TimeDetail,
+) -> Result<String> {
+    let tera = load(dir)?;
+    let name = template_name(renderer);
+    if !tera.get_template_names().any(|n| n == name) {
+        return Err(
+            ErrorKind::Template(format!("Template directory {:?} has no {}", dir, name)).into(),
+        );
+    }
+    debug!("Rendering digest with {:?}/{}", dir, name);
+    render_loaded(&tera, &name, batch, annotations, detail)
+}
+
+fn render_loaded(
+    tera: &Tera,
+    name: &str,
+    batch: &Batch,
+    annotations: &Annotations,
+    detail: TimeDetail,
+) -> Result<String> {
+    let context =
+        tera::Context::from_serialize(DigestContext::from_batch(batch, annotations, detail))
+            .chain_err(|| ErrorKind::Template("Could not build template context".into()))?;
+    tera.render(name, &context).map_err(|e| describe(&e))
+}
+
+// Tera keeps the useful part (line, column, missing variable) in the source chain
+// Flatten it into one message so it shows up without -vv
+fn describe(e: &tera::Error) -> Error {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        msg.push_str(&format!("\n  {}", s));
+        source = s.source();
+    }
+    ErrorKind::Template(msg.trim().to_string()).into()
+}
+
+/// Renders every digest template found in dir against Batch::sample()
+/// Returns the names checked, or the first failure
+pub fn check(dir: &Path) -> Result<Vec<String>> {
+    let tera = load(dir)?;
+    let sample = Batch::sample();
+    let mut checked = Vec::new();
+    for renderer in &[Renderer::Html, Renderer::Text, Renderer::Markdown] {
+        let name = template_name(*renderer);
+        if tera.get_template_names().any(|n| n == name) {
+            render_loaded(
+                &tera,
+                &name,
+                &sample,
+                &Annotations::default(),
+                TimeDetail::default(),
+            )?;
+            checked.push(name);
+        }
+    }
+    if checked.is_empty() {
+        return Err(ErrorKind::Template(format!(
+            "Template directory {:?} has no digest.html, digest.txt or digest.md",
+            dir
+        ))
+        .into());
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempBrain;
+    use std::fs::write;
+
+    fn temp_template_dir(files: &[(&str, &str)]) -> TempBrain {
+        let dir = TempBrain::new();
+        for (name, contents) in files {
+            write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_render_custom_template() {
+        let dir = temp_template_dir(&[(
+            "digest.txt",
+            "{% for entry in entries %}{{ entry.id }}:{% for alert in entry.alerts_vec %} {{ alert.0 }} x{{ alert.1 | length }}{% endfor %}\n{% endfor %}",
+        )]);
+        let out = render(
+            &dir,
+            Renderer::Text,
+            &Batch::test(),
+            &Annotations::default(),
+            TimeDetail::default(),
+        )
+        .unwrap();
+        assert_eq!(out, "12345: Non-builtin product COOL_PROD x1\n")
+    }
+    #[test]
+    fn test_render_unparsed_time() {
+        let dir = temp_template_dir(&[(
+            "digest.txt",
+            "{{ dates.first_alert }} - {{ dates.last_alert }}",
+        )]);
+        let mut batch = Batch::test();
+        for be in batch.entries.values_mut() {
+            for times in be.alerts.values_mut() {
+                times.insert(0, "sometime last week".into());
+                times.push("Friday, July 20, 2018 9:00 AM".into());
+            }
+        }
+        let out = render(
+            &dir,
+            Renderer::Text,
+            &batch,
+            &Annotations::default(),
+            TimeDetail::All,
+        )
+        .unwrap();
+        // The one that didn't parse goes last, as it does in the built-in digests
+        assert_eq!(out, "Friday, July 20, 2018 9:00 AM - sometime last week")
+    }
+    #[test]
+    fn test_render_missing_template() {
+        let dir = temp_template_dir(&[("digest.md", "# Digest")]);
+        let res = render(
+            &dir,
+            Renderer::Html,
+            &Batch::test(),
+            &Annotations::default(),
+            TimeDetail::default(),
+        );
+        assert!(res.is_err())
+    }
+    #[test]
+    fn test_check_extends() {
+        let dir = temp_template_dir(&[
+            ("skel.html", "<body>{% block content %}{% endblock %}</body>"),
+            (
+                "digest.html",
+                "{% extends \"skel.html\" %}{% block content %}{% for entry in entries %}{{ entry.id }}{% endfor %} {{ dates.first_alert }}{% endblock %}",
+            ),
+        ]);
+        let res = check(&dir);
+        assert_eq!(res.unwrap(), vec!["digest.html".to_string()])
+    }
+    #[test]
+    fn test_check_skips_other_files() {
+        let dir = temp_template_dir(&[("digest.html", "<img src=\"logo.png\">")]);
+        std::fs::write(
+            dir.join("logo.png"),
+            [0x89, b'P', b'N', b'G', 0xff, 0xfe, 0x00],
+        )
+        .unwrap();
+        let res = check(&dir);
+        assert_eq!(res.unwrap(), vec!["digest.html".to_string()])
+    }
+    #[test]
+    fn test_check_unknown_variable() {
+        let dir = temp_template_dir(&[("digest.html", "{{ entry.nickname }}")]);
+        let res = check(&dir);
+        assert!(res.is_err())
+    }
+}
@@ -0,0 +1,61 @@
+// test_util.rs holds fixtures shared by the lib's tests, the binary's tests and tests/
+// Only built for tests - the test-util feature, switched on from [dev-dependencies], lets the other two see it
+use crate::{brain::Context, config::Config};
+use std::{
+    env::temp_dir,
+    fs::{create_dir_all, remove_dir_all},
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// A directory of its own under the temp dir for one test - a brain, or just somewhere to write files
+/// It's removed again when dropped, so a failed assertion doesn't leave it behind
+#[derive(Debug)]
+pub struct TempBrain(PathBuf);
+
+impl TempBrain {
+    pub fn new() -> Self {
+        let path = temp_dir().join(format!("ar-bot-test-{}", Uuid::new_v4()));
+        create_dir_all(&path).unwrap();
+        TempBrain(path)
+    }
+
+    /// The default config, pointed at this brain
+    pub fn config(&self) -> Config {
+        let mut config = Config::default();
+        config.directory.path = self.0.clone();
+        config
+    }
+
+    /// Locks the brain and reads it in - declare it after the TempBrain, so it's dropped first
+    pub fn context(&self) -> Context {
+        Context::initialize(self.config()).unwrap()
+    }
+}
+
+impl Default for TempBrain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for TempBrain {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempBrain {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempBrain {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,465 @@
+// tui.rs is a full-screen browser for the pending batch, for when preview scrolls off the screen
+// Browser holds all the state and answers keys, so it can be tested without a terminal
+use crate::{
+    batch::{Entry, Product, UserID},
+    brain::Context,
+    email::Email,
+    errors::*,
+    parser::Registry,
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line as TextLine,
+    widgets::{Block, Clear, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::collections::BTreeSet;
+
+static HELP: &str =
+    " ↑↓ move  ⏎ expand  r raw email  f filter by product  h hold  d cut digest  q quit";
+static RAW_HELP: &str = " ↑↓ scroll  esc back";
+
+/// How the browser was left
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Quit,
+    // Cut a digest, leaving out everything held
+    Digest,
+}
+
+// One alert, and the email it came from
+#[derive(Debug)]
+struct Alert {
+    product: Product,
+    time: String,
+    email: usize,
+}
+
+// Everything pending for one iMIS ID, sorted by product and then time
+#[derive(Debug)]
+struct Group {
+    id: UserID,
+    alerts: Vec<Alert>,
+}
+
+// One line on screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Line {
+    Entry(usize),
+    Alert(usize, usize),
+}
+
+/// The batch as the browser shows it: which entries are open, held back, or filtered out
+#[derive(Debug)]
+pub struct Browser {
+    emails: Vec<Email>,
+    groups: Vec<Group>,
+    products: Vec<Product>,
+    expanded: BTreeSet<UserID>,
+    /// IDs to leave out of the digest, pending for the next one
+    pub held: BTreeSet<UserID>,
+    filter: Option<Product>,
+    selected: usize,
+    // The email open in the raw view, and how far it's scrolled
+    raw: Option<(usize, u16)>,
+    // Messages no parser recognized
+    unmatched: usize,
+}
+
+impl Browser {
+    /// Reads every alert out of the brain
+    pub fn new(ctx: &Context) -> Self {
+        let registry = Registry::default();
+        let mut alerts: Vec<(Entry, usize)> = Vec::new();
+        let mut unmatched = 0;
+        for (i, email) in ctx.brain.emails.iter().enumerate() {
+            match registry.parse(email) {
+                Ok(entry) => alerts.push((entry, i)),
+                Err(_) => unmatched += 1,
+            }
+        }
+        alerts.sort_by(|(a, _), (b, _)| (a.id, &a.product).cmp(&(b.id, &b.product)));
+
+        let mut groups: Vec<Group> = Vec::new();
+        for (entry, email) in alerts {
+            let alert = Alert {
+                product: entry.product,
+                time: entry.time,
+                email,
+            };
+            match groups.last_mut() {
+                Some(g) if g.id == entry.id => g.alerts.push(alert),
+                _ => groups.push(Group {
+                    id: entry.id,
+                    alerts: vec![alert],
+                }),
+            }
+        }
+        let products: BTreeSet<Product> = groups
+            .iter()
+            .flat_map(|g| g.alerts.iter().map(|a| a.product.clone()))
+            .collect();
+
+        Browser {
+            emails: ctx.brain.emails.clone(),
+            groups,
+            products: products.into_iter().collect(),
+            expanded: BTreeSet::new(),
+            held: BTreeSet::new(),
+            filter: None,
+            selected: 0,
+            raw: None,
+            unmatched,
+        }
+    }
+
+    fn shows(&self, alert: &Alert) -> bool {
+        self.filter.as_ref().is_none_or(|p| alert.product == *p)
+    }
+
+    // Every line on screen, top to bottom
+    fn lines(&self) -> Vec<Line> {
+        let mut ret = Vec::new();
+        for (g, group) in self.groups.iter().enumerate() {
+            if !group.alerts.iter().any(|a| self.shows(a)) {
+                continue;
+            }
+            ret.push(Line::Entry(g));
+            if self.expanded.contains(&group.id) {
+                for (a, alert) in group.alerts.iter().enumerate() {
+                    if self.shows(alert) {
+                        ret.push(Line::Alert(g, a));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn current(&self) -> Option<Line> {
+        self.lines().get(self.selected).copied()
+    }
+
+    fn current_group(&self) -> Option<usize> {
+        match self.current()? {
+            Line::Entry(g) | Line::Alert(g, _) => Some(g),
+        }
+    }
+
+    fn select_entry(&mut self, g: usize) {
+        self.selected = self
+            .lines()
+            .iter()
+            .position(|l| *l == Line::Entry(g))
+            .unwrap_or(0);
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        let len = self.lines().len();
+        if len > 0 {
+            self.selected = (self.selected as isize + delta).clamp(0, len as isize - 1) as usize;
+        }
+    }
+
+    /// Answers one key press - Some once the browser should close
+    pub fn handle(&mut self, key: KeyCode) -> Option<Outcome> {
+        if let Some((email, scroll)) = self.raw {
+            match key {
+                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('r') => self.raw = None,
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.raw = Some((email, scroll.saturating_sub(1)))
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    self.raw = Some((email, scroll.saturating_add(1)))
+                }
+                _ => {}
+            }
+            return None;
+        }
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Outcome::Quit),
+            KeyCode::Char('d') => return Some(Outcome::Digest),
+            KeyCode::Up | KeyCode::Char('k') => self.move_by(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-20),
+            KeyCode::PageDown => self.move_by(20),
+            KeyCode::Home => self.selected = 0,
+            KeyCode::End => self.move_by(isize::MAX / 2),
+            KeyCode::Enter | KeyCode::Char(' ') => {
+                if let Some(g) = self.current_group() {
+                    let id = self.groups[g].id;
+                    if !self.expanded.remove(&id) {
+                        self.expanded.insert(id);
+                    }
+                    self.select_entry(g);
+                }
+            }
+            KeyCode::Right | KeyCode::Char('l') => {
+                if let Some(g) = self.current_group() {
+                    self.expanded.insert(self.groups[g].id);
+                }
+            }
+            KeyCode::Left => {
+                if let Some(g) = self.current_group() {
+                    self.expanded.remove(&self.groups[g].id);
+                    self.select_entry(g);
+                }
+            }
+            KeyCode::Char('h') => {
+                if let Some(g) = self.current_group() {
+                    let id = self.groups[g].id;
+                    if !self.held.remove(&id) {
+                        self.held.insert(id);
+                    }
+                }
+            }
+            KeyCode::Char('r') => {
+                // On an entry, the first alert under it that isn't filtered out
+                let email = match self.current() {
+                    Some(Line::Alert(g, a)) => Some(self.groups[g].alerts[a].email),
+                    Some(Line::Entry(g)) => self.groups[g]
+                        .alerts
+                        .iter()
+                        .find(|a| self.shows(a))
+                        .map(|a| a.email),
+                    None => None,
+                };
+                self.raw = email.map(|e| (e, 0));
+            }
+            KeyCode::Char('f') => {
+                // All products, then each in turn
+                let next = match &self.filter {
+                    None => self.products.first(),
+                    Some(p) => self.products.iter().skip_while(|q| *q != p).nth(1),
+                };
+                let group = self.current_group();
+                self.filter = next.cloned();
+                match group {
+                    Some(g) if self.lines().contains(&Line::Entry(g)) => self.select_entry(g),
+                    _ => self.selected = 0,
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn title(&self) -> String {
+        let alerts: usize = self.groups.iter().map(|g| g.alerts.len()).sum();
+        let mut ret = format!(
+            " ar-bot - {} IDs, {} alerts pending",
+            self.groups.len(),
+            alerts
+        );
+        if !self.held.is_empty() {
+            ret.push_str(&format!(", {} held", self.held.len()));
+        }
+        if self.unmatched > 0 {
+            ret.push_str(&format!(", {} messages that aren't alerts", self.unmatched));
+        }
+        if let Some(p) = &self.filter {
+            ret.push_str(&format!("  [showing {} only - f for the next]", p.code()));
+        }
+        ret
+    }
+
+    fn line_text(&self, line: Line) -> String {
+        match line {
+            Line::Entry(g) => {
+                let group = &self.groups[g];
+                let shown: Vec<&Alert> = group.alerts.iter().filter(|a| self.shows(a)).collect();
+                let products: BTreeSet<&Product> = shown.iter().map(|a| &a.product).collect();
+                format!(
+                    "{} {:<10} {} products, {} alerts{}",
+                    if self.expanded.contains(&group.id) {
+                        "▾"
+                    } else {
+                        "▸"
+                    },
+                    group.id,
+                    products.len(),
+                    shown.len(),
+                    if self.held.contains(&group.id) {
+                        "  HELD"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            Line::Alert(g, a) => {
+                let alert = &self.groups[g].alerts[a];
+                format!(
+                    "    {:<16} {:<30} {}",
+                    alert.product.code(),
+                    alert.product,
+                    alert.time
+                )
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, list_state: &mut ListState) {
+        let [top, main, bottom] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        frame.render_widget(Paragraph::new(self.title()).reversed(), top);
+
+        let items: Vec<ListItem> = self
+            .lines()
+            .into_iter()
+            .map(|line| {
+                let item = ListItem::new(self.line_text(line));
+                match line {
+                    Line::Entry(g) if self.held.contains(&self.groups[g].id) => item.dim(),
+                    Line::Entry(_) => item.bold(),
+                    Line::Alert(..) => item,
+                }
+            })
+            .collect();
+        list_state.select(Some(self.selected));
+        let list = List::new(items)
+            .block(Block::bordered())
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, main, list_state);
+
+        let help = if self.raw.is_some() { RAW_HELP } else { HELP };
+        frame.render_widget(Paragraph::new(help).dim(), bottom);
+
+        if let Some((i, scroll)) = self.raw {
+            let email = &self.emails[i];
+            let area = popup(main);
+            let raw = Paragraph::new(email.contents.replace('\r', ""))
+                .block(Block::bordered().title(TextLine::from(format!(" {} ", email.filename))))
+                .wrap(Wrap { trim: false })
+                .scroll((scroll, 0));
+            frame.render_widget(Clear, area);
+            frame.render_widget(raw, area);
+        }
+    }
+}
+
+// Most of the area, centered
+fn popup(area: Rect) -> Rect {
+    let [_, middle, _] = Layout::vertical([
+        Constraint::Percentage(10),
+        Constraint::Percentage(80),
+        Constraint::Percentage(10),
+    ])
+    .areas(area);
+    let [_, center, _] = Layout::horizontal([
+        Constraint::Percentage(10),
+        Constraint::Percentage(80),
+        Constraint::Percentage(10),
+    ])
+    .areas(middle);
+    center
+}
+
+/// Runs the browser full-screen until it's closed
+/// Returns what to do next, and the IDs held back
+pub fn browse(ctx: &Context) -> Result<(Outcome, BTreeSet<UserID>)> {
+    let mut browser = Browser::new(ctx);
+    let mut terminal = ratatui::try_init().chain_err(|| "Could not start the terminal UI")?;
+    let outcome = run(&mut browser, &mut terminal);
+    ratatui::try_restore().chain_err(|| "Could not restore the terminal")?;
+    Ok((outcome?, browser.held))
+}
+
+fn run(browser: &mut Browser, terminal: &mut DefaultTerminal) -> Result<Outcome> {
+    let mut list_state = ListState::default();
+    loop {
+        terminal
+            .draw(|frame| browser.draw(frame, &mut list_state))
+            .chain_err(|| "Could not draw the terminal UI")?;
+        if let Event::Key(key) = event::read().chain_err(|| "Could not read from the terminal")? {
+            if key.kind == KeyEventKind::Press {
+                if let Some(outcome) = browser.handle(key.code) {
+                    return Ok(outcome);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::TempBrain, util::*};
+    use ratatui::{backend::TestBackend, Terminal};
+    use std::{fs::write, str::FromStr};
+
+    fn test_browser() -> Browser {
+        let dir = TempBrain::new();
+        let mut ctx = dir.context();
+        for (name, alert) in &[
+            ("a.txt", TEST_COOL_STR),
+            ("b.txt", TEST_DIF_PROD),
+            ("c.txt", TEST_DIF_BOTH),
+        ] {
+            write(dir.join(name), &Email::from_str(alert).unwrap().contents).unwrap();
+        }
+        ctx.read_fs().unwrap();
+        Browser::new(&ctx)
+    }
+
+    #[test]
+    fn test_browse_keys() {
+        let mut browser = test_browser();
+        assert_eq!(browser.lines(), vec![Line::Entry(0), Line::Entry(1)]);
+
+        // Opening 12345 shows both its products
+        browser.handle(KeyCode::Enter);
+        assert_eq!(
+            browser.lines(),
+            vec![
+                Line::Entry(0),
+                Line::Alert(0, 0),
+                Line::Alert(0, 1),
+                Line::Entry(1)
+            ]
+        );
+        browser.handle(KeyCode::Down);
+        browser.handle(KeyCode::Char('r'));
+        let (email, _) = browser.raw.unwrap();
+        assert_eq!(browser.emails[email].filename, "a.txt");
+        assert_eq!(browser.handle(KeyCode::Char('q')), None);
+        assert_eq!(browser.raw, None);
+
+        // COL_PROD, COOL_PROD, COOL_PROOD - the second leaves just one alert for 12345
+        browser.handle(KeyCode::Char('f'));
+        browser.handle(KeyCode::Char('f'));
+        assert_eq!(browser.lines(), vec![Line::Entry(0), Line::Alert(0, 0)]);
+        browser.handle(KeyCode::Char('f'));
+        browser.handle(KeyCode::Char('f'));
+        assert_eq!(browser.filter, None);
+
+        browser.handle(KeyCode::End);
+        browser.handle(KeyCode::Char('h'));
+        assert_eq!(browser.held, vec![22345].into_iter().collect());
+        assert_eq!(browser.handle(KeyCode::Char('d')), Some(Outcome::Digest));
+    }
+    #[test]
+    fn test_browse_draw() {
+        let mut browser = test_browser();
+        browser.handle(KeyCode::Char('h'));
+        let mut terminal = Terminal::new(TestBackend::new(80, 8)).unwrap();
+        terminal
+            .draw(|frame| browser.draw(frame, &mut ListState::default()))
+            .unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|c| c.symbol())
+            .collect();
+        assert!(screen.contains("2 IDs, 3 alerts pending, 1 held"));
+        assert!(screen.contains("12345      2 products, 2 alerts  HELD"));
+    }
+}
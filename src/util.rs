@@ -1,21 +1,26 @@
 // util.rs holds, well, utility functions, as well as things I didn't know where else to put
 use crate::errors::*;
-use std::io::{prelude::*, BufReader};
+use std::{
+    fs::{remove_file, rename, File},
+    io::{self, prelude::*, BufReader},
+    path::Path,
+};
 
 #[cfg(test)]
-pub static TEST_COOL_STR: &'static str = "The Cool Invoice For iMIS ID 12345 For the Product COOL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
+pub static TEST_COOL_STR: &str = "The Cool Invoice For iMIS ID 12345 For the Product COOL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
 #[cfg(test)]
-pub static TEST_DIF_ID: &'static str = "The Cool Invoice For iMIS ID 12346 For the Product COOL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
+pub static TEST_DIF_ID: &str = "The Cool Invoice For iMIS ID 12346 For the Product COOL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
 #[cfg(test)]
-pub static TEST_DIF_PROD: &'static str = "The Cool Invoice For iMIS ID 12345 For the Product COOL_PROOD Has Changed\r\nYou need to verify the Autodraft is now correct";
+pub static TEST_DIF_PROD: &str = "The Cool Invoice For iMIS ID 12345 For the Product COOL_PROOD Has Changed\r\nYou need to verify the Autodraft is now correct";
 #[cfg(test)]
-pub static TEST_DIF_BOTH: &'static str = "The Cool Invoice For iMIS ID 22345 For the Product COL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
+pub static TEST_DIF_BOTH: &str = "The Cool Invoice For iMIS ID 22345 For the Product COL_PROD Has Changed\r\nYou need to verify the Autodraft is now correct";
 
-//pub static DATE_OUT_FMT: &'static str = "%e %a %m/d %Y";
+//pub static DATE_OUT_FMT: &str = "%e %a %m/d %Y";
 //from http://man7.org/linux/man-pages/man3/strftime.3.html
 // turns out there's a stdlib fn, leaving for reference
-//static RFC_2822_FMT: &'static str = "%a, %d %b %Y %T %z";
+//static RFC_2822_FMT: &str = "%a, %d %b %Y %T %z";
 
+/// Reads a whole file into a String
 pub fn file_contents_from_str_path(s: &str) -> Result<String> {
     use std::{fs::File, path::Path};
 
@@ -27,3 +32,32 @@ pub fn file_contents_from_str_path(s: &str) -> Result<String> {
 
     Ok(input)
 }
+
+/// Writes a file all at once: to a hidden temp file next to it, synced to disk, then renamed into place
+/// Anything reading path sees the old file or the new one, never half of it
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let temp = path.with_file_name(format!(".{}.tmp", name));
+    let written = File::create(&temp)
+        .and_then(|mut f| {
+            f.write_all(contents)?;
+            f.sync_all()
+        })
+        .and_then(|_| rename(&temp, path));
+    if written.is_err() {
+        let _ = remove_file(&temp);
+    }
+    written
+}
+
+/// Makes new files and renames in a directory stick, where the OS lets a directory be synced
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(path)?.sync_all()
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,469 @@
+// validate.rs finds everything wrong with a config, and where it was written
+use crate::{
+    batch::Product,
+    config::{Auto, Config, Digest, Directory, Imap, Origin, Security, Smtp},
+};
+use chrono::{NaiveTime, Weekday};
+use serde_derive::Serialize;
+use std::{collections::BTreeMap, fmt, path::Path, str::FromStr};
+use toml_edit::{ImDocument, TableLike};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    // Loads, but probably isn't what was meant
+    Warning,
+    // Won't load
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// One problem with the config, and where it came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // The file, variable or --set it came from - None for something that's missing everywhere
+    #[serde(serialize_with = "origin_string")]
+    pub origin: Option<Origin>,
+    // 1-based, in the file
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // Dotted key, like digest.render
+    pub key: Option<String>,
+    pub message: String,
+}
+
+fn origin_string<S: serde::Serializer>(origin: &Option<Origin>, s: S) -> Result<S::Ok, S::Error> {
+    match origin {
+        Some(o) => s.serialize_some(&o.to_string()),
+        None => s.serialize_none(),
+    }
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// Bot.toml:3:1: error: ... for files, like a compiler would
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.origin, self.line, self.column) {
+            (Some(Origin::System(p)), Some(l), Some(c))
+            | (Some(Origin::User(p)), Some(l), Some(c))
+            | (Some(Origin::Project(p)), Some(l), Some(c)) => {
+                write!(f, "{}:{}:{}: ", p.display(), l, c)?
+            }
+            (Some(Origin::System(p)), ..)
+            | (Some(Origin::User(p)), ..)
+            | (Some(Origin::Project(p)), ..) => write!(f, "{}: ", p.display())?,
+            (Some(Origin::Default), ..) | (None, ..) => {}
+            (Some(o), ..) => write!(f, "{}: ", o)?,
+        }
+        match &self.key {
+            Some(key) => write!(f, "{}: {}: {}", self.severity, key, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// Line and column of every key in one config file, tables included
+#[derive(Debug, Default)]
+pub struct Spans(BTreeMap<String, (usize, usize)>);
+
+impl Spans {
+    /// Reads the positions out of a config file's text
+    /// A syntax error comes back as its message with the line and column it's at
+    pub fn parse(text: &str) -> std::result::Result<Self, (String, Option<(usize, usize)>)> {
+        let doc = ImDocument::parse(text).map_err(|e| {
+            let at = e.span().map(|s| line_col(text, s.start));
+            (e.message().trim().to_string(), at)
+        })?;
+        let mut spans = BTreeMap::new();
+        index(text, "", doc.as_table(), &mut spans);
+        Ok(Spans(spans))
+    }
+
+    /// Where a key was written, or failing that the closest table around it
+    pub fn find(&self, key: &str) -> Option<(usize, usize)> {
+        let mut key = key;
+        loop {
+            if let Some(at) = self.0.get(key) {
+                return Some(*at);
+            }
+            key = &key[..key.rfind('.')?];
+        }
+    }
+}
+
+fn index(
+    text: &str,
+    prefix: &str,
+    table: &dyn TableLike,
+    spans: &mut BTreeMap<String, (usize, usize)>,
+) {
+    for (name, _) in table.iter() {
+        let (key, item) = match table.get_key_value(name) {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let dotted = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        if let Some(span) = key.span() {
+            spans.insert(dotted.clone(), line_col(text, span.start));
+        }
+        if let Some(t) = item.as_table_like() {
+            index(text, &dotted, t, spans);
+        }
+    }
+}
+
+fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+/// A config with every optional setting filled in
+/// Stands in for values that didn't load, so one mistake doesn't hide the ones after it
+pub fn full_config() -> Config {
+    Config {
+        config_path: None,
+        profile: None,
+        directory: Directory {
+            compressed: false,
+            path: "brain".into(),
+            stale_lock_minutes: 60,
+        },
+        digest: Digest {
+            render: Default::default(),
+            template_dir: Some("templates".into()),
+            times: Default::default(),
+            split: false,
+            groups: Vec::new(),
+        },
+        smtp: Some(Smtp {
+            host: "localhost".into(),
+            port: Some(25),
+            security: Security::Starttls,
+            username: Some("ar-bot".into()),
+            password: Some("password".into()),
+            from: "ar-bot@localhost".into(),
+            to: vec!["ar@localhost".into()],
+            subject: "AR-Bot Digest".into(),
+        }),
+        imap: Some(Imap {
+            host: "localhost".into(),
+            port: Some(993),
+            security: Security::Tls,
+            username: "ar-bot".into(),
+            password: Some("password".into()),
+            folder: "INBOX".into(),
+            processed_folder: Some("Processed".into()),
+        }),
+        auto: Some(Auto {
+            min_ids: Some(1),
+            min_alerts: Some(1),
+            max_age_hours: Some(1),
+            days: vec!["mon".into()],
+            not_before: Some("09:00".into()),
+            send: false,
+            exports: Vec::new(),
+            interval_minutes: 15,
+        }),
+        anomalies: Default::default(),
+    }
+}
+
+/// Everything that loads but won't work: missing paths, settings that contradict each other, bad values
+/// Each comes back as the dotted key it's about, and what's wrong with it
+pub fn semantics(config: &Config) -> Vec<(String, Severity, String)> {
+    use self::Severity::*;
+    let mut ret = Vec::new();
+    let mut problem =
+        |key: &str, severity, message: String| ret.push((key.to_string(), severity, message));
+
+    let brain = &config.directory.path;
+    if brain.exists() && !brain.is_dir() {
+        problem(
+            "directory.path",
+            Error,
+            format!("{} is not a directory", brain.display()),
+        );
+    } else if !brain.exists() {
+        let parent = brain
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !parent.is_dir() {
+            problem(
+                "directory.path",
+                Error,
+                format!(
+                    "{} doesn't exist, and can't be created because {} doesn't either",
+                    brain.display(),
+                    parent.display()
+                ),
+            );
+        }
+    }
+    if config.directory.stale_lock_minutes == 0 {
+        problem(
+            "directory.stale_lock_minutes",
+            Error,
+            "has to be at least 1".into(),
+        );
+    }
+    if let Some(dir) = &config.digest.template_dir {
+        if !dir.is_dir() {
+            problem(
+                "digest.template_dir",
+                Error,
+                format!("{} is not a directory", dir.display()),
+            );
+        }
+    }
+    if config.anomalies.repeat_alerts > 0 && config.anomalies.repeat_window_hours <= 0 {
+        problem(
+            "anomalies.repeat_window_hours",
+            Error,
+            "has to be at least 1, or set repeat_alerts to 0".into(),
+        );
+    }
+    if config.digest.split && config.digest.groups.is_empty() {
+        problem(
+            "digest.split",
+            Warning,
+            "there are no [[digest.groups]] to split by".into(),
+        );
+    }
+    let mut slugs = BTreeMap::new();
+    let mut grouped = BTreeMap::new();
+    for group in &config.digest.groups {
+        let slug = group.slug();
+        if slug.is_empty() {
+            problem(
+                "digest.groups",
+                Error,
+                format!(
+                    "\"{}\" has no letters or digits to name its file by",
+                    group.name
+                ),
+            );
+        } else if let Some(other) = slugs.insert(slug.clone(), &group.name) {
+            problem(
+                "digest.groups",
+                Error,
+                format!(
+                    "\"{}\" and \"{}\" would both be written to digest-TIMESTAMP-{}",
+                    other, group.name, slug
+                ),
+            );
+        }
+        if group.products.is_empty() {
+            problem(
+                "digest.groups",
+                Warning,
+                format!(
+                    "\"{}\" has no products, so its digest is never cut",
+                    group.name
+                ),
+            );
+        }
+        for product in &group.products {
+            if let Product::Other(code) = product {
+                problem(
+                    "digest.groups",
+                    Warning,
+                    format!(
+                        "\"{}\" lists {}, which isn't a known product code",
+                        group.name, code
+                    ),
+                );
+            }
+            if let Some(other) = grouped.insert(product.clone(), &group.name) {
+                problem(
+                    "digest.groups",
+                    Error,
+                    format!(
+                        "{} is in both \"{}\" and \"{}\" - it can only go in one digest",
+                        product.code(),
+                        other,
+                        group.name
+                    ),
+                );
+            }
+        }
+    }
+
+    if let Some(smtp) = &config.smtp {
+        if smtp.to.is_empty() {
+            problem(
+                "smtp.to",
+                Error,
+                "no recipients - the digest would go nowhere".into(),
+            );
+        }
+        if smtp.password.is_some() && smtp.username.is_none() {
+            problem(
+                "smtp.password",
+                Warning,
+                "a password without a username is never used".into(),
+            );
+        }
+        match (smtp.security, smtp.port) {
+            (Security::Tls, Some(p)) if p == 25 || p == 587 => problem(
+                "smtp.port",
+                Warning,
+                format!(
+                    "port {} normally uses security = \"starttls\", not \"tls\"",
+                    p
+                ),
+            ),
+            (Security::Starttls, Some(465)) => problem(
+                "smtp.port",
+                Warning,
+                "port 465 normally uses security = \"tls\", not \"starttls\"".into(),
+            ),
+            _ => {}
+        }
+    }
+
+    if let Some(imap) = &config.imap {
+        if imap.processed_folder.as_ref() == Some(&imap.folder) {
+            problem(
+                "imap.processed_folder",
+                Error,
+                format!(
+                    "is the same as folder ({}) - alerts would be fetched again after every digest",
+                    imap.folder
+                ),
+            );
+        }
+    }
+
+    if let Some(auto) = &config.auto {
+        if auto.min_ids.is_none() && auto.min_alerts.is_none() && auto.max_age_hours.is_none() {
+            problem(
+                "auto",
+                Warning,
+                "no thresholds set - `ar-bot auto` will never cut a digest".into(),
+            );
+        }
+        for day in &auto.days {
+            if Weekday::from_str(day).is_err() {
+                problem(
+                    "auto.days",
+                    Error,
+                    format!("{:?} is not a day of the week", day),
+                );
+            }
+        }
+        if let Some(t) = &auto.not_before {
+            if NaiveTime::parse_from_str(t, "%H:%M").is_err() {
+                problem(
+                    "auto.not_before",
+                    Error,
+                    format!("should look like 09:00, not {:?}", t),
+                );
+            }
+        }
+        if auto.interval_minutes == 0 {
+            problem(
+                "auto.interval_minutes",
+                Error,
+                "has to be at least 1".into(),
+            );
+        }
+        if auto.send && config.smtp.is_none() {
+            problem(
+                "auto.send",
+                Error,
+                "send = true needs an [smtp] section to send with".into(),
+            );
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProductGroup;
+
+    #[test]
+    fn test_spans() {
+        let spans = Spans::parse("# comment\n[digest]\n  render = \"text\"\n").unwrap();
+        assert_eq!(spans.find("digest"), Some((2, 2)));
+        assert_eq!(spans.find("digest.render"), Some((3, 3)));
+        assert_eq!(spans.find("digest.template_dir"), Some((2, 2)));
+        assert_eq!(spans.find("smtp.host"), None);
+
+        let (message, at) = Spans::parse("[digest]\nrender = \n").unwrap_err();
+        assert!(!message.is_empty());
+        assert_eq!(at, Some((2, 10)));
+    }
+    #[test]
+    fn test_semantics() {
+        let mut config = full_config();
+        config.directory.path = "/no/such/dir/brain".into();
+        config.digest.template_dir = None;
+        if let Some(auto) = config.auto.as_mut() {
+            auto.days = vec!["mon".into(), "someday".into()];
+            auto.send = true;
+        }
+        config.smtp = None;
+        let keys: Vec<String> = semantics(&config).into_iter().map(|(k, _, _)| k).collect();
+        assert_eq!(keys, vec!["directory.path", "auto.days", "auto.send"]);
+
+        let mut config = full_config();
+        config.directory.path = std::env::temp_dir();
+        config.digest.template_dir = None;
+        assert!(semantics(&config).is_empty());
+    }
+    #[test]
+    fn test_semantics_groups() {
+        let mut config = full_config();
+        config.directory.path = std::env::temp_dir();
+        config.digest.template_dir = None;
+        let group = |name: &str, products: &[&str]| ProductGroup {
+            name: name.into(),
+            products: products
+                .iter()
+                .map(|p| Product::from_str(p).unwrap())
+                .collect(),
+            to: Vec::new(),
+        };
+        config.digest.groups = vec![
+            group(
+                "Camp Transportation",
+                &["CAMP_KALE_TRANS", "CAMP_KING_TRANS"],
+            ),
+            group("camp transportation!", &["CAMP_KING_TRANS", "CAMP_BUS"]),
+        ];
+        let messages: Vec<String> = semantics(&config).into_iter().map(|(_, _, m)| m).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "\"Camp Transportation\" and \"camp transportation!\" would both be written to \
+                 digest-TIMESTAMP-camp-transportation",
+                "CAMP_KING_TRANS is in both \"Camp Transportation\" and \"camp transportation!\" - \
+                 it can only go in one digest",
+                "\"camp transportation!\" lists CAMP_BUS, which isn't a known product code",
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// watch.rs keeps the batch up to date as new files land in the brain
+use crate::{
+    batch::{Batch, EntryClass},
+    brain::{is_input, read_email_file, Context},
+    errors::*,
+    parser::Registry,
+};
+use log::*;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_dir,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+// Files are often written in a few goes - wait until one has been quiet this long before reading it
+static QUIET: Duration = Duration::from_millis(500);
+
+/// One alert read out of a new file, and what the batch made of it
+#[derive(Debug, PartialEq)]
+pub struct Added {
+    pub entry: String,
+    pub class: EntryClass,
+}
+
+/// The batch so far, and which files went into it
+pub struct Ingest {
+    pub batch: Batch,
+    registry: Registry,
+    seen: HashSet<PathBuf>,
+    hx_path: PathBuf,
+}
+
+impl Ingest {
+    /// Starts from the batch already in the brain - those files are never read twice
+    pub fn new(ctx: &Context) -> Result<Self> {
+        let mut seen = HashSet::new();
+        for f in read_dir(&ctx.config.directory.path).chain_err(|| "Could not read brain!")? {
+            seen.insert(f.chain_err(|| "Could not read brain entry")?.path());
+        }
+        Ok(Ingest {
+            batch: Batch::from_brain(&ctx.brain)?,
+            registry: Registry::default(),
+            seen,
+            hx_path: ctx.hx_path(),
+        })
+    }
+
+    /// Reads a new file into the batch
+    /// Files we've already read, directories and anything in hx/ are skipped
+    pub fn ingest(&mut self, path: &Path) -> Result<Vec<Added>> {
+        if !path.is_file() || path.starts_with(&self.hx_path) || !is_input(path) {
+            return Ok(Vec::new());
+        }
+        if !self.seen.insert(path.to_path_buf()) {
+            info!(
+                "{:?} changed after it was read - rerun preview to pick up edits",
+                path
+            );
+            return Ok(Vec::new());
+        }
+
+        let mut added = Vec::new();
+        for email in read_email_file(path)? {
+            match self.registry.parse(&email) {
+                Ok(entry) => {
+                    let summary = entry.to_string();
+                    let class = self.batch.add_entry(entry)?;
+                    added.push(Added {
+                        entry: summary,
+                        class,
+                    });
+                }
+                Err(e) => warn!("SKIP: {}", e),
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Runs until killed, printing each alert as it's added to the batch
+pub fn watch(ctx: &Context) -> Result<()> {
+    let brain_path = &ctx.config.directory.path;
+    let (tx, rx) = channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).chain_err(|| "Could not start file watcher")?;
+    watcher
+        .watch(brain_path, RecursiveMode::NonRecursive)
+        .chain_err(|| format!("Could not watch {:?}", brain_path))?;
+
+    let mut ingest = Ingest::new(ctx)?;
+    println!(
+        "Watching {:?} - {} IDs in the batch so far.  Ctrl-C to stop.\n",
+        brain_path,
+        ingest.batch.entries.len()
+    );
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        match rx.recv_timeout(QUIET) {
+            Ok(Ok(event)) => {
+                if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
+                    for p in event.paths {
+                        pending.insert(p, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => warn!("File watcher: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err("File watcher stopped".into()),
+        }
+
+        let ready: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= QUIET)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for p in ready {
+            pending.remove(&p);
+            match ingest.ingest(&p) {
+                Ok(added) => {
+                    for a in added {
+                        println!("ADDED {} - {}", a.entry, a.class);
+                    }
+                }
+                Err(e) => warn!("Could not read {:?}: {}", p, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch::Product, email::Email, test_util::TempBrain, util::*};
+    use std::{fs::write, str::FromStr};
+
+    #[test]
+    fn test_ingest_classifies() {
+        let dir = TempBrain::new();
+        let ctx = dir.context();
+        let mut ingest = Ingest::new(&ctx).unwrap();
+
+        let mut results = Vec::new();
+        for (name, alert) in &[
+            ("a.txt", TEST_COOL_STR),
+            ("b.txt", TEST_DIF_PROD),
+            ("c.txt", TEST_COOL_STR),
+        ] {
+            let path = dir.join(name);
+            write(&path, &Email::from_str(alert).unwrap().contents).unwrap();
+            results.extend(ingest.ingest(&path).unwrap());
+        }
+        // Reading the same file again is a no-op
+        let again = ingest.ingest(&dir.join("a.txt")).unwrap();
+
+        let classes: Vec<EntryClass> = results.into_iter().map(|a| a.class).collect();
+        assert_eq!(
+            classes,
+            vec![
+                EntryClass::New,
+                EntryClass::NewProduct(12345),
+                EntryClass::Duplicate((12345, Product::from_str("COOL_PROD").unwrap())),
+            ]
+        );
+        assert!(again.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+// golden.rs renders each brain under tests/fixtures and compares it to the snapshots kept beside it
+// A template change that moves anything on the paper shows up here as a diff
+//
+// tests/fixtures/CASE/brain/     - the alerts, and hx/ if the case needs earlier digests
+// tests/fixtures/CASE/expected/  - what preview, the review and each digest renderer should print
+//
+// After a change that's meant to alter the output, rewrite the snapshots and check the diff in git:
+//     UPDATE_GOLDEN=1 cargo test --test golden
+use ar_bot::{
+    batch::TimeDetail,
+    page::{annotate, preview_text, render_digest, Renderer},
+    review::Review,
+    test_util::TempBrain,
+};
+use pretty_assertions::assert_eq;
+use std::{
+    env,
+    fs::{copy, create_dir_all, read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+};
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+}
+
+fn sorted_dir(path: &Path) -> Vec<PathBuf> {
+    let mut ret: Vec<PathBuf> = read_dir(path).unwrap().map(|f| f.unwrap().path()).collect();
+    ret.sort();
+    ret
+}
+
+// Reading a brain locks it and may recover a journal in it, so each case runs on a copy
+fn copy_dir(from: &Path, to: &Path) {
+    create_dir_all(to).unwrap();
+    for path in sorted_dir(from) {
+        let dest = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &dest);
+        } else {
+            copy(&path, &dest).unwrap();
+        }
+    }
+}
+
+// Everything one case renders, by snapshot name
+fn render_case(brain: &Path) -> Vec<(String, String)> {
+    let dir = TempBrain::new();
+    copy_dir(brain, &dir);
+    let ctx = dir.context();
+
+    let review = Review::new(&ctx);
+    let batch = review.batch().unwrap();
+    let annotations = annotate(&ctx, &batch).unwrap();
+
+    let mut ret = Vec::new();
+    let mut summary = format!("{}\n", review.summary().unwrap());
+    for (name, why) in &review.unmatched {
+        summary.push_str(&format!("  {}: {}\n", name, why));
+    }
+    ret.push(("review.txt".to_string(), summary));
+    for (name, detail) in &[
+        ("preview.txt", TimeDetail::Counted),
+        ("preview-all.txt", TimeDetail::All),
+        ("preview-days.txt", TimeDetail::Days),
+    ] {
+        ret.push((
+            name.to_string(),
+            preview_text(&batch, &annotations, *detail),
+        ));
+    }
+    for renderer in &[Renderer::Html, Renderer::Text, Renderer::Markdown] {
+        let output = render_digest(&ctx, &batch, *renderer, &[]).unwrap();
+        ret.push((format!("digest.{}", renderer.extension()), output.digest));
+    }
+
+    ret
+}
+
+#[test]
+fn test_golden() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let cases: Vec<PathBuf> = sorted_dir(&fixtures())
+        .into_iter()
+        .filter(|p| p.is_dir())
+        .collect();
+    assert!(!cases.is_empty(), "No fixtures in {:?}", fixtures());
+
+    for case in cases {
+        let expected_dir = case.join("expected");
+        for (name, rendered) in render_case(&case.join("brain")) {
+            let expected_path = expected_dir.join(&name);
+            if update {
+                create_dir_all(&expected_dir).unwrap();
+                write(&expected_path, &rendered).unwrap();
+                continue;
+            }
+            let expected = read_to_string(&expected_path).unwrap_or_else(|_| {
+                panic!(
+                    "No snapshot {:?} - run UPDATE_GOLDEN=1 cargo test --test golden",
+                    expected_path
+                )
+            });
+            assert_eq!(
+                expected, rendered,
+                "{:?} changed - run UPDATE_GOLDEN=1 cargo test --test golden if it should have",
+                expected_path
+            );
+        }
+    }
+}